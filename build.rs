@@ -32,6 +32,16 @@ use std::collections::HashSet;
 extern crate regex;
 use regex::Regex;
 
+extern crate toml;
+use toml::Value;
+
+/* the capsule launch manifest to use, unless overridden by the DIOSIX_MANIFEST environment variable */
+const DEFAULT_MANIFEST: &str = "boot/manifest.toml";
+
+/* service IDs from 1 up to this are reserved for well-known services, and only these can be allowed in the manifest.
+IDs from this value upwards are handed out by the hypervisor, which takes this value from service_base.rs */
+const DYNAMIC_SERVICE_BASE: i64 = 0x1000;
+
 /* describe a build target from its user-supplied triple */
 struct Target
 {
@@ -111,23 +121,14 @@ fn main()
         target: &target
     };
 
-    /* provide a supervisor kernel for the first capsule to run. this should contain an executable
-    that unpacks a basic filesystem and then loads more files as needed from storage.
-    its job is to manage all child capsules, which should also be loaded as needed from storage.
+    /* provide supervisor kernels for the capsules to run at boot. the manifest lists each capsule to
+    create and the supervisor it runs. the first capsule should contain an executable that unpacks a
+    basic filesystem and then loads more files as needed from storage. its job is to manage
+    all child capsules, which should also be loaded as needed from storage.
 
-    the boot capsule's supervisor is expected in boot/binaries/cpu/supervisor
-    where cpu = target CPU architectures, such as rv32imac, rv64gc, etc */
-    
-    let boot_files = String::from(format!("boot/binaries/{}", target.target));
-    let boot_supervisor_name = String::from("supervisor");
-    let boot_supervisor = format!("{}/{}", boot_files, boot_supervisor_name);
-
-    /* check we have a supervisor to boot, and if so, include them in the final linking process */
-    match metadata(&boot_supervisor)
-    {
-        Err(e) => panic!("Expected boot capsule supervisor at {}, can't find it (error: {:?})", boot_supervisor, e),
-        _ => package_binary(&boot_files, &boot_supervisor_name, &mut context)
-    };
+    supervisors are expected in boot/binaries/cpu/ where cpu = target CPU architectures,
+    such as rv32imac, rv64gc, etc */
+    package_manifest(&mut context);
 
     /* tell cargo to rebuild if linker file changes */
     println!("cargo:rerun-if-changed=src/platform-{}/link.ld", &target.platform);
@@ -139,6 +140,180 @@ fn main()
     link_archive(&mut context);
 }
 
+/* Read the capsule launch manifest, check each capsule it describes, and package the
+   supervisor binaries it names so they can be linked with the hypervisor.
+
+   The hypervisor parses the manifest at boot, so rather than include the manifest as written,
+   a normalized copy, one key = value per line, is written to manifest.toml in the output directory.
+   Alongside it, images.rs is generated, which maps the name of each packaged supervisor
+   to its _binary_component_start and _binary_component_end symbols, and service_base.rs,
+   which gives the hypervisor the first service ID it can hand out.
+   => context = build context
+*/
+fn package_manifest(mut context: &mut Context)
+{
+    let manifest_path = env::var("DIOSIX_MANIFEST").unwrap_or(String::from(DEFAULT_MANIFEST));
+    println!("cargo:rerun-if-env-changed=DIOSIX_MANIFEST");
+    println!("cargo:rerun-if-changed={}", &manifest_path);
+
+    let contents = match fs::read_to_string(&manifest_path)
+    {
+        Ok(c) => c,
+        Err(e) => panic!("Expected capsule manifest at {}, can't read it (error: {:?})", &manifest_path, e)
+    };
+
+    let manifest = match contents.parse::<Value>()
+    {
+        Ok(m) => m,
+        Err(e) => panic!("Can't parse capsule manifest {}: {}", &manifest_path, e)
+    };
+
+    let capsules = match manifest.get("capsule").and_then(|c| c.as_array())
+    {
+        Some(c) if c.len() > 0 => c,
+        _ => panic!("Capsule manifest {} must describe at least one [[capsule]]", &manifest_path)
+    };
+
+    let boot_files = String::from(format!("boot/binaries/{}", context.target.target));
+    let mut normalized = String::from("# generated by build.rs from the capsule launch manifest\n");
+    let mut images: Vec<String> = Vec::new();
+
+    for (index, capsule) in capsules.iter().enumerate()
+    {
+        let (image, entry) = check_manifest_capsule(index, capsule, &manifest_path);
+        normalized.push_str(&entry);
+
+        /* capsules can share a supervisor, though it only needs to be packaged once */
+        if images.contains(&image) == false
+        {
+            let supervisor = format!("{}/{}", &boot_files, &image);
+            match metadata(&supervisor)
+            {
+                Err(e) => panic!("Expected capsule supervisor at {}, can't find it (error: {:?})", &supervisor, e),
+                _ => package_binary(&boot_files, &image, &mut context)
+            };
+
+            println!("cargo:rerun-if-changed={}", &supervisor);
+            images.push(image);
+        }
+    }
+
+    /* generate the table of supervisors for the hypervisor */
+    let mut table = String::from("/* generated by build.rs: locate the supervisors packaged into the hypervisor */\n\nextern \"C\"\n{\n");
+    for image in images.iter()
+    {
+        table.push_str(&format!("    static _binary_{}_start: u8;\n    static _binary_{}_end: u8;\n", image, image));
+    }
+    table.push_str("}\n\n/* return the start and end addresses of the named supervisor in memory, or None if not packaged */\n");
+    table.push_str("pub fn find(name: &str) -> Option<(usize, usize)>\n{\n    unsafe\n    {\n        match name\n        {\n");
+    for image in images.iter()
+    {
+        table.push_str(&format!("            \"{}\" => Some((&_binary_{}_start as *const u8 as usize, &_binary_{}_end as *const u8 as usize)),\n",
+            image, image, image));
+    }
+    table.push_str("            _ => None\n        }\n    }\n}\n");
+
+    /* share the end of the range of reserved service IDs with the hypervisor */
+    let service_base = format!("/* generated by build.rs: first dynamically allocated service ID */\n0x{:x}\n", DYNAMIC_SERVICE_BASE);
+
+    for (leafname, data) in [("manifest.toml", &normalized), ("images.rs", &table), ("service_base.rs", &service_base)].iter()
+    {
+        let path = format!("{}/{}", &context.output_dir, leafname);
+        if let Err(e) = fs::write(&path, data)
+        {
+            panic!("Can't write {} (error: {:?})", &path, e);
+        }
+    }
+}
+
+/* Check a capsule described in the manifest has everything it needs, and nothing it shouldn't
+   => index = position of the capsule in the manifest, counting from zero
+      capsule = the capsule's table from the manifest
+      manifest_path = path of the manifest, for error messages
+   <= name of the capsule's supervisor binary, and the capsule normalized into a [[capsule]] table */
+fn check_manifest_capsule(index: usize, capsule: &Value, manifest_path: &String) -> (String, String)
+{
+    let table = match capsule.as_table()
+    {
+        Some(t) => t,
+        None => panic!("Capsule {} in manifest {} is not a table", index, manifest_path)
+    };
+
+    for key in table.keys()
+    {
+        match key.as_str()
+        {
+            "image" | "ram" | "quota" | "vcores" | "priority" | "auto_restart" | "manager" | "services" => (),
+            unknown => panic!("Capsule {} in manifest {} has unknown setting '{}'", index, manifest_path, unknown)
+        }
+    }
+
+    /* the image name becomes part of the supervisor's symbol names, so keep it simple */
+    let image = match table.get("image").and_then(|i| i.as_str())
+    {
+        Some(i) if Regex::new(r"^[A-Za-z0-9_]+$").unwrap().is_match(i) => String::from(i),
+        _ => panic!("Capsule {} in manifest {} needs an image name made of letters, digits and underscores", index, manifest_path)
+    };
+
+    let mut entry = format!("\n[[capsule]]\nimage = \"{}\"\n", &image);
+
+    for key in ["ram", "vcores"].iter()
+    {
+        match table.get(*key).and_then(|v| v.as_integer())
+        {
+            Some(v) if v > 0 => entry.push_str(&format!("{} = {}\n", key, v)),
+            _ => panic!("Capsule {} in manifest {} needs a {} setting greater than zero", index, manifest_path, key)
+        }
+    }
+
+    /* the quota, in megabytes, defaults to the capsule's RAM and can't be less than it */
+    match table.get("quota").map(|q| q.as_integer())
+    {
+        None => (),
+        Some(Some(q)) if q >= table.get("ram").and_then(|r| r.as_integer()).unwrap_or(0) => entry.push_str(&format!("quota = {}\n", q)),
+        _ => panic!("Capsule {} in manifest {} needs a quota no smaller than its ram setting", index, manifest_path)
+    }
+
+    match table.get("priority").map(|p| p.as_str())
+    {
+        None => (),
+        Some(Some(p)) if p == "high" || p == "normal" => entry.push_str(&format!("priority = \"{}\"\n", p)),
+        _ => panic!("Capsule {} in manifest {} has a priority other than \"high\" or \"normal\"", index, manifest_path)
+    }
+
+    for key in ["auto_restart", "manager"].iter()
+    {
+        match table.get(*key).map(|v| v.as_bool())
+        {
+            None => (),
+            Some(Some(flag)) => entry.push_str(&format!("{} = {}\n", key, flag)),
+            _ => panic!("Capsule {} in manifest {} needs {} to be true or false", index, manifest_path, key)
+        }
+    }
+
+    if let Some(services) = table.get("services")
+    {
+        let mut list = Vec::new();
+        match services.as_array()
+        {
+            Some(array) => for sid in array
+            {
+                /* only the reserved service IDs, from 1 up to DYNAMIC_SERVICE_BASE, can be allowed */
+                match sid.as_integer()
+                {
+                    Some(sid) if sid > 0 && sid < DYNAMIC_SERVICE_BASE => list.push(format!("{}", sid)),
+                    _ => panic!("Capsule {} in manifest {} has a service ID outside the reserved range of 1 to {}",
+                                index, manifest_path, DYNAMIC_SERVICE_BASE - 1)
+                }
+            },
+            None => panic!("Capsule {} in manifest {} needs services to be a list of service IDs", index, manifest_path)
+        }
+        entry.push_str(&format!("services = [ {} ]\n", list.join(", ")));
+    }
+
+    (image, entry)
+}
+
 /* Turn a binary file into a .o object file to link with hypervisor. 
    the following symbols will be defined pointing to the start and end
    of the object when it is located in memory and its size in bytes:
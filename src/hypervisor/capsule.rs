@@ -10,18 +10,24 @@ use hashbrown::hash_map::HashMap;
 use hashbrown::hash_map::Entry::{Occupied, Vacant};
 use hashbrown::hash_set::HashSet;
 use alloc::vec::Vec;
-use platform::physmem::PhysMemSize;
+use platform::physmem::{PhysMemBase, PhysMemSize};
 use platform::virtmem::VirtMemBase;
 use platform::cpu::Entry;
 use super::loader;
+use super::dtb;
+use super::hardware;
+use super::console;
 use super::error::Cause;
+use super::heap;
 use super::physmem::{self, Region};
-use super::virtmem::Mapping;
+use super::virtmem::{self, Mapping, GStageTable, Access};
+use super::grant::{self, GrantID};
 use super::vcore::{self, Priority, VirtualCoreID};
 use super::service::ServiceID;
-use super::pcore::PhysicalCore;
+use super::pcore::{PhysicalCore, PhysicalCoreID};
 use super::service;
 use super::message;
+use super::scheduler;
 
 pub type CapsuleID = usize;
 
@@ -38,39 +44,303 @@ lazy_static!
     static ref CAPSULES: Mutex<HashMap<CapsuleID, Capsule>> = Mutex::new(HashMap::new());
 }
 
+/* crashed auto-restarting capsules wait in this list until their backoff delay has passed */
+lazy_static!
+{
+    /* acquire RESTARTS lock before queuing or starting capsule restarts */
+    static ref RESTARTS: Mutex<Vec<Restart>> = Mutex::new(Vec::new());
+}
+
+/* RAM taken away from a capsule could still be reachable through memory protection loaded on other
+physical CPU cores, so it can't be reused until they've all reloaded it. each revocation of access to
+memory is numbered, and each physical CPU core acknowledges revocations once it has reloaded the memory
+protection of the capsule it's running. freed RAM waits until every revocation made before it was freed
+has been acknowledged by every physical CPU core */
+lazy_static!
+{
+    /* acquire REVOCATIONS lock before revoking memory or retiring freed RAM */
+    static ref REVOCATIONS: Mutex<Revocations> = Mutex::new(Revocations
+    {
+        issued: 0,
+        acked: HashMap::new(),
+        retired: Vec::new()
+    });
+}
+
+struct Revocations
+{
+    issued: usize,                          /* number of the latest revocation */
+    acked: HashMap<PhysicalCoreID, usize>,  /* latest revocation acknowledged by each physical CPU core */
+    retired: Vec<(usize, Region)>           /* freed RAM and the latest revocation made before it was freed */
+}
+
+/* wait this many milliseconds before restarting a crashed capsule, doubling the delay
+for each consecutive crash up to a maximum */
+const RESTART_BACKOFF_INITIAL: u64 = 100;
+const RESTART_BACKOFF_MAX: u64 = 10000;
+
+/* give up restarting a capsule after this many consecutive crashes */
+const RESTART_LIMIT: usize = 8;
+
+/* a capsule that runs for this many milliseconds before crashing is considered to have
+been stable, and its count of consecutive crashes starts again from zero */
+const RESTART_STABLE_PERIOD: u64 = 60000;
+
+const MILLISECONDS_PER_SECOND: u64 = 1000;
+
+/* the original configuration of an auto-restarting capsule, needed to re-create it from scratch */
+#[derive(Clone, Copy)]
+struct Launch
+{
+    image: Region,          /* supervisor binary. this must stay in memory while the capsule can be restarted */
+    ram: PhysMemSize,       /* bytes of RAM given to the capsule */
+    quota: PhysMemSize,     /* most bytes of RAM the capsule can hold */
+    prio: Priority,         /* priority of the capsule's virtual cores */
+    restarts: usize,        /* number of consecutive crashes */
+    started: Option<u64>    /* timer value when this incarnation of the capsule was created */
+}
+
+/* describe a capsule waiting to be restarted */
+struct Restart
+{
+    previous: CapsuleID,                /* ID of the capsule that died */
+    due: Option<u64>,                   /* timer value from which the capsule can be restarted */
+    launch: Launch,
+    manager: bool,
+    max_vcores: usize,
+    services: HashSet<ServiceID>
+}
+
 struct Capsule
 {
+    dying: bool,                            /* true if being torn down, waiting for its virtual cores to go */
     restart: bool,                          /* true to auto-restart on death */
+    manager: bool,                          /* true if allowed to create and manage other capsules */
+    max_vcores: usize,                      /* maximum number of virtual cores this capsule can run */
+    quota: PhysMemSize,                     /* most bytes of host physical RAM this capsule can hold */
+    used: PhysMemSize,                      /* bytes of host physical RAM allocated on this capsule's behalf */
     vcores: HashSet<VirtualCoreID>,         /* set of virtual core IDs assigned to this capsule */
     memory: Vec<Mapping>,                   /* map capsule supervisor virtual addresses to host physical addresses */
-    allowed_services: HashSet<ServiceID>    /* set of services this capsule is allowed to provide */
+    gstage: Option<GStageTable>,            /* G-stage page tables, if two-stage address translation is in use */
+    allowed_services: HashSet<ServiceID>,   /* set of services this capsule is allowed to provide */
+    launch: Option<Launch>                  /* how to re-create this capsule if it auto-restarts */
 }
 
 impl Capsule
 {
     /* create a new empty capsule using the current capsule on this physical CPU core.
     => auto_restart_flag = tue to auto-restart on death by the hypervisor (eg, for the boot capsule)
+       manager_flag = true to allow the capsule to create and manage other capsules
+       max_vcores = maximum number of virtual cores the capsule's supervisor can start
+       quota = most bytes of physical RAM that can be allocated on the capsule's behalf
     <= capsule object, or error code */
-    pub fn new(auto_restart_flag: bool) -> Result<Capsule, Cause>
+    pub fn new(auto_restart_flag: bool, manager_flag: bool, max_vcores: usize, quota: PhysMemSize) -> Result<Capsule, Cause>
     {
         Ok(Capsule
         {
+            dying: false,
             restart: auto_restart_flag,
+            manager: manager_flag,
+            max_vcores: max_vcores,
+            quota: quota,
+            used: 0,
             vcores: HashSet::new(),
             memory: Vec::new(),
+            gstage: match virtmem::gstage_mode()
+            {
+                Some(mode) => Some(GStageTable::new(mode)?),
+                None => None
+            },
             allowed_services: HashSet::new(),
+            launch: None
         })
     }
 
-    /* add a mapping to this capsule */
-    pub fn set_memory_mapping(&mut self, to_add: Mapping)
+    /* add a mapping to this capsule, installing it in the capsule's G-stage page tables if it has them.
+    the mapping is refused if the hardware can't protect all of the capsule's physical RAM regions at once
+    <= Ok for success, or an error code */
+    pub fn set_memory_mapping(&mut self, to_add: Mapping) -> Result<(), Cause>
     {
+        heap::reserved(self.memory.try_reserve(1))?;
         self.memory.push(to_add);
+        if self.get_physical_regions().len() > physmem::max_protected_regions()
+        {
+            self.memory.pop();
+            return Err(Cause::CapsuleTooManyRegions);
+        }
+
+        if let Some(table) = self.gstage.as_mut()
+        {
+            let result = match (to_add.get_virtual(), to_add.get_physical())
+            {
+                /* map() undoes any partial mapping itself if it fails */
+                (Some(base), Some(region)) => table.map(base, region, to_add.get_access()),
+                (_, _) => Err(Cause::VirtMemPhysNotSet)
+            };
+
+            if result.is_err()
+            {
+                self.memory.pop();
+                return result;
+            }
+        }
+
+        Ok(())
+    }
+
+    /* remove the mapping of memory shared with this capsule by a grant, along with its G-stage translations.
+    the capsule's physical CPU cores must reload its memory protection afterwards
+    <= true if the grant was mapped, or false if not */
+    pub fn clear_grant_mapping(&mut self, id: GrantID) -> bool
+    {
+        let mapping = match self.memory.iter().position(|m| m.get_grant() == Some(id))
+        {
+            Some(index) => self.memory.remove(index),
+            None => return false
+        };
+
+        if let (Some(table), Some(base), Some(region)) = (self.gstage.as_mut(), mapping.get_virtual(), mapping.get_physical())
+        {
+            if let Err(e) = table.unmap(base, region.size())
+            {
+                hvalert!("BUG: Could not unmap grant {} from capsule ({:?})", id, e);
+            }
+        }
+        true
+    }
+
+    /* choose where the capsule will see memory added to it after it was created, such as memory shared with it.
+    with two-stage address translation, it's placed above the capsule's highest mapping. otherwise, the capsule
+    sees it where it is in physical memory
+    => region = host physical memory to be added
+    <= capsule's address of the memory */
+    fn place_memory(&self, region: Region) -> VirtMemBase
+    {
+        if self.gstage.is_none()
+        {
+            return region.base();
+        }
+
+        let mut top = virtmem::GUEST_RAM_BASE;
+        for mapping in self.memory.iter()
+        {
+            if let (Some(base), Some(r)) = (mapping.get_virtual(), mapping.get_physical())
+            {
+                if base + r.size() > top
+                {
+                    top = base + r.size();
+                }
+            }
+        }
+        (top + virtmem::PAGE_SIZE - 1) & !(virtmem::PAGE_SIZE - 1)
+    }
+
+    /* remove a page-aligned range of the capsule's own memory, splitting the mapping that covers it.
+    the capsule's physical CPU cores must reload its memory protection afterwards
+    => base = capsule's address of the start of the range
+       size = number of bytes in the range
+    <= host physical memory removed, or an error code */
+    pub fn remove_memory(&mut self, base: VirtMemBase, size: PhysMemSize) -> Result<Region, Cause>
+    {
+        let last = match base.checked_add(size)
+        {
+            Some(end) if size > 0 => end - 1,
+            _ => return Err(Cause::CapsuleBadAddress)
+        };
+
+        let index = match self.memory.iter().position(|m| m.get_grant().is_none() &&
+                                                          m.virtual_to_physical(base).is_some() &&
+                                                          m.virtual_to_physical(last).is_some())
+        {
+            Some(i) => i,
+            None => return Err(Cause::CapsuleBadAddress)
+        };
+
+        let original = self.memory[index];
+        let (vbase, region) = match (original.get_virtual(), original.get_physical())
+        {
+            (Some(v), Some(r)) => (v, r),
+            (_, _) => return Err(Cause::VirtMemPhysNotSet)
+        };
+
+        /* keep whatever's left either side of the range */
+        let offset = base - vbase;
+        let removed = Region::new(region.base() + offset, size);
+        let mut pieces: Vec<Mapping> = Vec::new();
+        heap::reserved(pieces.try_reserve(2))?;
+        for (piece_base, piece) in [(vbase, Region::new(region.base(), offset)),
+                                    (base + size, Region::new(removed.end(), region.end() - removed.end()))].iter()
+        {
+            if piece.size() > 0
+            {
+                let mut mapping = Mapping::new();
+                mapping.set_physical(*piece);
+                mapping.set_virtual(*piece_base);
+                pieces.push(mapping);
+            }
+        }
+
+        heap::reserved(self.memory.try_reserve(pieces.len()))?;
+        self.memory.remove(index);
+        for (count, mapping) in pieces.iter().enumerate()
+        {
+            self.memory.insert(index + count, *mapping);
+        }
+
+        /* a hole in the capsule's memory may need another hardware protection slot */
+        if self.get_physical_regions().len() > physmem::max_protected_regions()
+        {
+            /* put the original mapping back in place of the pieces */
+            for _ in 0..pieces.len()
+            {
+                self.memory.remove(index);
+            }
+            self.memory.insert(index, original);
+            return Err(Cause::CapsuleTooManyRegions);
+        }
+
+        if let Some(table) = self.gstage.as_mut()
+        {
+            if let Err(e) = table.unmap(base, size)
+            {
+                hvalert!("BUG: Could not unmap 0x{:x}, {} bytes from capsule ({:?})", base, size, e);
+            }
+        }
+        Ok(removed)
     }
 
     /* get a copy of the capsule's memory mappings */
     pub fn get_memory_mappings(&self) -> Vec<Mapping> { self.memory.clone() }
 
+    /* get the host physical RAM regions backing this capsule and how the capsule may access them, sorted by
+    base address and with adjoining regions of the same access merged, so that the fewest hardware protection
+    slots are needed */
+    pub fn get_physical_regions(&self) -> Vec<(Region, Access)>
+    {
+        let mut regions: Vec<(Region, Access)> = Vec::new();
+        for mapping in self.memory.iter()
+        {
+            if let Some(r) = mapping.get_physical()
+            {
+                regions.push((r, mapping.get_access()));
+            }
+        }
+
+        regions.sort_by_key(|(r, _)| r.base());
+
+        let mut merged: Vec<(Region, Access)> = Vec::new();
+        for (region, access) in regions
+        {
+            match merged.last_mut()
+            {
+                Some((last, last_access)) if last.end() == region.base() && *last_access == access =>
+                    *last = Region::new(last.base(), last.size() + region.size()),
+                _ => merged.push((region, access))
+            }
+        }
+        merged
+    }
+
     /* boot capsules are auto-restarted by the hypervisor */
     pub fn set_auto_restart(&mut self, flag: bool)
     {
@@ -83,10 +353,30 @@ impl Capsule
         self.restart
     }
 
-    /* add a virtual core ID to the capsule */
-    pub fn add_vcore(&mut self, id: VirtualCoreID)
+    /* returns true if this capsule is being torn down, or false if not */
+    pub fn is_dying(&self) -> bool
     {
+        self.dying
+    }
+
+    /* returns true if this can create and manage other capsules, or false if not */
+    pub fn is_manager(&self) -> bool
+    {
+        self.manager
+    }
+
+    /* add a virtual core ID to the capsule
+       <= Ok for success, or an error code if the ID is already in use or there isn't enough memory */
+    pub fn add_vcore(&mut self, id: VirtualCoreID) -> Result<(), Cause>
+    {
+        if self.vcores.contains(&id) == true
+        {
+            return Err(Cause::CapsuleVirtualCoreExists);
+        }
+
+        heap::reserved(self.vcores.try_reserve(1))?;
         self.vcores.insert(id);
+        Ok(())
     }
 
     /* remove a virtual core ID from the capsule. returns true if that
@@ -102,6 +392,39 @@ impl Capsule
         self.vcores.len()
     }
 
+    /* return true if the given virtual core ID is registered */
+    pub fn has_vcore(&self, id: VirtualCoreID) -> bool
+    {
+        self.vcores.contains(&id)
+    }
+
+    /* return the maximum number of virtual cores this capsule can have */
+    pub fn get_max_vcores(&self) -> usize
+    {
+        self.max_vcores
+    }
+
+    /* account for physical RAM allocated on this capsule's behalf
+    <= Ok for success, or an error code if that would take the capsule over its quota */
+    pub fn charge(&mut self, bytes: PhysMemSize) -> Result<(), Cause>
+    {
+        match self.used.checked_add(bytes)
+        {
+            Some(total) if total <= self.quota =>
+            {
+                self.used = total;
+                Ok(())
+            },
+            _ => Err(Cause::CapsuleQuotaExceeded)
+        }
+    }
+
+    /* account for physical RAM freed on this capsule's behalf */
+    pub fn uncharge(&mut self, bytes: PhysMemSize)
+    {
+        self.used = self.used.saturating_sub(bytes);
+    }
+
     /* allow capsule to register service sid */
     pub fn allow_service(&mut self, sid: ServiceID)
     {
@@ -116,74 +439,314 @@ impl Capsule
     }
 }
 
-/* handle the destruction of a capsule */
+/* handle the destruction of a capsule. by now, it must have no virtual cores left
+that could access its memory. its services, console, and grants are removed by destroy(),
+and its RAM is held back until the capsules its grants were mapped into can't reach it */
 impl Drop for Capsule
 {
     fn drop(&mut self)
     {
         hvdebug!("Tearing down capsule");
         
-        /* free up memory... */
+        /* free up memory, leaving alone memory shared with this capsule, which belongs to other capsules */
         for mapping in self.memory.clone()
         {
-            if let Some(r) = mapping.get_physical()
+            if let (Some(r), None) = (mapping.get_physical(), mapping.get_grant())
             {
-                physmem::dealloc_region(r);
+                self.uncharge(r.size());
+                retire(r);
             }
         }
+    }
+}
 
-        /* ...and services held by this capsule */
-        for sid in self.allowed_services.iter()
+/* create a capsule, load its supervisor binary, and start it running
+   => image = region of physical memory holding the supervisor binary to load
+      size = number of bytes of physical RAM to give the capsule
+      quota = most bytes of physical RAM the capsule can hold, which must be at least size
+      cpus = maximum number of virtual cores the capsule can run
+      prio = priority of the capsule's virtual cores
+      auto_restart = true to be auto-restarted by hypervisor
+      manager = true to allow the capsule to create and manage other capsules
+      services = set of services the capsule is allowed to register. these are granted before it starts
+   <= ID of the new capsule, or an error code */
+pub fn create_from_image(image: Region, size: PhysMemSize, quota: PhysMemSize, cpus: usize, prio: Priority,
+                         auto_restart: bool, manager: bool, services: HashSet<ServiceID>) -> Result<CapsuleID, Cause>
+{
+    /* auto-restarting capsules are re-created from their image, so it must stay in memory */
+    let launch = match auto_restart
+    {
+        true => Some(Launch { image: image, ram: size, quota: quota, prio: prio, restarts: 0, started: None }),
+        false => None
+    };
+
+    launch_capsule(image, size, quota, cpus, prio, manager, launch, services)
+}
+
+/* create a capsule and start it running. see create_from_image() for a description of the parameters
+   => launch = configuration needed to restart the capsule, or None if it doesn't auto-restart
+   <= ID of the new capsule, or an error code */
+fn launch_capsule(image: Region, size: PhysMemSize, quota: PhysMemSize, cpus: usize, prio: Priority, manager: bool,
+                  launch: Option<Launch>, services: HashSet<ServiceID>) -> Result<CapsuleID, Cause>
+{
+    let _tag = heaptag!();
+    let capid = create(launch.is_some(), manager, cpus, quota)?;
+    if let Some(c) = CAPSULES.lock().get_mut(&capid)
+    {
+        /* record when this capsule started so that crash loops can be detected */
+        c.launch = match launch
         {
-            service::deregister(*sid);
+            Some(mut l) =>
+            {
+                l.started = hardware::scheduler_get_timer_now();
+                Some(l)
+            },
+            None => None
+        };
+        c.allowed_services = services;
+    }
+
+    match populate(capid, image, size, cpus, prio)
+    {
+        Ok(()) => Ok(capid),
+        Err(e) =>
+        {
+            /* don't leave a half-built capsule lying around */
+            if let Err(f) = destroy(capid)
+            {
+                hvalert!("Failed to destroy half-built capsule {} ({:?})", capid, f);
+            }
+            Err(e)
         }
     }
 }
 
-/* create the boot capsule, from which all other capsules spawn */
-pub fn create_boot_capsule() -> Result<(), Cause>
+/* give a new capsule its RAM, load its supervisor, and start its first virtual core.
+   see create_from_image() for a description of the parameters */
+fn populate(capid: CapsuleID, image: Region, size: PhysMemSize, cpus: usize, prio: Priority) -> Result<(), Cause>
 {
-    /* create an auto-restarting capsule */
-    let capid = create(true)?;
-
-    /* reserve 128MB of physical RAM for the capsule */
-    let size = 128 * 1024 * 1024;
-    let cpus = 1;
-    let ram = physmem::alloc_region(size)?;
+    let ram = alloc_memory(capid, size)?;
 
-    /* map that physical memory into the capsule */
+    /* map that physical memory into the capsule. with two-stage address translation, the capsule
+    gets a conventional memory layout. otherwise, it sees its RAM where it is in physical memory */
     let mut mapping = Mapping::new();
     mapping.set_physical(ram);
-    mapping.identity_mapping()?;
+    match virtmem::gstage_mode()
+    {
+        Some(_) => mapping.set_virtual(virtmem::GUEST_RAM_BASE),
+        None => mapping.identity_mapping()?
+    };
     map_memory(capid, mapping)?;
 
-    /* parse + copy the boot capsule's binary into its physical memory */
-    let phys_binary_location = physmem::boot_supervisor();
-    let entry = loader::load(ram, phys_binary_location)?;
+    /* parse + copy the capsule's binary into its physical memory, and find its entry point in the capsule's memory */
+    let entry = match mapping.physical_to_virtual(loader::load(ram, image)?)
+    {
+        Some(e) => e,
+        None => return Err(Cause::LoaderBadEntry)
+    };
+
+    /* describe to the supervisor its virtualized environment */
+    let dtb = install_dtb(capid, cpus)?;
+
+    /* start the capsule's first virtual core. the supervisor can bring up the rest using the SBI */
+    create_and_add_vcore(capid, 0, entry, dtb, prio)?;
+    Ok(())
+}
+
+/* allocate physical RAM on a capsule's behalf, charging it to the capsule's quota
+   => cid = ID of capsule to allocate RAM for
+      size = number of bytes to allocate
+   <= region of physical RAM, or an error code */
+fn alloc_memory(cid: CapsuleID, size: PhysMemSize) -> Result<Region, Cause>
+{
+    let region = physmem::alloc_region(size)?;
+    let charged = match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) => c.charge(region.size()),
+        None => Err(Cause::CapsuleBadID)
+    };
+
+    match charged
+    {
+        Ok(()) => Ok(region),
+        Err(e) =>
+        {
+            if let Err(f) = physmem::dealloc_region(region)
+            {
+                hvalert!("BUG: Could not free RAM at 0x{:x}, {} bytes ({:?})", region.base(), region.size(), f);
+            }
+            Err(e)
+        }
+    }
+}
 
-    /* create virtual CPU cores for the capsule as required */
-    for vcoreid in 0..cpus
+/* free physical RAM allocated on a capsule's behalf, and take it off the capsule's quota. the RAM
+   is retired until no physical CPU core can reach it: see retire()
+   => cid = ID of capsule the RAM was allocated for
+      region = physical RAM to free */
+fn free_memory(cid: CapsuleID, region: Region)
+{
+    if let Some(c) = CAPSULES.lock().get_mut(&cid)
     {
-        create_and_add_vcore(capid, vcoreid, entry, Priority::High)?;
+        c.uncharge(region.size());
+    }
+
+    retire(region);
+}
+
+/* tell all physical CPU cores to reload a capsule's memory protection after access to some of its memory
+   was taken away, and to acknowledge they've done so. RAM freed from now on is held back until they have
+   => cid = ID of capsule that lost access to memory */
+pub fn revoke_access(cid: CapsuleID)
+{
+    let mut revocations = REVOCATIONS.lock();
+    revocations.issued = revocations.issued + 1;
+    let number = revocations.issued;
+
+    /* send the message with the lock held so that revocations arrive in order */
+    let msg = message::Message::new(message::Recipient::send_to_all(), message::MessageContent::CapsuleMemoryRevoked(cid, number));
+    if let Err(e) = message::send(msg)
+    {
+        hvalert!("BUG: Could not broadcast removal of memory from capsule {} ({:?}). Freed RAM will be held back", cid, e);
+    }
+}
+
+/* note that this physical CPU core has reloaded the memory protection of the capsule it's running,
+   if any, following a revocation, and free any retired RAM no physical CPU core can now reach
+   => number = revocation acknowledged */
+pub fn acknowledge_revocation(number: usize)
+{
+    REVOCATIONS.lock().acked.insert(PhysicalCore::get_id(), number);
+    free_retired();
+}
+
+/* hold back freed physical RAM until all revocations made so far have been acknowledged,
+   and then return it to the physical memory allocator
+   => region = physical RAM to free */
+fn retire(region: Region)
+{
+    {
+        let mut revocations = REVOCATIONS.lock();
+        let number = revocations.issued;
+        revocations.retired.push((number, region));
+    }
+    free_retired();
+}
+
+/* return to the physical memory allocator any retired RAM that no physical CPU core can reach */
+fn free_retired()
+{
+    let pcores = message::mailbox_owners();
+    let freed = {
+        let mut revocations = REVOCATIONS.lock();
+
+        /* find the latest revocation all physical CPU cores have acknowledged */
+        let mut done = revocations.issued;
+        for pcore in pcores
+        {
+            let acked = revocations.acked.get(&pcore).cloned().unwrap_or(0);
+            if acked < done
+            {
+                done = acked;
+            }
+        }
+
+        let mut freed = Vec::new();
+        let mut index = 0;
+        while index < revocations.retired.len()
+        {
+            match revocations.retired[index].0 <= done
+            {
+                true => freed.push(revocations.retired.remove(index).1),
+                false => index = index + 1
+            }
+        }
+        freed
+    };
+
+    for region in freed
+    {
+        if let Err(e) = physmem::dealloc_region(region)
+        {
+            hvalert!("BUG: Could not free RAM at 0x{:x}, {} bytes ({:?})", region.base(), region.size(), e);
+        }
+    }
+}
+
+/* generate a device tree blob describing the given capsule, and copy it
+   to the top of the capsule's first block of RAM, where it should be out of
+   the way of the supervisor image loaded at the bottom of the RAM
+   => cid = ID of capsule to describe
+      vcores = number of virtual CPU cores in the capsule
+   <= capsule's address of the device tree blob, or an error code */
+fn install_dtb(cid: CapsuleID, vcores: usize) -> Result<VirtMemBase, Cause>
+{
+    let mappings = match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => c.get_memory_mappings(),
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    let timebase = match hardware::get_timebase_frequency()
+    {
+        Some(f) => f,
+        None => return Err(Cause::DeviceTreeNoTimebase)
+    };
+
+    let blob = dtb::generate(vcores, &mappings, timebase)?;
+
+    /* find where to put the blob */
+    let (mapping, region) = match mappings.first()
+    {
+        Some(m) => match m.get_physical()
+        {
+            Some(r) => (m, r),
+            None => return Err(Cause::VirtMemPhysNotSet)
+        },
+        None => return Err(Cause::VirtMemPhysNotSet)
+    };
+
+    if blob.len() + dtb::DTB_ALIGNMENT > region.size()
+    {
+        return Err(Cause::DeviceTreeCreateFailed);
+    }
+
+    let target = (region.end() - blob.len()) & !(dtb::DTB_ALIGNMENT - 1);
+    unsafe
+    {
+        /* definition is: copy_nonoverlapping<T>(src: *const T, dst: *mut T, count: usize) */
+        core::intrinsics::copy_nonoverlapping::<u8>(blob.as_ptr(), target as *mut u8, blob.len());
+    }
+
+    match mapping.physical_to_virtual(target)
+    {
+        Some(addr) => Ok(addr),
+        None => Err(Cause::VirtMemPhysNotSet)
     }
-    Ok(())
 }
 
 /* create a virtual core and add it to the given capsule
    => cid = capsule ID
       vid = virtual core ID
       entry = starting address for execution of this virtual core
+      dtb = capsule's address of its device tree blob
       prio = priority to run this virtual core
-   <= return Ok for success, or error code
+   <= return Ok for success, or error code, which is CapsuleVirtualCoreExists if the capsule already has this virtual core
 */
-pub fn create_and_add_vcore(cid: CapsuleID, vid: VirtualCoreID, entry: Entry, prio: Priority) -> Result<(), Cause>
+pub fn create_and_add_vcore(cid: CapsuleID, vid: VirtualCoreID, entry: Entry, dtb: VirtMemBase, prio: Priority) -> Result<(), Cause>
 {
-    vcore::VirtualCore::create(cid, vid, entry, prio)?;
+    /* register the virtual core before it can be scheduled, so that it's accounted for if the capsule is torn down */
     match CAPSULES.lock().get_mut(&cid)
     {
-        Some(c) => c.add_vcore(vid),
-        None => return Err(Cause::CapsuleBadID)
+        Some(c) if c.is_dying() == false => c.add_vcore(vid)?,
+        _ => return Err(Cause::CapsuleBadID)
     };
+
+    if let Err(e) = vcore::VirtualCore::create(cid, vid, entry, dtb, prio)
+    {
+        remove_vcore(cid, vid);
+        return Err(e);
+    }
     Ok(())
 }
 
@@ -191,10 +754,13 @@ pub fn create_and_add_vcore(cid: CapsuleID, vid: VirtualCoreID, entry: Entry, pr
    Once created, it needs to be given a supervisor image, at least.
    then it is ready to be scheduled by assigning it virtual CPU cores.
    => auto_restart = true to be auto-restarted by hypervisor
+      manager = true to allow the capsule to create and manage other capsules
+      max_vcores = maximum number of virtual cores the capsule can have
+      quota = most bytes of physical RAM the capsule can hold
    <= CapsuleID for this new capsule, or an error code */
-fn create(auto_restart: bool) -> Result<CapsuleID, Cause>
+fn create(auto_restart: bool, manager: bool, max_vcores: usize, quota: PhysMemSize) -> Result<CapsuleID, Cause>
 {
-    let new_capsule = Capsule::new(auto_restart)?;
+    let new_capsule = Capsule::new(auto_restart, manager, max_vcores, quota)?;
 
     /* assign a new ID (in the unlikely event the given ID is already in-use, try again) */
     let mut overflowed_already = false;
@@ -210,7 +776,10 @@ fn create(auto_restart: bool) -> Result<CapsuleID, Cause>
         {
             Vacant(_) =>
             {
-                /* insert our new capsule */
+                /* give our new capsule a console and insert it, making room for it first
+                so that running out of memory leaves nothing half-created */
+                heap::reserved(capsules.try_reserve(1))?;
+                console::create(new_id)?;
                 capsules.insert(new_id, new_capsule);
 
                 /* we're all done here */
@@ -242,14 +811,390 @@ fn create(auto_restart: bool) -> Result<CapsuleID, Cause>
 */
 pub fn destroy(cid: CapsuleID) -> Result<(), Cause>
 {
-    if let Some(victim) = CAPSULES.lock().remove(&cid)
+    teardown(cid).and(Ok(()))
+}
+
+/* mark a capsule as dying, see destroy(), and tell all physical CPU cores to purge its virtual cores
+   => cid = ID of capsule to kill
+   <= what's needed to restart the capsule if it's flagged to auto-restart, or
+      None if not or if it was already dying, or an error code */
+fn teardown(cid: CapsuleID) -> Result<Option<Restart>, Cause>
+{
+    let (restart, idle) = match CAPSULES.lock().get_mut(&cid)
     {
-        drop(victim); // see above implementation of drop for Capsule
-        Ok(())
+        Some(c) if c.is_dying() == true => return Ok(None),
+        Some(c) =>
+        {
+            c.dying = true;
+            let restart = match (c.restart, c.launch)
+            {
+                (true, Some(launch)) => Some(Restart
+                {
+                    previous: cid,
+                    due: None,
+                    launch: launch,
+                    manager: c.manager,
+                    max_vcores: c.max_vcores,
+                    services: c.allowed_services.clone()
+                }),
+                (_, _) => None
+            };
+            (restart, c.count_vcores() == 0)
+        },
+        None => return Err(Cause::CapsuleBadID)
+    };
+
+    /* stop the capsule receiving any more requests or input */
+    service::deregister_capsule(cid);
+    service::forget_capsule(cid);
+    console::destroy(cid);
+
+    /* take back memory this capsule shared with others before its RAM can be freed */
+    grant::forget_capsule(cid);
+
+    if idle == true
+    {
+        /* nothing can be using the capsule's memory so release it now */
+        release(cid);
     }
     else
     {
-        Err(Cause::CapsuleBadID)
+        /* waiting virtual cores are purged from the global queue here, and physical cores purge their own
+        queues and running virtual core when they receive this message. the capsule is released when the last
+        of its virtual cores is dropped. see remove_vcore() */
+        scheduler::purge_capsule(cid);
+        let msg = message::Message::new(message::Recipient::send_to_all(), message::MessageContent::CapsuleTeardown(cid));
+        if let Err(e) = message::send(msg)
+        {
+            hvalert!("BUG: Could not broadcast teardown of capsule {} ({:?})", cid, e);
+        }
+    }
+
+    Ok(restart)
+}
+
+/* free a dying capsule's memory and remove it from the system
+   => cid = ID of capsule to release */
+fn release(cid: CapsuleID)
+{
+    let victim = CAPSULES.lock().remove(&cid);
+    if let Some(c) = victim
+    {
+        hvdebug!("Releasing capsule {}", cid);
+        drop(c); // see above implementation of drop for Capsule
+    }
+}
+
+/* handle a capsule's supervisor crashing. capsules flagged to auto-restart are
+   destroyed and then re-created from their original image and configuration, after
+   a delay that grows with each consecutive crash. other capsules are just destroyed.
+   => cid = ID of capsule that crashed
+   <= Ok for success, or an error code */
+pub fn crashed(cid: CapsuleID) -> Result<(), Cause>
+{
+    teardown_and_restart(cid, true)
+}
+
+/* restart a capsule at its supervisor's request. this is immediate and doesn't count
+   as a crash. capsules that aren't flagged to auto-restart can't be re-created, so they
+   are destroyed instead
+   => cid = ID of capsule to restart
+   <= Ok for success, or an error code */
+pub fn reboot(cid: CapsuleID) -> Result<(), Cause>
+{
+    teardown_and_restart(cid, false)
+}
+
+/* destroy a capsule and, if it's flagged to auto-restart, queue it to be re-created
+   => cid = ID of capsule to restart
+      crashed = true if the capsule crashed, or false if it asked to be restarted
+   <= Ok for success, or an error code */
+fn teardown_and_restart(cid: CapsuleID, crashed: bool) -> Result<(), Cause>
+{
+    /* the restarted capsule gets a new ID and memory, so it doesn't need to wait for this one to go */
+    match teardown(cid)?
+    {
+        Some(mut r) =>
+        {
+            /* forget earlier crashes if the capsule ran long enough to be considered stable */
+            if let (Some(started), Some(now)) = (r.launch.started, hardware::scheduler_get_timer_now())
+            {
+                if now.saturating_sub(started) >= milliseconds_to_ticks(RESTART_STABLE_PERIOD)
+                {
+                    r.launch.restarts = 0;
+                }
+            }
+            queue_restart(r, crashed);
+        },
+        None => hvdebug!("Capsule {} will not be restarted", cid)
+    }
+
+    Ok(())
+}
+
+/* queue a capsule to be restarted, unless it has crashed too many times in a row
+   => restart = capsule to restart
+      crashed = true to count this as a crash and back off, or false to restart right away */
+fn queue_restart(mut restart: Restart, crashed: bool)
+{
+    let mut delay = 0;
+    if crashed == true
+    {
+        restart.launch.restarts = restart.launch.restarts + 1;
+        if restart.launch.restarts > RESTART_LIMIT
+        {
+            hvalert!("Capsule {} crashed {} times in a row, giving up restarting it",
+                     restart.previous, restart.launch.restarts);
+            return;
+        }
+
+        delay = RESTART_BACKOFF_INITIAL << (restart.launch.restarts - 1);
+        if delay > RESTART_BACKOFF_MAX
+        {
+            delay = RESTART_BACKOFF_MAX;
+        }
+    }
+
+    hvdebug!("Restarting capsule {} in {} ms (consecutive crashes: {})",
+             restart.previous, delay, restart.launch.restarts);
+
+    restart.due = match hardware::scheduler_get_timer_now()
+    {
+        Some(now) => Some(now + milliseconds_to_ticks(delay)),
+        None => None
+    };
+    RESTARTS.lock().push(restart);
+}
+
+/* re-create any crashed capsules whose backoff delay has passed. call this regularly
+   from all physical CPU cores. capsules that can't be re-created are queued again,
+   counting as another crash */
+pub fn restart_waiting()
+{
+    let now = hardware::scheduler_get_timer_now();
+    let mut ready: Vec<Restart> = Vec::new();
+    {
+        let mut waiting = RESTARTS.lock();
+        let mut index = 0;
+        while index < waiting.len()
+        {
+            match (waiting[index].due, now)
+            {
+                (Some(due), Some(now)) if now < due => index = index + 1,
+                (_, _) => ready.push(waiting.remove(index))
+            }
+        }
+    }
+
+    for restart in ready
+    {
+        let launch = restart.launch;
+        match launch_capsule(launch.image, launch.ram, launch.quota, restart.max_vcores, launch.prio,
+                             restart.manager, Some(launch), restart.services.clone())
+        {
+            Ok(cid) => hvdebug!("Capsule {} restarted as capsule {}", restart.previous, cid),
+            Err(e) =>
+            {
+                hvalert!("Failed to restart capsule {} ({:?})", restart.previous, e);
+                queue_restart(restart, true);
+            }
+        }
+    }
+}
+
+/* convert milliseconds into timer ticks, or zero if the timer frequency is unknown */
+fn milliseconds_to_ticks(ms: u64) -> u64
+{
+    match hardware::get_timebase_frequency()
+    {
+        Some(freq) => (ms * freq) / MILLISECONDS_PER_SECOND,
+        None => 0
+    }
+}
+
+/* remove a virtual core from a capsule's list of registered virtual cores. this is called
+   when a virtual core is dropped. if the capsule is dying and that was its last virtual core,
+   the capsule is released
+   => cid = capsule ID
+      vid = virtual core ID to remove
+   <= true if the virtual core was registered, or false if not or the capsule doesn't exist */
+pub fn remove_vcore(cid: CapsuleID, vid: VirtualCoreID) -> bool
+{
+    let (removed, last) = match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) =>
+        {
+            let removed = c.remove_vcore(vid);
+            (removed, c.is_dying() && c.count_vcores() == 0)
+        },
+        None => (false, false)
+    };
+
+    if last == true
+    {
+        release(cid);
+    }
+    removed
+}
+
+/* raise an external interrupt in a capsule to tell it messages are waiting. the interrupt is
+   raised in the capsule's lowest-numbered virtual core, and delivered by the physical CPU core
+   running it, or when it next runs if it isn't running
+   => cid = ID of capsule to notify */
+pub fn notify(cid: CapsuleID)
+{
+    let target = match CAPSULES.lock().get(&cid)
+    {
+        Some(c) if c.is_dying() == false => c.vcores.iter().min().cloned(),
+        _ => None
+    };
+
+    if let Some(vid) = target
+    {
+        vcore::raise(vcore::VirtualCoreCanonicalID { capsuleid: cid, vcoreid: vid }, vcore::Pending::ExternalIRQ);
+    }
+}
+
+/* return true if the capsule exists and isn't being torn down, or false if it can't run */
+pub fn is_alive(cid: CapsuleID) -> bool
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => c.is_dying() == false,
+        None => false
+    }
+}
+
+/* check whether a capsule has the given virtual core registered
+   => cid = capsule ID
+      vid = virtual core ID to check
+   <= Some true if registered, Some false if not, or None if the capsule doesn't exist */
+pub fn has_vcore(cid: CapsuleID, vid: VirtualCoreID) -> Option<bool>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Some(c.has_vcore(vid)),
+        None => None
+    }
+}
+
+/* return the maximum number of virtual cores a capsule can have, or None if the capsule doesn't exist */
+pub fn get_max_vcores(cid: CapsuleID) -> Option<usize>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Some(c.get_max_vcores()),
+        None => None
+    }
+}
+
+/* translate an address in a capsule's memory into a host physical address
+   => cid = capsule ID
+      addr = capsule's address to translate
+   <= Some host physical address, or None if the capsule doesn't exist or the address isn't mapped */
+pub fn translate(cid: CapsuleID, addr: VirtMemBase) -> Option<PhysMemBase>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) =>
+        {
+            for mapping in c.memory.iter()
+            {
+                if let Some(phys) = mapping.virtual_to_physical(addr)
+                {
+                    return Some(phys);
+                }
+            }
+            None
+        },
+        None => None
+    }
+}
+
+/* translate a range of a capsule's memory into a region of host physical memory.
+   the range must fit within a single one of the capsule's memory mappings
+   => cid = capsule ID
+      addr = capsule's address of the start of the range
+      size = number of bytes in the range
+   <= Some physical memory region, or None if the capsule doesn't exist or the range isn't mapped */
+pub fn translate_region(cid: CapsuleID, addr: VirtMemBase, size: PhysMemSize) -> Option<Region>
+{
+    find_region(cid, addr, size, |_| true)
+}
+
+/* translate a range of a capsule's memory that the capsule can write to, such as a record the
+   hypervisor will fill in, into a region of host physical memory. see translate_region() */
+pub fn translate_writable_region(cid: CapsuleID, addr: VirtMemBase, size: PhysMemSize) -> Option<Region>
+{
+    find_region(cid, addr, size, |m| m.get_access().is_writable())
+}
+
+/* translate a range of a capsule's own memory, rather than memory shared with it by other capsules,
+   into a region of host physical memory. see translate_region() */
+pub fn translate_owned_region(cid: CapsuleID, addr: VirtMemBase, size: PhysMemSize) -> Option<Region>
+{
+    find_region(cid, addr, size, |m| m.get_grant().is_none())
+}
+
+/* translate a range of a capsule's memory into a region of host physical memory
+   using the first of the capsule's mappings that covers the range and is accepted
+   => cid, addr, size = see translate_region()
+      accept = return true if the given mapping can be used
+   <= Some physical memory region, or None if no such mapping covers the range */
+fn find_region(cid: CapsuleID, addr: VirtMemBase, size: PhysMemSize, accept: fn(&Mapping) -> bool) -> Option<Region>
+{
+    let last = match (size, addr.checked_add(size))
+    {
+        (0, _) | (_, None) => return None,
+        (_, Some(end)) => end - 1
+    };
+
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) =>
+        {
+            for mapping in c.memory.iter().filter(|m| accept(m))
+            {
+                if let (Some(base), Some(_)) = (mapping.virtual_to_physical(addr), mapping.virtual_to_physical(last))
+                {
+                    return Some(Region::new(base, size));
+                }
+            }
+            None
+        },
+        None => None
+    }
+}
+
+/* check whether a capsule is allowed to create and manage other capsules
+   => cid = capsule ID to check
+   <= Some true if the capsule exists and is a manager, Some false if it
+      exists and is not, or None if the capsule doesn't exist */
+pub fn is_manager(cid: CapsuleID) -> Option<bool>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Some(c.is_manager()),
+        None => None
+    }
+}
+
+/* describe how much physical RAM a capsule holds */
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryUsage
+{
+    pub used: PhysMemSize,  /* bytes of RAM allocated on the capsule's behalf */
+    pub quota: PhysMemSize  /* most bytes of RAM the capsule can hold */
+}
+
+/* <= Some amount of physical RAM a capsule holds, or None if the capsule doesn't exist.
+   memory shared with the capsule by other capsules counts against its owner, not the capsule */
+pub fn memory_usage(cid: CapsuleID) -> Option<MemoryUsage>
+{
+    match CAPSULES.lock().get(&cid)
+    {
+        Some(c) => Some(MemoryUsage { used: c.used, quota: c.quota }),
+        None => None
     }
 }
 
@@ -289,14 +1234,14 @@ pub fn is_service_allowed(cid: CapsuleID, sid: ServiceID) -> Option<bool>
 /* add a memory mapping to a capsule
    cid = ID of capsule to add the mapping to
    to_map = memory mapping object to add
-   Ok for success, or an error code
+   Ok for success, or an error code. the mapping is refused if the hardware
+   can't protect all of the capsule's physical RAM regions at the same time
 */
 pub fn map_memory(cid: CapsuleID, to_map: Mapping) -> Result<(), Cause>
 {
     if let Occupied(mut c) = CAPSULES.lock().entry(cid)
     {
-        c.get_mut().set_memory_mapping(to_map);
-        return Ok(());
+        return c.get_mut().set_memory_mapping(to_map);
     }
     else
     {
@@ -304,38 +1249,178 @@ pub fn map_memory(cid: CapsuleID, to_map: Mapping) -> Result<(), Cause>
     }
 }
 
+/* map memory shared by another capsule's grant into a capsule
+   => cid = ID of capsule to receive the memory
+      id = ID of the grant
+      region = host physical memory to share
+      access = how the capsule may access the memory
+   <= capsule's address of the shared memory, or an error code */
+pub fn map_grant(cid: CapsuleID, id: GrantID, region: Region, access: Access) -> Result<VirtMemBase, Cause>
+{
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) if c.is_dying() == false =>
+        {
+            /* overlapping protected regions with different permissions can't be enforced reliably */
+            for mapping in c.memory.iter()
+            {
+                match mapping.get_physical()
+                {
+                    Some(r) if r.overlaps(&region) => return Err(Cause::GrantOverlap),
+                    _ => ()
+                }
+            }
+
+            let base = c.place_memory(region);
+            let mut mapping = Mapping::new();
+            mapping.set_physical(region);
+            mapping.set_virtual(base);
+            mapping.set_grant(id, access);
+            c.set_memory_mapping(mapping)?;
+            Ok(base)
+        },
+        _ => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* give a capsule more RAM, charged to its quota, when its balloon driver asks for it
+   => cid = ID of capsule to give RAM to
+      pages = number of pages of RAM to give it
+   <= capsule's address of the new RAM, or an error code */
+pub fn add_memory(cid: CapsuleID, pages: usize) -> Result<VirtMemBase, Cause>
+{
+    let size = match pages.checked_mul(physmem::PAGE_SIZE)
+    {
+        Some(s) if s > 0 => s,
+        _ => return Err(Cause::CapsuleBadParameter)
+    };
+
+    let ram = alloc_memory(cid, size)?;
+    let result = match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) if c.is_dying() == false =>
+        {
+            let base = c.place_memory(ram);
+            let mut mapping = Mapping::new();
+            mapping.set_physical(ram);
+            mapping.set_virtual(base);
+            c.set_memory_mapping(mapping).and(Ok(base))
+        },
+        _ => Err(Cause::CapsuleBadID)
+    };
+
+    if result.is_err()
+    {
+        free_memory(cid, ram);
+    }
+    result
+}
+
+/* take back RAM a capsule's balloon driver has given up. any grants of the RAM to other capsules
+   are revoked, and physical CPU cores running the capsule are told to reload its memory protection
+   => cid = ID of capsule giving up the RAM
+      base = capsule's page-aligned address of the RAM
+      pages = number of pages of RAM to take back
+   <= Ok for success, or an error code */
+pub fn return_memory(cid: CapsuleID, base: VirtMemBase, pages: usize) -> Result<(), Cause>
+{
+    let size = match pages.checked_mul(physmem::PAGE_SIZE)
+    {
+        Some(s) if s > 0 && base % physmem::PAGE_SIZE == 0 => s,
+        _ => return Err(Cause::CapsuleBadAddress)
+    };
+
+    let removed = match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) if c.is_dying() == false => c.remove_memory(base, size)?,
+        _ => return Err(Cause::CapsuleBadID)
+    };
+
+    grant::revoke_region(cid, removed);
+    revoke_access(cid);
+
+    /* the RAM isn't handed to another capsule until every physical CPU core has stopped
+    the capsule reaching it, and it has been scrubbed */
+    free_memory(cid, removed);
+    Ok(())
+}
+
+/* ask a capsule's balloon driver to give some of its RAM back to the hypervisor. the request
+   waits in the capsule's inbox, and the driver returns what it can using the memory_return hypercall
+   => cid = ID of capsule to ask
+      pages = number of pages of RAM to ask for
+   <= Ok for success, or an error code */
+pub fn request_memory_return(cid: CapsuleID, pages: usize) -> Result<(), Cause>
+{
+    if pages == 0
+    {
+        return Err(Cause::CapsuleBadParameter);
+    }
+
+    let msg = message::Message::new(message::Recipient::send_to_capsule(cid), message::MessageContent::MemoryReturnRequest(pages));
+    message::send(msg)
+}
+
+/* remove memory shared by a grant from a capsule. use grant::revoke() rather than calling this directly,
+   so that physical CPU cores running the capsule are told to reload its memory protection
+   => cid = ID of capsule to remove the memory from
+      id = ID of the grant
+   <= true if the grant was mapped into the capsule, or false if not */
+pub fn unmap_grant(cid: CapsuleID, id: GrantID) -> bool
+{
+    match CAPSULES.lock().get_mut(&cid)
+    {
+        Some(c) => c.clear_grant_mapping(id),
+        None => false
+    }
+}
+
 /* enforce hardware security restrictions for the given capsule.
    supervisor-level code will only be able to access the physical
    RAM covered by that assigned to the given capsule. call this
    when switching to a capsule, so that the previous enforcement is
    replaced by enforcement of this capsule. 
    => id = capsule to enforce
-   <= true for success, or fail for failure
+   <= Ok for success, or an error code for failure, in which case
+      supervisor-level code is denied access to all physical RAM
 */
-pub fn enforce(id: CapsuleID) -> bool
+pub fn enforce(id: CapsuleID) -> Result<(), Cause>
 {
-    /* this is a filthy hardcode hack that I hate but it's needed for now */
-    let mut index = 0;
-
-    match CAPSULES.lock().entry(id)
+    let regions = match CAPSULES.lock().get(&id)
     {
-        Occupied(c) => 
+        Some(c) =>
         {
-            for mapping in c.get().get_memory_mappings()
+            /* switch to the capsule's G-stage page tables, if it has them. stale translations
+            may be cached from the last time this capsule ran here, so flush them out */
+            if let Some(table) = c.gstage.as_ref()
             {
-                if let Some(r) = mapping.get_physical()
-                {
-                    if index > 0
-                    {
-                        panic!("TODO / FIXME: Capsules can't have more than one physical RAM region");
-                    }
-
-                    r.grant_access();
-                    index = index + 1;
-                }
+                platform::virtmem::load_gstage(table.get_mode() as usize, id, table.get_root());
+                platform::virtmem::flush_gstage();
             }
-            return true
+
+            /* physical memory protection is still enforced as a second line of defense */
+            c.get_physical_regions()
         },
-        _ => false
+        None =>
+        {
+            physmem::revoke_access_from(0);
+            return Err(Cause::CapsuleBadID);
+        }
+    };
+
+    if regions.len() > physmem::max_protected_regions()
+    {
+        physmem::revoke_access_from(0);
+        return Err(Cause::CapsuleTooManyRegions);
     }
+
+    /* give each region its own protection slot, and clear out
+    any slots left over from the previous capsule */
+    for (slot, (region, access)) in regions.iter().enumerate()
+    {
+        region.grant_access(slot, access.permissions());
+    }
+    physmem::revoke_access_from(regions.len());
+
+    Ok(())
 }
@@ -0,0 +1,298 @@
+/* diosix virtual consoles for capsules
+ *
+ * Each capsule gets its own virtual console, accessed through the SBI's
+ * putchar and getchar calls. Output is buffered per capsule and written
+ * to the hypervisor's debug port tagged with the capsule's ID. Input
+ * from the debug port is routed to one selected capsule at a time.
+ * Press ctrl-a then n to switch input to the next capsule, or ctrl-a
+ * twice to send a ctrl-a to the selected capsule.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::vec_deque::VecDeque;
+use hashbrown::hash_map::HashMap;
+use super::capsule::CapsuleID;
+use super::error::Cause;
+use super::hardware;
+use super::heap;
+
+/* press this key, ctrl-a, followed by a command key to control console input */
+const ESCAPE_KEY: u8 = 0x01;
+const COMMAND_NEXT_CAPSULE: u8 = b'n';
+
+/* maximum number of bytes buffered in each direction per capsule */
+const CONSOLE_OUTPUT_MAX: usize = 1024;
+const CONSOLE_INPUT_MAX: usize = 256;
+
+lazy_static!
+{
+    /* acquire CONSOLES lock before accessing any capsule's virtual console */
+    static ref CONSOLES: Mutex<Consoles> = Mutex::new(Consoles::new());
+}
+
+/* poll the debug port for input on idle physical CPU cores */
+macro_rules! consolehousekeeper
+{
+    () => ($crate::console::poll_input());
+}
+
+/* describe a capsule's virtual console */
+struct VirtualConsole
+{
+    output: Vec<u8>,        /* raw output awaiting writing to the debug port */
+    input: VecDeque<u8>,    /* input awaiting reading by the capsule */
+    line_start: bool        /* true if the next output begins a new line */
+}
+
+impl VirtualConsole
+{
+    pub fn new() -> VirtualConsole
+    {
+        VirtualConsole
+        {
+            output: Vec::new(),
+            input: VecDeque::new(),
+            line_start: true
+        }
+    }
+}
+
+/* all the virtual consoles, and which one is receiving input */
+struct Consoles
+{
+    consoles: HashMap<CapsuleID, VirtualConsole>,
+    selected: Option<CapsuleID>,
+    escaped: bool           /* true if the escape key was the last key pressed */
+}
+
+impl Consoles
+{
+    pub fn new() -> Consoles
+    {
+        Consoles
+        {
+            consoles: HashMap::new(),
+            selected: None,
+            escaped: false
+        }
+    }
+
+    /* move input to the capsule with the next highest ID, wrapping around to the lowest */
+    fn select_next(&mut self)
+    {
+        let current = self.selected.unwrap_or(0);
+        let mut next: Option<CapsuleID> = None;
+        let mut lowest: Option<CapsuleID> = None;
+
+        for &cid in self.consoles.keys()
+        {
+            if cid > current && (next.is_none() || cid < next.unwrap())
+            {
+                next = Some(cid);
+            }
+            if lowest.is_none() || cid < lowest.unwrap()
+            {
+                lowest = Some(cid);
+            }
+        }
+
+        self.selected = match next
+        {
+            Some(cid) => Some(cid),
+            None => lowest
+        };
+
+        if let Some(cid) = self.selected
+        {
+            hvlog!("Console input now goes to capsule {}", cid);
+        }
+    }
+
+    /* handle a key pressed on the debug port */
+    fn input(&mut self, key: u8)
+    {
+        match (self.escaped, key)
+        {
+            (false, ESCAPE_KEY) =>
+            {
+                self.escaped = true;
+                return;
+            },
+            (true, COMMAND_NEXT_CAPSULE) => self.select_next(),
+            (true, ESCAPE_KEY) | (false, _) =>
+            {
+                /* pass the key onto the selected capsule, dropping it if the capsule isn't keeping up */
+                if let Some(cid) = self.selected
+                {
+                    if let Some(console) = self.consoles.get_mut(&cid)
+                    {
+                        if console.input.len() < CONSOLE_INPUT_MAX
+                        {
+                            console.input.push_back(key);
+                        }
+                    }
+                }
+            },
+            (true, _) => () /* ignore unknown commands */
+        }
+
+        self.escaped = false;
+    }
+
+    /* write a capsule's buffered output to the debug port, tagging each new line with the capsule's ID.
+    the debug port takes text, so bytes that aren't valid UTF-8 are replaced, and unless everything
+    must be written out, a character split across the end of the buffer is kept until the rest arrives
+    => cid = ID of the capsule
+       everything = true to write out all buffered output, or false to allow a split character to be kept */
+    fn flush(&mut self, cid: CapsuleID, everything: bool)
+    {
+        if let Some(console) = self.consoles.get_mut(&cid)
+        {
+            let finish = match everything
+            {
+                true => console.output.len(),
+                false => console.output.len() - incomplete_tail(&console.output)
+            };
+
+            let mut start = 0;
+            while start < finish
+            {
+                let end = match console.output[start..finish].iter().position(|&c| c == b'\n')
+                {
+                    Some(index) => start + index + 1,
+                    None => finish
+                };
+
+                let line = &console.output[start..end];
+                let text = String::from_utf8_lossy(line);
+                if console.line_start == true
+                {
+                    hvprint!("[capsule {}] {}", cid, text);
+                }
+                else
+                {
+                    hvprint!("{}", text);
+                }
+
+                console.line_start = line.ends_with(b"\n");
+                start = end;
+            }
+            console.output.drain(..finish);
+        }
+    }
+}
+
+/* => output = raw console output
+   <= number of bytes at the end of output that start a multi-byte UTF-8 character still missing its final bytes */
+fn incomplete_tail(output: &[u8]) -> usize
+{
+    /* a UTF-8 character is at most four bytes long, so look back no further than three bytes */
+    for back in 1..(output.len().min(3) + 1)
+    {
+        let c = output[output.len() - back];
+        let length = match c
+        {
+            0x80..=0xbf => continue, /* continuation byte: keep looking for the first byte */
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xff => 4,
+            _ => 1
+        };
+
+        return match length > back
+        {
+            true => back,
+            false => 0
+        };
+    }
+    0
+}
+
+/* create a virtual console for a capsule. the first capsule to get a console receives input
+   => cid = ID of the capsule
+   <= Ok for success, or an error code if there isn't enough memory */
+pub fn create(cid: CapsuleID) -> Result<(), Cause>
+{
+    let mut consoles = CONSOLES.lock();
+    heap::reserved(consoles.consoles.try_reserve(1))?;
+    consoles.consoles.insert(cid, VirtualConsole::new());
+    if consoles.selected.is_none()
+    {
+        consoles.selected = Some(cid);
+    }
+    Ok(())
+}
+
+/* destroy a capsule's virtual console, writing out any remaining output. if the capsule
+   was receiving input, move input to another capsule
+   => cid = ID of the capsule */
+pub fn destroy(cid: CapsuleID)
+{
+    let mut consoles = CONSOLES.lock();
+    consoles.flush(cid, true);
+    consoles.consoles.remove(&cid);
+    if consoles.selected == Some(cid)
+    {
+        consoles.selected = None;
+        consoles.select_next();
+    }
+}
+
+/* write a character to a capsule's console. the output is written to the debug port
+   at the end of each line, when the buffer is full, or straight away if the capsule
+   has the user's attention
+   => cid = ID of the capsule
+      c = character to write */
+pub fn putchar(cid: CapsuleID, c: u8)
+{
+    let mut consoles = CONSOLES.lock();
+    let selected = consoles.selected == Some(cid);
+    let flush = match consoles.consoles.get_mut(&cid)
+    {
+        Some(console) =>
+        {
+            console.output.push(c);
+            match console.output.len() >= CONSOLE_OUTPUT_MAX
+            {
+                true => Some(true),
+                false if selected || c == b'\n' => Some(false),
+                false => None
+            }
+        },
+        None => None
+    };
+
+    if let Some(everything) = flush
+    {
+        consoles.flush(cid, everything);
+    }
+}
+
+/* read a character from a capsule's console
+   => cid = ID of the capsule
+   <= Some character, or None if there is nothing to read */
+pub fn getchar(cid: CapsuleID) -> Option<u8>
+{
+    poll_input();
+    match CONSOLES.lock().consoles.get_mut(&cid)
+    {
+        Some(console) => console.input.pop_front(),
+        None => None
+    }
+}
+
+/* read any waiting keys from the debug port and route them to the selected capsule */
+pub fn poll_input()
+{
+    let mut consoles = CONSOLES.lock();
+    while let Some(key) = hardware::read_debug_char()
+    {
+        consoles.input(key);
+    }
+}
@@ -10,6 +10,12 @@ use spin::Mutex;
 use alloc::string::String;
 use super::hardware;
 
+/* size of the stack buffer used to write straight to the debug port */
+const DIRECT_BUFFER_SIZE: usize = 128;
+
+/* give up writing straight to the debug port after this many attempts to get at the hardware */
+const DIRECT_WRITE_ATTEMPTS: usize = 1000;
+
 lazy_static!
 {
     pub static ref DEBUG_LOCK: Mutex<bool> = Mutex::new(false);
@@ -33,6 +39,21 @@ macro_rules! hvalert
     ($fmt:expr, $($arg:tt)*) => (hvprintln!(concat!("[!] CPU {}: ", $fmt), $crate::pcore::PhysicalCore::get_id(), $($arg)*));
 }
 
+/* bad news that must get out even if the heap can't be used, such as when it has run out of memory.
+this is written straight to the debug port rather than queued, so it may appear out of order */
+#[macro_export]
+macro_rules! hvalert_now
+{
+    ($fmt:expr) => ({
+        use core::fmt::Write;
+        let _ = writeln!($crate::debug::DirectWriter::new(), "[!] CPU {}: {}", $crate::pcore::PhysicalCore::get_id(), $fmt);
+    });
+    ($fmt:expr, $($arg:tt)*) => ({
+        use core::fmt::Write;
+        let _ = writeln!($crate::debug::DirectWriter::new(), concat!("[!] CPU {}: ", $fmt), $crate::pcore::PhysicalCore::get_id(), $($arg)*);
+    });
+}
+
 /* only output if debug build is enabled */
 #[macro_export]
 #[cfg(debug_assertions)]
@@ -95,6 +116,95 @@ impl fmt::Write for ConsoleWriter
     }
 }
 
+/* write straight to the debug port from a buffer on the stack, skipping the queue, so that nothing is
+allocated and the debug lock isn't needed. output is written a line, or a buffer, at a time */
+pub struct DirectWriter
+{
+    buffer: [u8; DIRECT_BUFFER_SIZE],
+    used: usize
+}
+
+impl DirectWriter
+{
+    pub fn new() -> DirectWriter
+    {
+        DirectWriter
+        {
+            buffer: [0; DIRECT_BUFFER_SIZE],
+            used: 0
+        }
+    }
+
+    /* write out the buffer, keeping back any character split across the end of it */
+    pub fn flush(&mut self)
+    {
+        let valid = match core::str::from_utf8(&self.buffer[..self.used])
+        {
+            Ok(_) => self.used,
+            Err(e) => e.valid_up_to()
+        };
+
+        let text = unsafe { core::str::from_utf8_unchecked(&self.buffer[..valid]) };
+        for _ in 0..DIRECT_WRITE_ATTEMPTS
+        {
+            if hardware::write_debug_string(text) == true
+            {
+                break;
+            }
+        }
+
+        self.buffer.copy_within(valid..self.used, 0);
+        self.used = self.used - valid;
+    }
+}
+
+impl fmt::Write for DirectWriter
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result
+    {
+        for &byte in s.as_bytes()
+        {
+            if self.used == DIRECT_BUFFER_SIZE
+            {
+                self.flush();
+            }
+
+            self.buffer[self.used] = byte;
+            self.used = self.used + 1;
+            if byte == b'\n'
+            {
+                self.flush();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DirectWriter
+{
+    fn drop(&mut self)
+    {
+        self.flush();
+    }
+}
+
+/* empty the queue out to the debug port before this physical CPU core halts. if this core was
+adding to the queue when things went wrong, it holds the queue's lock, so don't wait for it */
+pub fn flush_queue()
+{
+    if let Some(mut queue) = DEBUG_QUEUE.try_lock()
+    {
+        for _ in 0..DIRECT_WRITE_ATTEMPTS
+        {
+            if queue.len() == 0 || hardware::write_debug_string(&queue) == true
+            {
+                queue.clear();
+                return;
+            }
+        }
+    }
+}
+
 /* attempt to empty queue out to the debug port */
 pub fn drain_queue()
 {
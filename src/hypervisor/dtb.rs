@@ -0,0 +1,168 @@
+/* diosix device tree generator for capsules
+ *
+ * Describe to a capsule's supervisor kernel the virtualized
+ * environment it has been loaded into: its RAM, its virtual
+ * CPU cores, its console and its timer. The output is a
+ * flattened device tree blob that is copied into the
+ * capsule's RAM and handed to the supervisor at boot.
+ *
+ * (c) Chris Williams, 2019-2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use devicetree::{DeviceTree, DeviceTreeProperty};
+use super::error::Cause;
+use super::virtmem::Mapping;
+use super::vcore::VirtualCoreID;
+
+/* keep the generated blob aligned to this many bytes within capsule RAM */
+pub const DTB_ALIGNMENT: usize = 4096;
+
+/* the virtual console is provided through the SBI, so tell the supervisor to use it */
+const CAPSULE_BOOTARGS: &'static str = "console=hvc0 earlycon=sbi";
+
+/* RISC-V ISA string for the virtual CPU cores, matching this build of the hypervisor */
+#[cfg(all(target_pointer_width = "64", target_feature = "d"))]
+const CAPSULE_ISA: &'static str = "rv64imafdc";
+#[cfg(all(target_pointer_width = "64", not(target_feature = "d")))]
+const CAPSULE_ISA: &'static str = "rv64imac";
+#[cfg(target_pointer_width = "32")]
+const CAPSULE_ISA: &'static str = "rv32imac";
+
+/* the virtual memory scheme available to supervisors on this build */
+#[cfg(target_pointer_width = "64")]
+const CAPSULE_MMU: &'static str = "riscv,sv39";
+#[cfg(target_pointer_width = "32")]
+const CAPSULE_MMU: &'static str = "riscv,sv32";
+
+/* generate a device tree blob describing a capsule's virtualized environment
+   => vcores = number of virtual CPU cores in the capsule
+      mappings = the capsule's memory mappings. each must have a virtual base and
+                 physical region defined: the supervisor will be told the RAM is found
+                 at the virtual base addresses
+      timebase = frequency of the capsule's timer in ticks per second, which must fit in 32 bits
+   <= array of bytes containing the device tree blob, or an error code */
+pub fn generate(vcores: usize, mappings: &Vec<Mapping>, timebase: u64) -> Result<Vec<u8>, Cause>
+{
+    /* the frequency is described in a single 32-bit cell */
+    if timebase > u32::max_value() as u64
+    {
+        return Err(Cause::DeviceTreeBadTimebase);
+    }
+
+    let mut dt = DeviceTree::new();
+
+    /* describe the top level of the virtual machine. all addresses and sizes are 64-bit */
+    let root = String::from("/");
+    dt.edit_property(&root, &String::from("#address-cells"), DeviceTreeProperty::UnsignedInt32(2));
+    dt.edit_property(&root, &String::from("#size-cells"), DeviceTreeProperty::UnsignedInt32(2));
+    dt.edit_property(&root, &String::from("compatible"), DeviceTreeProperty::Text(String::from("diosix,capsule")));
+    dt.edit_property(&root, &String::from("model"), DeviceTreeProperty::Text(String::from("diosix virtualized environment")));
+
+    /* tell the supervisor where to find its console */
+    let chosen = String::from("/chosen");
+    dt.edit_property(&chosen, &String::from("bootargs"), DeviceTreeProperty::Text(String::from(CAPSULE_BOOTARGS)));
+
+    /* describe the virtual CPU cores. the timer frequency is defined here, too */
+    let cpus = String::from("/cpus");
+    dt.edit_property(&cpus, &String::from("#address-cells"), DeviceTreeProperty::UnsignedInt32(1));
+    dt.edit_property(&cpus, &String::from("#size-cells"), DeviceTreeProperty::UnsignedInt32(0));
+    dt.edit_property(&cpus, &String::from("timebase-frequency"), DeviceTreeProperty::UnsignedInt32(timebase as u32));
+
+    for vcoreid in 0..vcores
+    {
+        add_vcore(&mut dt, vcoreid);
+    }
+
+    /* describe each block of RAM the capsule can see */
+    for mapping in mappings.iter()
+    {
+        match (mapping.get_virtual(), mapping.get_physical())
+        {
+            (Some(base), Some(region)) =>
+            {
+                let memory = format!("/memory@{:x}", base);
+                dt.edit_property(&memory, &String::from("device_type"), DeviceTreeProperty::Text(String::from("memory")));
+                dt.edit_property(&memory, &String::from("reg"),
+                    DeviceTreeProperty::MultipleUnsignedInt64_64(vec!((base as u64, region.size() as u64))));
+            },
+            (_, _) => return Err(Cause::VirtMemPhysNotSet)
+        }
+    }
+
+    match dt.to_blob()
+    {
+        Ok(blob) => Ok(blob),
+        Err(_) => Err(Cause::DeviceTreeCreateFailed)
+    }
+}
+
+/* add a virtual CPU core and its interrupt controller to the given device tree
+   => dt = device tree to update
+      vcoreid = ID of the virtual core to add */
+fn add_vcore(dt: &mut DeviceTree, vcoreid: VirtualCoreID)
+{
+    let cpu = format!("/cpus/cpu@{}", vcoreid);
+    dt.edit_property(&cpu, &String::from("device_type"), DeviceTreeProperty::Text(String::from("cpu")));
+    dt.edit_property(&cpu, &String::from("reg"), DeviceTreeProperty::UnsignedInt32(vcoreid as u32));
+    dt.edit_property(&cpu, &String::from("status"), DeviceTreeProperty::Text(String::from("okay")));
+    dt.edit_property(&cpu, &String::from("compatible"), DeviceTreeProperty::Text(String::from("riscv")));
+    dt.edit_property(&cpu, &String::from("riscv,isa"), DeviceTreeProperty::Text(String::from(CAPSULE_ISA)));
+    dt.edit_property(&cpu, &String::from("mmu-type"), DeviceTreeProperty::Text(String::from(CAPSULE_MMU)));
+
+    /* each core has its own local interrupt controller for timer and software interrupts */
+    let intc = format!("{}/interrupt-controller", cpu);
+    dt.edit_property(&intc, &String::from("#interrupt-cells"), DeviceTreeProperty::UnsignedInt32(1));
+    dt.edit_property(&intc, &String::from("interrupt-controller"), DeviceTreeProperty::Empty);
+    dt.edit_property(&intc, &String::from("compatible"), DeviceTreeProperty::Text(String::from("riscv,cpu-intc")));
+}
+
+/* check a generated device tree can be parsed back, and that it describes what we asked for */
+#[test_case]
+fn test_generate()
+{
+    use super::physmem::Region;
+
+    let mut mapping = Mapping::new();
+    mapping.set_physical(Region::new(0x80000000, 0x8000000));
+    mapping.identity_mapping().unwrap();
+
+    let blob = generate(2, &vec!(mapping), 10000000).unwrap();
+    let dt = DeviceTree::from_blob(unsafe { &*(blob.as_ptr() as *const devicetree::DeviceTreeBlob) }).unwrap();
+
+    match dt.get_property(&String::from("/memory@80000000"), &String::from("reg"))
+    {
+        Ok(DeviceTreeProperty::MultipleUnsignedInt64_64(reg)) => assert_eq!(reg[0], (0x80000000, 0x8000000)),
+        _ => panic!("capsule RAM missing from generated device tree")
+    };
+
+    match dt.get_property(&String::from("/cpus"), &String::from("timebase-frequency"))
+    {
+        Ok(DeviceTreeProperty::UnsignedInt32(freq)) => assert_eq!(*freq, 10000000),
+        _ => panic!("timebase missing from generated device tree")
+    };
+
+    assert!(dt.get_property(&String::from("/cpus/cpu@1"), &String::from("reg")).is_ok());
+    assert!(dt.get_property(&String::from("/cpus/cpu@2"), &String::from("reg")).is_err());
+}
+
+#[test_case]
+fn test_generate_bad_timebase()
+{
+    use super::physmem::Region;
+
+    let mut mapping = Mapping::new();
+    mapping.set_physical(Region::new(0x80000000, 0x8000000));
+    mapping.identity_mapping().unwrap();
+
+    /* a timer frequency too large for the device tree's 32-bit cell is refused rather than truncated */
+    match generate(1, &vec!(mapping), (u32::max_value() as u64) + 1)
+    {
+        Err(Cause::DeviceTreeBadTimebase) => (),
+        _ => panic!("oversized timebase wasn't refused")
+    };
+    assert!(generate(1, &vec!(mapping), u32::max_value() as u64).is_ok());
+}
@@ -14,6 +14,9 @@ pub enum Cause
 
     /* devices */
     DeviceTreeBad,
+    DeviceTreeCreateFailed,
+    DeviceTreeNoTimebase,
+    DeviceTreeBadTimebase,
 
     /* physical CPU cores */
     PhysicalCoreBadID,
@@ -23,9 +26,13 @@ pub enum Cause
     ServiceAlreadyRegistered,
     ServiceNotAllowed,
     ServiceNotFound,
+    ServiceIDExhaustion,
+    ServiceLimitReached,
 
     /* messages */
     MessageBadType,
+    MessageQueueFull,
+    MessageTooLarge,
 
     /* heap */
     HeapNotInUse,
@@ -37,15 +44,33 @@ pub enum Cause
     PhysNoRAMFound,
     PhysNotEnoughFreeRAM,
     PhysRegionCollision,
-    PhysRegionNoMatch,
+    PhysRegionNotManaged,
+    PhysRegionBadAlignment,
     PhysRegionSplitOutOfBounds,
+    PhysAllocatorBusy,
 
     /* capsule virtual memory */
     VirtMemPhysNotSet,
+    VirtMemBadAlignment,
+    VirtMemCollision,
 
     /* containers */
     CapsuleIDExhaustion,
     CapsuleBadID,
+    CapsuleBadParameter,
+    CapsuleBadAddress,
+    CapsulePermissionDenied,
+    CapsuleTooManyRegions,
+    CapsuleQuotaExceeded,
+    CapsuleVirtualCoreExists,
+
+    /* shared memory grants */
+    GrantIDExhaustion,
+    GrantBadID,
+    GrantBadRange,
+    GrantNotAllowed,
+    GrantAlreadyMapped,
+    GrantOverlap,
 
     /* scheduler and timer */
     SchedNoTimer,
@@ -53,5 +78,12 @@ pub enum Cause
     /* supervisor binary loading */
     LoaderSupervisorTooLarge,
     LoaderUnrecognizedSupervisor,
-    LoaderBadEntry
+    LoaderBadEntry,
+
+    /* capsule launch manifest */
+    ManifestBadSyntax,
+    ManifestBadKey,
+    ManifestBadValue,
+    ManifestNoCapsules,
+    ManifestImageNotFound
 }
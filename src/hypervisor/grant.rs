@@ -0,0 +1,228 @@
+/* diosix shared memory grants between capsules
+ *
+ * Capsules normally only see their own private RAM. A capsule can
+ * offer a page-aligned range of its own RAM to another capsule by
+ * creating a grant, which allows the other capsule read-only or
+ * read-write access. The recipient maps the grant into its memory
+ * using the grant's ID, which the owner can pass on in a message
+ * payload. Either capsule can revoke the grant, which unmaps the
+ * memory from the recipient. Grants are revoked when either capsule
+ * is torn down.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use spin::Mutex;
+use alloc::vec::Vec;
+use hashbrown::hash_map::HashMap;
+use platform::physmem::PhysMemSize;
+use platform::virtmem::VirtMemBase;
+use super::error::Cause;
+use super::physmem::Region;
+use super::virtmem::{self, Access};
+use super::capsule::{self, CapsuleID};
+
+/* grant ID unique to the system */
+pub type GrantID = usize;
+
+/* describe memory offered by one capsule to another */
+struct Grant
+{
+    owner: CapsuleID,               /* capsule that owns the memory */
+    grantee: CapsuleID,             /* capsule allowed to map the memory */
+    region: Region,                 /* host physical memory shared */
+    access: Access,                 /* how the grantee may access the memory */
+    mapped: Option<VirtMemBase>     /* grantee's address of the memory, or None if not mapped */
+}
+
+lazy_static!
+{
+    /* acquire GRANTS lock before accessing any grants. if CAPSULES is needed too, acquire GRANTS first */
+    static ref GRANTS: Mutex<HashMap<GrantID, Grant>> = Mutex::new(HashMap::new());
+    static ref GRANT_ID_NEXT: Mutex<GrantID> = Mutex::new(1);
+}
+
+/* offer a range of a capsule's own memory to another capsule
+   => owner = ID of capsule that owns the memory
+      addr = owner's page-aligned address of the start of the range
+      size = page-aligned number of bytes in the range
+      grantee = ID of capsule to share the memory with
+      writable = true to allow the grantee to write to the memory, or false for read-only access
+   <= ID of the new grant, or an error code */
+pub fn create(owner: CapsuleID, addr: VirtMemBase, size: PhysMemSize, grantee: CapsuleID, writable: bool) -> Result<GrantID, Cause>
+{
+    let _tag = heaptag!();
+    if size == 0 || addr % virtmem::PAGE_SIZE != 0 || size % virtmem::PAGE_SIZE != 0
+    {
+        return Err(Cause::GrantBadRange);
+    }
+
+    if grantee == owner || capsule::is_alive(grantee) == false
+    {
+        return Err(Cause::CapsuleBadID);
+    }
+
+    let access = match writable
+    {
+        true => Access::ReadWrite,
+        false => Access::ReadOnly
+    };
+
+    /* hold GRANTS while looking up the memory so that if the owner gives it back to the
+    hypervisor at the same time, this grant is created in time to be revoked. see revoke_region().
+    memory shared with the owner can't be passed on, else read-only access could be upgraded */
+    let mut grants = GRANTS.lock();
+    let region = match capsule::translate_owned_region(owner, addr, size)
+    {
+        Some(r) => r,
+        None => return Err(Cause::GrantBadRange)
+    };
+
+    let id = allocate_id(&grants)?;
+    grants.insert(id, Grant
+    {
+        owner: owner,
+        grantee: grantee,
+        region: region,
+        access: access,
+        mapped: None
+    });
+
+    hvdebug!("Capsule {} granted capsule {} {:?} access to 0x{:x}, {} bytes (grant {})",
+             owner, grantee, access, region.base(), region.size(), id);
+    Ok(id)
+}
+
+/* find an unused grant ID
+   => grants = table of grants, which must be locked by the caller
+   <= unused ID, or an error code if they've all been used */
+fn allocate_id(grants: &HashMap<GrantID, Grant>) -> Result<GrantID, Cause>
+{
+    let mut next = GRANT_ID_NEXT.lock();
+    let first = *next;
+    loop
+    {
+        let id = *next;
+        *next = match next.checked_add(1)
+        {
+            Some(n) => n,
+            None => 1
+        };
+
+        if grants.contains_key(&id) == false
+        {
+            return Ok(id);
+        }
+
+        if *next == first
+        {
+            return Err(Cause::GrantIDExhaustion);
+        }
+    }
+}
+
+/* map a grant's memory into the capsule it was granted to
+   => id = ID of the grant
+      cid = ID of the capsule mapping the grant, which must be its grantee
+   <= capsule's address of the shared memory, or an error code */
+pub fn map(id: GrantID, cid: CapsuleID) -> Result<VirtMemBase, Cause>
+{
+    let mut grants = GRANTS.lock();
+    let grant = match grants.get_mut(&id)
+    {
+        Some(g) if g.grantee == cid => g,
+        Some(_) => return Err(Cause::GrantNotAllowed),
+        None => return Err(Cause::GrantBadID)
+    };
+
+    if grant.mapped.is_some()
+    {
+        return Err(Cause::GrantAlreadyMapped);
+    }
+
+    let base = capsule::map_grant(cid, id, grant.region, grant.access)?;
+    grant.mapped = Some(base);
+    Ok(base)
+}
+
+/* revoke a grant, unmapping its memory from the grantee if it was mapped. physical CPU
+   cores running the grantee are told to reload its memory protection straight away
+   => id = ID of the grant
+      cid = ID of the capsule revoking the grant, which must be its owner or grantee
+   <= Ok for success, or an error code */
+pub fn revoke(id: GrantID, cid: CapsuleID) -> Result<(), Cause>
+{
+    let mut grants = GRANTS.lock();
+    let grant = match grants.get(&id)
+    {
+        Some(g) if g.owner == cid || g.grantee == cid => g,
+        Some(_) => return Err(Cause::GrantNotAllowed),
+        None => return Err(Cause::GrantBadID)
+    };
+
+    let (grantee, mapped) = (grant.grantee, grant.mapped.is_some());
+    grants.remove(&id);
+    if mapped == true
+    {
+        unmap(id, grantee);
+    }
+    Ok(())
+}
+
+/* revoke all grants made by or to a capsule. call this when the capsule is torn down,
+   before its memory can be freed. grants are unmapped from a dying grantee too: it may
+   run for a little while longer, and its grants won't be revoked when their owners are torn down
+   => cid = ID of the capsule */
+pub fn forget_capsule(cid: CapsuleID)
+{
+    revoke_matching(|grant| grant.owner == cid || grant.grantee == cid);
+}
+
+/* revoke all grants of any part of a region of a capsule's memory. call this when the capsule
+   gives the memory back to the hypervisor, after removing it from the capsule and before freeing it
+   => owner = ID of the capsule that owned the memory
+      region = host physical memory given back */
+pub fn revoke_region(owner: CapsuleID, region: Region)
+{
+    revoke_matching(|grant| grant.owner == owner && grant.region.overlaps(&region));
+}
+
+/* revoke all grants for which the given function returns true
+   => doomed = function that returns true for a grant that should be revoked */
+fn revoke_matching<F>(doomed: F) where F: Fn(&Grant) -> bool
+{
+    let mut grants = GRANTS.lock();
+    let mut victims: Vec<(GrantID, CapsuleID, bool)> = Vec::new();
+    for (&id, grant) in grants.iter()
+    {
+        if doomed(grant) == true
+        {
+            victims.push((id, grant.grantee, grant.mapped.is_some()));
+        }
+    }
+
+    for (id, grantee, mapped) in victims
+    {
+        grants.remove(&id);
+        if mapped == true
+        {
+            unmap(id, grantee);
+        }
+    }
+}
+
+/* remove a grant's memory from its grantee and tell all physical CPU cores to reload the
+   grantee's memory protection if they're running it. GRANTS must be locked by the caller
+   => id = ID of the grant
+      grantee = ID of the capsule the grant was mapped into */
+fn unmap(id: GrantID, grantee: CapsuleID)
+{
+    if capsule::unmap_grant(grantee, id) == false
+    {
+        return;
+    }
+
+    capsule::revoke_access(grantee);
+}
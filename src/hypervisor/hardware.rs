@@ -116,6 +116,22 @@ pub fn write_debug_string(msg: &str) -> bool
     }
 }
 
+/* read a character from the debug logging console, if one is waiting.
+   if the system is busy then return immediately, don't block.
+   <= Some character, or None if nothing to read or the console is busy */
+pub fn read_debug_char() -> Option<u8>
+{
+    match acquire_hardware_lock(LockAttempts::Once)
+    {
+        Some(hw) => match &*hw
+        {
+            Some(d) => d.read_debug_char(),
+            None => None
+        },
+        None => None
+    }
+}
+
 /* return number of discovered logical CPU cores, or None if value unavailable */
 pub fn get_nr_cpu_cores() -> Option<usize>
 {
@@ -137,6 +153,16 @@ pub fn get_phys_ram_chunks() -> Option<Vec<platform::physmem::RAMArea>>
     }
 }
 
+/* return the frequency of the system timer in ticks per second, or None if value unavailable */
+pub fn get_timebase_frequency() -> Option<u64>
+{
+    match &*(acquire_hardware_lock(LockAttempts::Multiple).unwrap())
+    {
+        Some(d) => d.get_timebase_frequency(),
+        None => None
+    }
+}
+
 /* for this CPU core, enable scheduler timer interrupt and find a workload to run */
 pub fn scheduler_timer_start()
 {
@@ -156,3 +182,45 @@ pub fn scheduler_timer_next(usecs: u64)
         None => ()
     };
 }
+
+/* tell the scheduler to interrupt this core when its timer reaches the given value */
+pub fn scheduler_timer_at(target: u64)
+{
+    match &*(acquire_hardware_lock(LockAttempts::Multiple).unwrap())
+    {
+        Some(d) => d.scheduler_timer_at(target),
+        None => ()
+    };
+}
+
+/* return the current value of the system timer, or None if value unavailable */
+pub fn scheduler_get_timer_now() -> Option<u64>
+{
+    match &*(acquire_hardware_lock(LockAttempts::Multiple).unwrap())
+    {
+        Some(d) => d.scheduler_get_timer_now(),
+        None => None
+    }
+}
+
+/* raise a software interrupt in the given physical CPU core so that it checks its mailbox.
+   on RISC-V, this sets the core's msip bit in the CLINT
+   => id = ID of the physical CPU core to interrupt */
+pub fn interrupt_pcore(id: pcore::PhysicalCoreID)
+{
+    match &*(acquire_hardware_lock(LockAttempts::Multiple).unwrap())
+    {
+        Some(d) => d.interrupt_pcore(id),
+        None => ()
+    };
+}
+
+/* clear this physical CPU core's pending software interrupt, if any */
+pub fn clear_pcore_interrupt()
+{
+    match &*(acquire_hardware_lock(LockAttempts::Multiple).unwrap())
+    {
+        Some(d) => d.clear_pcore_interrupt(pcore::PhysicalCore::get_id()),
+        None => ()
+    };
+}
@@ -7,12 +7,25 @@
  * they are done with these allocations.
  * 
  * We use Rust's memory safety features to prevent any
- * use-after-free(). Blocks are free()'d atomically
- * preventing any races.
+ * use-after-free(). Blocks freed by other CPUs are pushed
+ * onto a lock-free queue belonging to their owner, which
+ * reclaims them the next time it allocates, so only the
+ * owner ever changes its heap's list of blocks.
  * 
  * This code interfaces with Rust's global allocator API
  * so things like vec! and Box just work. Heap is
  * the underlying engine for kAllocator.
+ *
+ * In debug builds, each allocation is tagged with the
+ * source file and line of the code, marked with heaptag!(),
+ * that was running when it was made, so that dump() can
+ * show what is using the heap. Debug builds also surround
+ * each block's contents with canary words, poison freed
+ * memory, and walk each heap on idle CPUs to check it's intact.
+ *
+ * A CPU's heap starts off in memory set aside for it by the
+ * platform. If that runs out, the heap grows by borrowing
+ * regions of physical memory, which it keeps from then on.
  * 
  * (c) Chris Williams, 2019.
  *
@@ -20,49 +33,142 @@
  */
 
 use core::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::ptr::{self, null_mut};
 use core::mem;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use core::result::Result;
 use platform::physmem::{barrier, PhysMemSize};
 use super::error::Cause;
-use super::pcore;
+use super::pcore::{self, PhysicalCoreID};
+use super::physmem;
+use super::slab;
 
 /* different states each recognized heap block can be in */
 #[repr(C)]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 enum HeapMagic
 {
     Free = 0x0deadded,
-    InUse = 0x0d10c0de
+    InUse = 0x0d10c0de,
+    Remote = 0x0d15ca4d /* freed by another CPU, awaiting its owner */
 }
 
 /* to avoid fragmentation, allocate in block sizes of this multiple, including header */
 const HEAP_BLOCK_SIZE: usize = 64;
 
+/* grow the heap by at least this many bytes at a time when it runs out of memory */
+const HEAP_GROWTH_MIN: PhysMemSize = 256 * 1024;
+
+/* in debug builds, canary words either side of a block's contents catch overruns and corrupted headers.
+they're mixed with the block's address so that a block copied elsewhere doesn't pass the check */
+#[cfg(debug_assertions)]
+const FRONT_CANARY: usize = 0x0ca7f00d;
+#[cfg(debug_assertions)]
+const REAR_CANARY: usize = 0x0b1ff0ed;
+
+/* bytes needed after a block's contents for its rear canary */
+#[cfg(debug_assertions)]
+const REDZONE_SIZE: usize = mem::size_of::<usize>();
+#[cfg(not(debug_assertions))]
+const REDZONE_SIZE: usize = 0;
+
+/* in debug builds, freed memory is filled with this */
+#[cfg(debug_assertions)]
+pub const POISON: u8 = 0xdd;
+
+/* in debug builds, allocations record the tag of the code that made them. the tag takes no space otherwise */
+#[cfg(debug_assertions)]
+pub type AllocTag = Option<&'static str>;
+#[cfg(not(debug_assertions))]
+pub type AllocTag = ();
+
+#[cfg(debug_assertions)]
+pub const UNTAGGED: AllocTag = None;
+#[cfg(not(debug_assertions))]
+pub const UNTAGGED: AllocTag = ();
+
+/* tag allocations made on this CPU core with the caller's source file and line until the returned value is dropped.
+for example, let _tag = heaptag!(); at the top of a function. this does nothing in release builds */
+#[macro_export]
+#[cfg(debug_assertions)]
+macro_rules! heaptag
+{
+    () => ($crate::heap::Tag::new(concat!(file!(), ":", line!())));
+}
+
+#[macro_export]
+#[cfg(not(debug_assertions))]
+macro_rules! heaptag
+{
+    () => (());
+}
+
+/* in debug builds, check this physical CPU core's heap is intact while it's idle */
+#[cfg(debug_assertions)]
+macro_rules! heaphousekeeper
+{
+    () => ($crate::heap::check());
+}
+
+#[cfg(not(debug_assertions))]
+macro_rules! heaphousekeeper
+{
+    () => (());
+}
+
+/* describe a heap's usage */
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats
+{
+    pub in_use: PhysMemSize,        /* bytes allocated, including block headers */
+    pub peak: PhysMemSize,          /* most bytes allocated at any one time */
+    pub blocks: usize,              /* number of allocated blocks */
+    pub failed: usize,              /* number of allocations that couldn't be made */
+    pub largest_free: PhysMemSize,  /* bytes in the largest free block, including its header */
+    pub borrowed: PhysMemSize       /* bytes of physical memory taken to grow the heap */
+}
+
 /* follow Rust's heap allocator API so we can drop our per-CPU allocator in and use things
 like Box. We allow the Rust toolchain to track and check pointers and object lifetimes,
-while we'll manage the underlying physical memory used by the heap. */
+while we'll manage the underlying physical memory used by the heap. small allocations are
+served from the CPU's slabs, which are themselves carved from the heap: see slab.rs */
 pub struct HVallocator;
 
 unsafe impl GlobalAlloc for HVallocator
 {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8
     {
-        let bytes = layout.size();
-        match (*<super::pcore::PhysicalCore>::this()).heap.alloc::<u8>(bytes)
+        let mut result = allocate(layout);
+
+        /* if the heap has run out of memory, try to grow it and then try again */
+        if let Err(Cause::HeapNoFreeMem) = result
         {
-            Ok(p) => p,
-            Err(e) =>
+            result = match grow(layout)
             {
-                hvalert!("HVallocator: request for {} bytes failed ({:?})", bytes, e);
-                null_mut() /* yeesh */
-            }
+                Ok(()) => allocate(layout),
+                Err(e) => Err(e)
+            };
+        }
+
+        /* fallible allocations, such as try_reserve(), recover from this, so stay quiet: the heap counts
+        its failed allocations, and if the failure can't be handled, the heap is described by kalloc_error() */
+        match result
+        {
+            Ok(p) => p,
+            Err(_) => null_mut() /* yeesh */
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout)
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
     {
-        match (*<super::pcore::PhysicalCore>::this()).heap.free::<u8>(ptr)
+        let cpu = <super::pcore::PhysicalCore>::this();
+        let result = match slab::class_of(layout)
+        {
+            Some(_) => slab::free(&mut cpu.slabs, &mut cpu.heap, ptr),
+            None => cpu.heap.free::<u8>(ptr)
+        };
+
+        match result
         {
             Err(e) =>
             {
@@ -73,6 +179,65 @@ unsafe impl GlobalAlloc for HVallocator
     }
 }
 
+/* allocate memory from this physical CPU core's slabs or heap
+   => layout = size and alignment of the memory required
+   <= pointer to the memory, or an error code */
+fn allocate(layout: Layout) -> Result<*mut u8, Cause>
+{
+    let cpu = pcore::PhysicalCore::this();
+    match slab::class_of(layout)
+    {
+        Some(class) => cpu.slabs.alloc(&mut cpu.heap, class),
+        None => cpu.heap.alloc_aligned(layout.size(), layout.align())
+    }
+}
+
+/* borrow a region of physical memory and add it to this physical CPU core's heap so that the given
+   allocation can be made. the physical memory allocator gives up rather than wait for its lock if it is held,
+   and should anything allocate from this heap while it's growing, the heap isn't grown again until the first growth is done
+   => layout = size and alignment of the allocation that failed
+   <= Ok if the heap grew, or an error code */
+fn grow(layout: Layout) -> Result<(), Cause>
+{
+    /* small allocations need room for a whole slab, which is aligned to its size */
+    let (bytes, align) = match slab::class_of(layout)
+    {
+        Some(_) => (slab::SLAB_SIZE, slab::SLAB_SIZE),
+        None => (layout.size(), layout.align())
+    };
+
+    /* leave enough room for the extension's header, and for the new block to be aligned and padded */
+    let overhead = mem::size_of::<HeapExtension>() + mem::size_of::<HeapBlock>() + REDZONE_SIZE + (2 * HEAP_BLOCK_SIZE);
+    let size = match bytes.checked_add(align).and_then(|s| s.checked_add(overhead))
+    {
+        Some(s) => core::cmp::max(s, HEAP_GROWTH_MIN),
+        None => return Err(Cause::HeapBadSize)
+    };
+
+    let heap = &mut pcore::PhysicalCore::this().heap;
+    if heap.growing == true
+    {
+        return Err(Cause::HeapNoFreeMem);
+    }
+
+    heap.growing = true;
+    let region = physmem::alloc_region_for_heap(size);
+    let heap = &mut pcore::PhysicalCore::this().heap;
+    heap.growing = false;
+
+    let region = region?;
+    heap.extend(region.base() as *mut HeapExtension, region.size());
+    Ok(())
+}
+
+/* each region of physical memory borrowed to grow a heap starts with one of these, followed by the region's blocks */
+#[repr(C)]
+struct HeapExtension
+{
+    next: *mut HeapExtension,   /* next region borrowed by this heap */
+    size: PhysMemSize           /* size of this region, including this header */
+}
+
 /* describe the layout of a per-CPU heap block */
 #[repr(C)]
 pub struct HeapBlock
@@ -82,10 +247,95 @@ pub struct HeapBlock
     /* size of this block *including* header */
     size: PhysMemSize,
     /* define block state using magic words */
-    magic: HeapMagic
+    magic: HeapMagic,
+    /* heap this block was allocated from */
+    owner: *const Heap,
+    /* tag of the code that allocated this block */
+    tag: AllocTag,
+    /* number of bytes asked for, after which the rear canary sits */
+    #[cfg(debug_assertions)]
+    requested: usize,
+    /* front canary, immediately before the contents */
+    #[cfg(debug_assertions)]
+    canary: usize
     /* block contents follows... */
 }
 
+/* memory freed by other CPUs waits in a queue of these, held in the freed memory itself */
+struct RemoteFree
+{
+    next: *mut RemoteFree
+}
+
+/* a queue of memory freed by other CPUs, which the CPU that owns the memory takes back when
+it's ready. any CPU can add to the queue without locking it. only the owner empties it */
+#[repr(C)]
+pub struct RemoteFrees
+{
+    head: AtomicPtr<RemoteFree>
+}
+
+impl RemoteFrees
+{
+    pub fn new() -> RemoteFrees
+    {
+        RemoteFrees
+        {
+            head: AtomicPtr::new(null_mut())
+        }
+    }
+
+    /* add freed memory to the queue. call this from any CPU
+       => ptr = memory to queue, which must be large and aligned enough to hold a pointer */
+    pub fn push(&self, ptr: *mut u8)
+    {
+        let node = ptr as *mut RemoteFree;
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop
+        {
+            unsafe { (*node).next = head; }
+            match self.head.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => head = current
+            }
+        }
+    }
+
+    /* empty the queue. only call this from the owner
+       <= iterator over the items of freed memory that were in the queue */
+    pub fn take(&self) -> TakenFrees
+    {
+        TakenFrees
+        {
+            node: self.head.swap(null_mut(), Ordering::Acquire)
+        }
+    }
+}
+
+/* iterate over memory taken from a queue of remote frees */
+pub struct TakenFrees
+{
+    node: *mut RemoteFree
+}
+
+impl Iterator for TakenFrees
+{
+    type Item = *mut u8;
+
+    fn next(&mut self) -> Option<*mut u8>
+    {
+        if self.node.is_null() == true
+        {
+            return None;
+        }
+
+        let item = self.node;
+        self.node = unsafe { (*item).next };
+        Some(item as *mut u8)
+    }
+}
+
 /* this is our own internal API for the per-CPU hypervisor heap. use high-level abstractions, such as Box,
 rather than this directly, so we get all the safety measures and lifetime checking. think of kallocator
 as the API and Heap as the engine. kallocator is built on top of Heap, and each CPU core has its own Heap. */
@@ -95,13 +345,57 @@ pub struct Heap
     /* pointer to list of in-use and freed blocks */
     block_list_head: *mut HeapBlock,
     /* stash a copy of the block header size here */
-    block_header_size: PhysMemSize
+    block_header_size: PhysMemSize,
+    /* blocks freed by other CPUs, to be marked free when this heap next allocates */
+    remote: RemoteFrees,
+    /* usage counters */
+    in_use: PhysMemSize,
+    peak: PhysMemSize,
+    blocks: usize,
+    failed: usize,
+    /* tag to give new allocations */
+    tag: AllocTag,
+    /* physical CPU core that owns this heap, and the bounds of the heap's initial memory */
+    id: PhysicalCoreID,
+    start: usize,
+    end: usize,
+    /* list of physical memory regions borrowed to grow the heap, and their total size in bytes */
+    extensions: *mut HeapExtension,
+    borrowed: PhysMemSize,
+    /* set while the heap is borrowing memory */
+    growing: bool,
+    /* set once the heap has been found to be corrupt, to avoid repeating the report */
+    damaged: bool
 }
 
 impl Heap
 {
+    /* create an empty heap. give it memory to manage with init() */
+    pub fn new() -> Heap
+    {
+        Heap
+        {
+            block_list_head: null_mut(),
+            block_header_size: mem::size_of::<HeapBlock>(),
+            remote: RemoteFrees::new(),
+            in_use: 0,
+            peak: 0,
+            blocks: 0,
+            failed: 0,
+            tag: UNTAGGED,
+            id: 0,
+            start: 0,
+            end: 0,
+            extensions: null_mut(),
+            borrowed: 0,
+            growing: false,
+            damaged: false
+        }
+    }
+
     /* initialize this heap area. start off with one giant block
     covering all of free space, from which other blocks will be carved.
+    call this on the physical CPU core that will own the heap.
     => start = pointer to start of heap area
        size = number of available bytes in heap */
     pub fn init(&mut self, start: *mut HeapBlock, size: PhysMemSize)
@@ -116,34 +410,304 @@ impl Heap
 
             self.block_header_size = mem::size_of::<HeapBlock>();
             self.block_list_head = block;
+            self.remote = RemoteFrees::new();
+            self.in_use = 0;
+            self.peak = 0;
+            self.blocks = 0;
+            self.failed = 0;
+            self.tag = UNTAGGED;
+            self.id = pcore::PhysicalCore::get_id();
+            self.start = start as usize;
+            self.end = (start as usize) + size;
+            self.extensions = null_mut();
+            self.borrowed = 0;
+            self.growing = false;
+            self.damaged = false;
+        }
+    }
+
+    /* add a region of memory to this heap as one free block. the heap keeps the memory from then on.
+    only call this from the heap's owner
+    => base = pointer to start of the region, aligned to at least a heap block header
+       size = number of bytes in the region */
+    pub fn extend(&mut self, base: *mut HeapExtension, size: PhysMemSize)
+    {
+        let header_size = mem::size_of::<HeapExtension>();
+        if size < header_size + HEAP_BLOCK_SIZE
+        {
+            return;
+        }
+
+        unsafe
+        {
+            (*base).next = self.extensions;
+            (*base).size = size;
+            self.extensions = base;
+            self.borrowed = self.borrowed + size;
+
+            let block = ((base as usize) + header_size) as *mut HeapBlock;
+            (*block).size = size - header_size;
+            (*block).next = Some(self.block_list_head);
+            (*block).magic = HeapMagic::Free;
+            self.block_list_head = block;
+        }
+    }
+
+    /* return true if the given address falls within this heap's blocks, either in its initial memory or a borrowed region */
+    fn contains(&self, addr: usize) -> bool
+    {
+        if addr >= self.start && addr < self.end
+        {
+            return true;
+        }
+
+        let mut extension = self.extensions;
+        while extension.is_null() == false
+        {
+            unsafe
+            {
+                let blocks = (extension as usize) + mem::size_of::<HeapExtension>();
+                if addr >= blocks && addr < (extension as usize) + (*extension).size
+                {
+                    return true;
+                }
+                extension = (*extension).next;
+            }
+        }
+        false
+    }
+
+    /* <= total bytes of memory available for this heap's blocks */
+    fn capacity(&self) -> usize
+    {
+        let mut total = self.end - self.start;
+        let mut extension = self.extensions;
+        while extension.is_null() == false
+        {
+            unsafe
+            {
+                total = total + (*extension).size - mem::size_of::<HeapExtension>();
+                extension = (*extension).next;
+            }
+        }
+        total
+    }
+
+    /* return the end of the memory containing the given address, which must be within the heap */
+    fn end_of(&self, addr: usize) -> usize
+    {
+        let mut extension = self.extensions;
+        while extension.is_null() == false
+        {
+            unsafe
+            {
+                let end = (extension as usize) + (*extension).size;
+                if addr > (extension as usize) && addr < end
+                {
+                    return end;
+                }
+                extension = (*extension).next;
+            }
         }
+        self.end
     }
 
-    /* free a previously allocated block
+    /* free a previously allocated block. if the block belongs to another CPU's heap,
+    it's queued for that CPU to reclaim
     => to_free = pointer previously returned by alloc()
     <= success or failure code */
     pub fn free<T>(&mut self, to_free: *mut T) -> Result<(), Cause>
     {
         /* convert this into a raw pointer so we can find the heap block header */
-        let mut ptr = to_free as usize;
-        ptr = ptr - self.block_header_size;
-        let block = ptr as *mut HeapBlock;
-        
+        let ptr = to_free as usize;
+        if ptr < self.block_header_size || ptr % mem::align_of::<HeapBlock>() != 0
+        {
+            hvalert!("Heap: Attempt to free bad pointer {:p}", to_free);
+            return Err(Cause::HeapBadBlock);
+        }
+        let block = (ptr - self.block_header_size) as *mut HeapBlock;
+
         unsafe
         {
-            match (*block).magic
+            /* if it's not in use, or bad magic, or its canaries have been overwritten, then bail out */
+            let check = match Heap::magic_of(block)
+            {
+                Some(HeapMagic::InUse) => Heap::check_guards(block),
+                Some(_) => Err(Cause::HeapNotInUse),
+                None => Err(Cause::HeapBadBlock)
+            };
+
+            if let Err(e) = check
+            {
+                match owner_of(to_free as *const u8)
+                {
+                    Some(id) => hvalert!("Heap: Failed to free block {:p} owned by physical CPU core {} ({:?})", to_free, id, e),
+                    None => hvalert!("Heap: Failed to free block {:p} of unknown owner ({:?})", to_free, e)
+                }
+                return Err(e);
+            }
+
+            match (*block).owner == self as *const Heap
             {
-                HeapMagic::InUse =>
+                true =>
                 {
+                    Heap::poison(block);
+
                     /* assume writes are atomic. place a memory barrier to avoid any release ordering issues */
                     barrier();
                     (*block).magic = HeapMagic::Free;
-                    Ok(())
+                    self.in_use = self.in_use - (*block).size;
+                    self.blocks = self.blocks - 1;
                 },
-                /* if it's not in use, or bad magic, then bail out */
-                HeapMagic::Free => Err(Cause::HeapNotInUse)
+                false =>
+                {
+                    /* the owner may be walking its blocks, checking the canaries of those in use, so mark
+                    the block as freed before poisoning it. leave the owner's list of blocks alone and let it
+                    take the block back itself */
+                    (*block).magic = HeapMagic::Remote;
+                    barrier();
+                    Heap::poison(block);
+                    (*(*block).owner).remote.push(to_free as *mut u8);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /* read a block's magic word without trusting it to be valid
+       => block = block to inspect
+       <= the block's state, or None if its magic word is corrupt */
+    unsafe fn magic_of(block: *const HeapBlock) -> Option<HeapMagic>
+    {
+        match ptr::read(&(*block).magic as *const HeapMagic as *const u32)
+        {
+            m if m == HeapMagic::Free as u32 => Some(HeapMagic::Free),
+            m if m == HeapMagic::InUse as u32 => Some(HeapMagic::InUse),
+            m if m == HeapMagic::Remote as u32 => Some(HeapMagic::Remote),
+            _ => None
+        }
+    }
+
+    /* in debug builds, write the canaries either side of a newly allocated block's contents
+       => block = the allocated block
+          requested = number of bytes asked for */
+    #[cfg(debug_assertions)]
+    unsafe fn guard(&self, block: *mut HeapBlock, requested: usize)
+    {
+        (*block).requested = requested;
+        (*block).canary = FRONT_CANARY ^ (block as usize);
+        let rear = (block as usize) + self.block_header_size + requested;
+        ptr::write_unaligned(rear as *mut usize, REAR_CANARY ^ (block as usize));
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn guard(&self, _block: *mut HeapBlock, _requested: usize) {}
+
+    /* in debug builds, check the canaries either side of an allocated block's contents are intact
+       => block = the allocated block
+       <= Ok if so, or an error code if not */
+    #[cfg(debug_assertions)]
+    unsafe fn check_guards(block: *const HeapBlock) -> Result<(), Cause>
+    {
+        let header_size = mem::size_of::<HeapBlock>();
+        if (*block).canary != FRONT_CANARY ^ (block as usize) || (*block).requested + header_size + REDZONE_SIZE > (*block).size
+        {
+            return Err(Cause::HeapBadBlock);
+        }
+
+        let rear = (block as usize) + header_size + (*block).requested;
+        match ptr::read_unaligned(rear as *const usize) == REAR_CANARY ^ (block as usize)
+        {
+            true => Ok(()),
+            false => Err(Cause::HeapBadBlock)
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn check_guards(_block: *const HeapBlock) -> Result<(), Cause> { Ok(()) }
+
+    /* in debug builds, fill a block's contents with poison so that use after free stands out
+       => block = block being freed */
+    #[cfg(debug_assertions)]
+    unsafe fn poison(block: *mut HeapBlock)
+    {
+        let header_size = mem::size_of::<HeapBlock>();
+        ptr::write_bytes(((block as usize) + header_size) as *mut u8, POISON, (*block).size - header_size);
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn poison(_block: *mut HeapBlock) {}
+
+    /* walk the whole heap checking each block's header, the canaries of allocated blocks, and the links
+       between blocks, which must cover the heap's memory, including borrowed regions, exactly. the first problem found is reported.
+       only call this from the heap's owner
+       <= Ok if the heap is intact, or an error code if not */
+    pub fn check(&mut self) -> Result<(), Cause>
+    {
+        let capacity = self.capacity();
+        let max_blocks = capacity / HEAP_BLOCK_SIZE;
+        let mut block = self.block_list_head;
+        let mut covered = 0;
+        let mut count = 0;
+
+        loop
+        {
+            let addr = block as usize;
+            let problem = unsafe
+            {
+                if self.contains(addr) == false || addr % mem::align_of::<HeapBlock>() != 0
+                {
+                    Some("link to a block outside the heap")
+                }
+                else if (*block).size < self.block_header_size || (*block).size > self.end_of(addr) - addr
+                {
+                    Some("bad block size")
+                }
+                else
+                {
+                    match Heap::magic_of(block)
+                    {
+                        None => Some("corrupt header"),
+                        Some(HeapMagic::InUse) if Heap::check_guards(block).is_err() => Some("overwritten canary"),
+                        _ => None
+                    }
+                }
+            };
+
+            count = count + 1;
+            let problem = match (problem, count > max_blocks)
+            {
+                (Some(p), _) => Some(p),
+                (None, true) => Some("loop in the list of blocks"),
+                (None, false) => None
+            };
+
+            if let Some(p) = problem
+            {
+                hvalert_now!("Heap: Found {} at {:p} in physical CPU core {}'s heap", p, block, self.id);
+                self.damaged = true;
+                return Err(Cause::HeapBadBlock);
+            }
+
+            unsafe
+            {
+                covered = covered + (*block).size;
+                match (*block).next
+                {
+                    Some(n) => block = n,
+                    None => break
+                }
             }
         }
+
+        if covered != capacity
+        {
+            hvalert_now!("Heap: Blocks cover {} of {} bytes in physical CPU core {}'s heap", covered, capacity, self.id);
+            self.damaged = true;
+            return Err(Cause::HeapBadBlock);
+        }
+
+        Ok(())
     }
 
     /* allocate memory for the given object type. the returned pointer skips
@@ -154,16 +718,46 @@ impl Heap
     <= pointer to memory, or error code */
     pub fn alloc<T>(&mut self, num: usize) -> Result<*mut T, Cause>
     {
-        if num == 0
+        let bytes = match mem::size_of::<T>().checked_mul(num)
+        {
+            Some(b) => b,
+            None => return Err(Cause::HeapBadSize)
+        };
+
+        match self.alloc_aligned(bytes, mem::align_of::<T>())
+        {
+            Ok(p) => Ok(p as *mut T),
+            Err(e) => Err(e)
+        }
+    }
+
+    /* allocate memory whose start is aligned to the given power of two. the block header
+    immediately precedes the returned pointer, as with alloc(), so free() works as normal.
+    => bytes = number of bytes to allocate
+       align = required alignment of the allocated memory, in bytes, which must be a power of two.
+               page-sized and larger alignments are supported
+    <= pointer to memory, or error code */
+    pub fn alloc_aligned(&mut self, bytes: usize, align: usize) -> Result<*mut u8, Cause>
+    {
+        if bytes == 0 || align.is_power_of_two() == false
         {
             return Err(Cause::HeapBadSize);
         }
 
+        self.reclaim_remote_frees();
+
+        /* block headers must be aligned, too */
+        let align = core::cmp::max(align, mem::align_of::<HeapBlock>());
+
         let mut done = false;
 
         /* calculate size of block required, including header, rounded up to
         nearest whole heap block multiple */
-        let mut size_req = (mem::size_of::<T>() * num) + mem::size_of::<HeapBlock>();
+        let mut size_req = match bytes.checked_add(mem::size_of::<HeapBlock>() + REDZONE_SIZE)
+        {
+            Some(s) => s,
+            None => return Err(Cause::HeapBadSize)
+        };
         size_req = ((size_req / HEAP_BLOCK_SIZE) + 1) * HEAP_BLOCK_SIZE;
 
         /* scan all blocks for first free fit */
@@ -174,35 +768,18 @@ impl Heap
             {
                 if (*search_block).magic == HeapMagic::Free && (*search_block).size >= size_req
                 {
-                    /* we've got a winner. if the found block is equal size, or only a few bytes
-                    larger than the required size, then take the whole block */
-                    if ((*search_block).size - size_req) < HEAP_BLOCK_SIZE
+                    if let Some(found_ptr) = self.carve(search_block, size_req, align)
                     {
-                        (*search_block).magic = HeapMagic::InUse;
-                        let found_ptr = (search_block as usize) + self.block_header_size;
-                        return Result::Ok(found_ptr as *mut T);
-                    }
-                    else
-                    {
-                        /* carve the end of a large-enough free block off to make a new block.
-                        then add this new block to the start of the list */
-                        (*search_block).size = (*search_block).size - size_req;
-                        
-                        /* skip to the new (shorter) end of the free block */
-                        let mut found_ptr = (search_block as usize) + (*search_block).size;
-
-                        /* set metadata for newly allocated block */
-                        let alloc_block = found_ptr as *mut HeapBlock;
-                        (*alloc_block).next  = Some(self.block_list_head);
-                        (*alloc_block).magic = HeapMagic::InUse;
-                        (*alloc_block).size  = size_req;
-
-                        /* point the head of the list at new block */
-                        self.block_list_head = alloc_block;
-
-                        /* adjust pointer to skip the header of our new block, and we're done */
-                        found_ptr = found_ptr + self.block_header_size;
-                        return Result::Ok(found_ptr as *mut T);
+                        let block = ((found_ptr as usize) - self.block_header_size) as *mut HeapBlock;
+                        (*block).tag = self.tag;
+                        self.guard(block, bytes);
+                        self.blocks = self.blocks + 1;
+                        self.in_use = self.in_use + (*block).size;
+                        if self.in_use > self.peak
+                        {
+                            self.peak = self.in_use;
+                        }
+                        return Result::Ok(found_ptr);
                     }
                 }
 
@@ -227,9 +804,186 @@ impl Heap
             }
         }
 
+        self.failed = self.failed + 1;
         return Result::Err(Cause::HeapNoFreeMem);
     }
 
+    /* mark as free the blocks other CPUs have freed since this heap last allocated */
+    fn reclaim_remote_frees(&mut self)
+    {
+        for ptr in self.remote.take()
+        {
+            let block = ((ptr as usize) - self.block_header_size) as *mut HeapBlock;
+            unsafe
+            {
+                (*block).magic = HeapMagic::Free;
+                self.in_use = self.in_use - (*block).size;
+            }
+            self.blocks = self.blocks - 1;
+        }
+    }
+
+    /* <= statistics describing this heap's usage. only call this from the heap's owner */
+    pub fn stats(&mut self) -> HeapStats
+    {
+        self.reclaim_remote_frees();
+
+        let mut largest_free = 0;
+        self.walk(|block|
+        {
+            if block.magic == HeapMagic::Free && block.size > largest_free
+            {
+                largest_free = block.size;
+            }
+        });
+
+        HeapStats
+        {
+            in_use: self.in_use,
+            peak: self.peak,
+            blocks: self.blocks,
+            failed: self.failed,
+            largest_free: largest_free,
+            borrowed: self.borrowed
+        }
+    }
+
+    /* call the given function with every block in the heap's list
+       => f = function to call with each block */
+    fn walk<F>(&self, mut f: F) where F: FnMut(&HeapBlock)
+    {
+        let mut block = self.block_list_head;
+        loop
+        {
+            unsafe
+            {
+                f(&*block);
+                match (*block).next
+                {
+                    Some(n) => block = n,
+                    None => return
+                }
+            }
+        }
+    }
+
+    /* set the tag to give new allocations
+       => tag = tag for allocations
+       <= the previous tag */
+    pub fn swap_tag(&mut self, tag: AllocTag) -> AllocTag
+    {
+        mem::replace(&mut self.tag, tag)
+    }
+
+    /* <= the tag being given to new allocations */
+    pub fn get_tag(&self) -> AllocTag { self.tag }
+
+    /* <= ID of the physical CPU core that owns this heap */
+    pub fn get_id(&self) -> PhysicalCoreID { self.id }
+
+    /* count this heap's outstanding allocations by tag. only call this from the heap's owner
+       => tally = tally to add the allocations to */
+    #[cfg(debug_assertions)]
+    fn tally(&mut self, tally: &mut Tally)
+    {
+        self.reclaim_remote_frees();
+
+        let header_size = self.block_header_size;
+        self.walk(|block|
+        {
+            if block.magic != HeapMagic::InUse
+            {
+                return;
+            }
+
+            /* slabs are broken down into their objects */
+            match block.tag == slab::SLAB_TAG
+            {
+                true => slab::tally((block as *const HeapBlock as usize) + header_size, tally),
+                false => tally.add(block.tag, block.size - header_size)
+            }
+        });
+    }
+
+    /* try to allocate a block from a free block so that the new block's contents are aligned
+    => block = free block to allocate from
+       size_req = size of block required, including header, in whole heap block multiples
+       align = required alignment of the new block's contents, a power of two
+    <= pointer to the new block's contents, or None if it won't fit */
+    unsafe fn carve(&mut self, block: *mut HeapBlock, size_req: PhysMemSize, align: usize) -> Option<*mut u8>
+    {
+        let start = block as usize;
+        let end = start + (*block).size;
+
+        /* if the found block is suitably aligned, and is equal size, or only a few bytes
+        larger than the required size, then take the whole block */
+        if (start + self.block_header_size) % align == 0 && ((*block).size - size_req) < HEAP_BLOCK_SIZE
+        {
+            (*block).magic = HeapMagic::InUse;
+            (*block).owner = self as *const Heap;
+            return Some((start + self.block_header_size) as *mut u8);
+        }
+
+        /* otherwise carve the new block off the end of the free block, as high up as alignment allows.
+        whatever is left of the free block below it must be either nothing or big enough to be a block */
+        let mut found_ptr = (end - size_req + self.block_header_size) & !(align - 1);
+        let below = loop
+        {
+            if found_ptr < start + self.block_header_size
+            {
+                return None;
+            }
+
+            let below = found_ptr - self.block_header_size - start;
+            if below == 0 || below >= HEAP_BLOCK_SIZE
+            {
+                break below;
+            }
+
+            found_ptr = found_ptr.checked_sub(align)?;
+        };
+
+        /* any space left over above the new block becomes a free block if it's large enough.
+        if not, add it to the new block so it's not lost */
+        let alloc_block = (found_ptr - self.block_header_size) as *mut HeapBlock;
+        let above = end - (alloc_block as usize + size_req);
+        let alloc_size = match above < HEAP_BLOCK_SIZE
+        {
+            true => size_req + above,
+            false => size_req
+        };
+
+        if below == 0
+        {
+            /* the new block starts where the free block did, so reuse its header */
+            (*alloc_block).magic = HeapMagic::InUse;
+            (*alloc_block).owner = self as *const Heap;
+            (*alloc_block).size  = alloc_size;
+        }
+        else
+        {
+            /* shorten the free block, and set metadata for the newly allocated block.
+            then add this new block to the start of the list */
+            (*block).size = below;
+            (*alloc_block).next  = Some(self.block_list_head);
+            (*alloc_block).magic = HeapMagic::InUse;
+            (*alloc_block).owner = self as *const Heap;
+            (*alloc_block).size  = alloc_size;
+            self.block_list_head = alloc_block;
+        }
+
+        if above >= HEAP_BLOCK_SIZE
+        {
+            let spare_block = (alloc_block as usize + alloc_size) as *mut HeapBlock;
+            (*spare_block).next  = Some(self.block_list_head);
+            (*spare_block).magic = HeapMagic::Free;
+            (*spare_block).size  = above;
+            self.block_list_head = spare_block;
+        }
+
+        Some(found_ptr as *mut u8)
+    }
+
     /* pass once over the heap and try to merge adjacent blocks
     <= size of the largest block seen, in bytes including header */
     fn consolidate(&mut self) -> PhysMemSize
@@ -295,3 +1049,360 @@ impl Heap
     }
 }
 
+/* tag allocations made on this CPU core until dropped. create this using heaptag!() */
+#[cfg(debug_assertions)]
+pub struct Tag
+{
+    previous: AllocTag
+}
+
+#[cfg(debug_assertions)]
+impl Tag
+{
+    pub fn new(tag: &'static str) -> Tag
+    {
+        Tag
+        {
+            previous: pcore::PhysicalCore::this().heap.swap_tag(Some(tag))
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Tag
+{
+    fn drop(&mut self)
+    {
+        pcore::PhysicalCore::this().heap.swap_tag(self.previous);
+    }
+}
+
+/* the most different tags counted when dumping outstanding allocations. the tally is kept on the stack
+so that the heap isn't changed while it's being counted */
+#[cfg(debug_assertions)]
+const TALLY_TAGS: usize = 32;
+
+/* number and total size of outstanding allocations with a given tag */
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct TallyEntry
+{
+    tag: AllocTag,
+    count: usize,
+    bytes: usize
+}
+
+/* count outstanding allocations by tag */
+#[cfg(debug_assertions)]
+pub struct Tally
+{
+    entries: [TallyEntry; TALLY_TAGS],
+    used: usize,
+    overflow: TallyEntry /* allocations whose tags didn't fit in the table */
+}
+
+#[cfg(debug_assertions)]
+impl Tally
+{
+    fn new() -> Tally
+    {
+        let empty = TallyEntry { tag: UNTAGGED, count: 0, bytes: 0 };
+        Tally
+        {
+            entries: [empty; TALLY_TAGS],
+            used: 0,
+            overflow: empty
+        }
+    }
+
+    /* count an allocation
+       => tag = allocation's tag
+          bytes = allocation's size in bytes */
+    pub fn add(&mut self, tag: AllocTag, bytes: usize)
+    {
+        let entry = match self.entries[..self.used].iter().position(|e| e.tag == tag)
+        {
+            Some(index) => &mut self.entries[index],
+            None if self.used < TALLY_TAGS =>
+            {
+                self.used = self.used + 1;
+                self.entries[self.used - 1].tag = tag;
+                &mut self.entries[self.used - 1]
+            },
+            None => &mut self.overflow
+        };
+
+        entry.count = entry.count + 1;
+        entry.bytes = entry.bytes + bytes;
+    }
+}
+
+/* turn the outcome of a fallible allocation, such as try_reserve(), into an error code
+   => result = outcome of the allocation
+   <= Ok for success, or an error code if the memory couldn't be allocated */
+pub fn reserved<E>(result: Result<(), E>) -> Result<(), Cause>
+{
+    match result
+    {
+        Ok(()) => Ok(()),
+        Err(_) => Err(Cause::HeapNoFreeMem)
+    }
+}
+
+/* find which physical CPU core owns an allocated heap block, trusting its header only if it looks intact
+   => contents = pointer to the block's contents, as returned by alloc()
+   <= ID of the owning core, or None if the header is damaged or the block isn't allocated */
+pub fn owner_of(contents: *const u8) -> Option<PhysicalCoreID>
+{
+    if contents.is_null() || (contents as usize) % mem::align_of::<HeapBlock>() != 0
+    {
+        return None;
+    }
+
+    unsafe
+    {
+        let block = (contents as usize - mem::size_of::<HeapBlock>()) as *const HeapBlock;
+        match Heap::magic_of(block)
+        {
+            Some(HeapMagic::InUse) | Some(HeapMagic::Remote) => (),
+            _ => return None
+        }
+
+        #[cfg(debug_assertions)]
+        {
+            if (*block).canary != FRONT_CANARY ^ (block as usize)
+            {
+                return None;
+            }
+        }
+
+        match (*block).owner.is_null()
+        {
+            true => None,
+            false => Some((*(*block).owner).id)
+        }
+    }
+}
+
+/* check this physical CPU core's heap is intact. once it's found to be damaged,
+it's not checked again so that the problem is reported only once */
+pub fn check()
+{
+    let heap = &mut pcore::PhysicalCore::this().heap;
+    if heap.damaged == false
+    {
+        let _ = heap.check();
+    }
+}
+
+/* describe this physical CPU core's heap and slabs straight to the debug port, without allocating,
+so this can be used when the heap has run out. in debug builds,
+check the heap is intact and list its outstanding allocations by tag, including those made
+by this core and freed by others that it hasn't yet taken back */
+pub fn dump()
+{
+    let cpu = pcore::PhysicalCore::this();
+    let stats = cpu.heap.stats();
+    hvalert_now!("Heap: {} bytes in use in {} blocks, peak {} bytes, {} failed allocations, largest free block {} bytes, {} bytes borrowed",
+             stats.in_use, stats.blocks, stats.peak, stats.failed, stats.largest_free, stats.borrowed);
+    cpu.slabs.dump();
+
+    #[cfg(debug_assertions)]
+    {
+        /* don't walk a damaged heap any further than necessary */
+        if cpu.heap.damaged == true || cpu.heap.check().is_err()
+        {
+            return;
+        }
+
+        let mut tally = Tally::new();
+        cpu.heap.tally(&mut tally);
+        for entry in tally.entries[..tally.used].iter()
+        {
+            hvalert_now!("Heap: {} bytes in {} allocations from {}", entry.bytes, entry.count, entry.tag.unwrap_or("untagged code"));
+        }
+
+        if tally.overflow.count > 0
+        {
+            hvalert_now!("Heap: {} bytes in {} allocations from elsewhere", tally.overflow.bytes, tally.overflow.count);
+        }
+    }
+}
+
+/* memory borrowed from this core's heap for tests to run private heaps in. unlike a vec of bytes,
+it's aligned to suit heap blocks, and it's handed back when the test is done */
+#[cfg(test)]
+struct TestArea
+{
+    base: *mut u8,
+    layout: Layout
+}
+
+#[cfg(test)]
+impl TestArea
+{
+    fn new(size: usize) -> TestArea
+    {
+        let layout = Layout::from_size_align(size, HEAP_BLOCK_SIZE).unwrap();
+        let base = unsafe { alloc::alloc::alloc(layout) };
+        assert_eq!(base.is_null(), false);
+        TestArea { base: base, layout: layout }
+    }
+
+    /* <= pointer to the given number of bytes into the area */
+    fn at<T>(&self, offset: usize) -> *mut T
+    {
+        ((self.base as usize) + offset) as *mut T
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestArea
+{
+    fn drop(&mut self)
+    {
+        unsafe { alloc::alloc::dealloc(self.base, self.layout) };
+    }
+}
+
+#[test_case]
+fn test_heap_alignment()
+{
+    /* run a private heap in a block borrowed from this core's heap */
+    let size = 64 * 1024;
+    let area = TestArea::new(size);
+    let mut heap = Heap::new();
+    heap.init(area.at(0), size);
+
+    let small = heap.alloc_aligned(100, 16).unwrap();
+    let page = heap.alloc_aligned(4096, 4096).unwrap();
+    let large = heap.alloc_aligned(4096, 16384).unwrap();
+    assert_eq!(small as usize % 16, 0);
+    assert_eq!(page as usize % 4096, 0);
+    assert_eq!(large as usize % 16384, 0);
+    assert_eq!(heap.alloc_aligned(100, 3).is_err(), true);
+
+    /* aligned blocks are freed like any other, and only once */
+    heap.free(page).unwrap();
+    assert_eq!(heap.free(page).is_err(), true);
+    heap.free(large).unwrap();
+    heap.free(small).unwrap();
+
+    /* freed memory can be reused */
+    let again = heap.alloc_aligned(8192, 8192).unwrap();
+    assert_eq!(again as usize % 8192, 0);
+}
+
+#[test_case]
+fn test_remote_free()
+{
+    /* run two private heaps side by side as if they belonged to different CPUs */
+    let size = 16 * 1024;
+    let area = TestArea::new(size * 2);
+    let mut owner = Heap::new();
+    let mut other = Heap::new();
+    owner.init(area.at(0), size);
+    other.init(area.at(size), size);
+
+    /* a block freed by another heap is queued for its owner, and can't be freed again */
+    let first = owner.alloc_aligned(size / 2, 8).unwrap();
+    other.free(first).unwrap();
+    assert_eq!(other.free(first).is_err(), true);
+    assert_eq!(owner.free(first).is_err(), true);
+
+    /* the owner takes the block back when it next allocates */
+    let second = owner.alloc_aligned(size / 2, 8).unwrap();
+    assert_eq!(second, first);
+    owner.free(second).unwrap();
+}
+
+#[test_case]
+fn test_heap_stats()
+{
+    let size = 16 * 1024;
+    let area = TestArea::new(size);
+    let mut heap = Heap::new();
+    heap.init(area.at(0), size);
+    assert_eq!(heap.stats().largest_free, size);
+
+    /* block sizes include their headers and are rounded up */
+    let first = heap.alloc_aligned(1000, 8).unwrap();
+    let second = heap.alloc_aligned(2000, 8).unwrap();
+    let stats = heap.stats();
+    assert_eq!(stats.blocks, 2);
+    assert_eq!(stats.in_use >= 3000 && stats.in_use <= 3000 + (2 * (HEAP_BLOCK_SIZE + mem::size_of::<HeapBlock>())), true);
+    assert_eq!(stats.largest_free, size - stats.in_use);
+
+    heap.free(first).unwrap();
+    assert_eq!(heap.alloc_aligned(size, 8).is_err(), true);
+    let stats = heap.stats();
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.failed, 1);
+    assert_eq!(stats.peak > stats.in_use, true);
+
+    /* in debug builds, outstanding allocations are counted by tag */
+    #[cfg(debug_assertions)]
+    {
+        let previous = heap.swap_tag(Some("test"));
+        let third = heap.alloc_aligned(100, 8).unwrap();
+        heap.swap_tag(previous);
+
+        let mut tally = Tally::new();
+        heap.tally(&mut tally);
+        assert_eq!(tally.used, 2);
+        assert_eq!(tally.entries.iter().any(|e| e.tag == Some("test") && e.count == 1), true);
+        heap.free(third).unwrap();
+    }
+
+    heap.free(second).unwrap();
+}
+
+#[test_case]
+fn test_heap_integrity()
+{
+    let size = 16 * 1024;
+    let area = TestArea::new(size);
+    let mut heap = Heap::new();
+    heap.init(area.at(0), size);
+    assert_eq!(heap.check().is_ok(), true);
+
+    let block = heap.alloc_aligned(100, 8).unwrap();
+    assert_eq!(owner_of(block), Some(heap.get_id()));
+    assert_eq!(heap.check().is_ok(), true);
+    heap.free(block).unwrap();
+    assert_eq!(owner_of(block), None);
+    assert_eq!(heap.free(block).is_err(), true);
+
+    /* in debug builds, freed memory is poisoned and writing past the end of a block is caught */
+    #[cfg(debug_assertions)]
+    {
+        assert_eq!(unsafe { *block.add(50) }, POISON);
+
+        let block = heap.alloc_aligned(100, 8).unwrap();
+        unsafe { *block.add(100) = 0 };
+        assert_eq!(heap.check().is_err(), true);
+        assert_eq!(heap.free(block).is_err(), true);
+    }
+}
+
+#[test_case]
+fn test_heap_extend()
+{
+    /* a small heap that runs out of memory can carry on once it's given more */
+    let size = 4 * 1024;
+    let area = TestArea::new(size * 4);
+    let mut heap = Heap::new();
+    heap.init(area.at(0), size);
+    let first = heap.alloc_aligned(size / 2, 8).unwrap();
+    assert_eq!(heap.alloc_aligned(size, 8).is_err(), true);
+
+    heap.extend(area.at(size), size * 2);
+    assert_eq!(heap.stats().borrowed, size * 2);
+    let second = heap.alloc_aligned(size, 1024).unwrap();
+    assert_eq!(second as usize % 1024, 0);
+    assert_eq!(heap.check().is_ok(), true);
+
+    heap.free(second).unwrap();
+    heap.free(first).unwrap();
+    assert_eq!(heap.check().is_ok(), true);
+}
@@ -0,0 +1,434 @@
+/* diosix hypercalls for capsules
+ *
+ * Capsules reach the hypervisor's capsule, service, message and
+ * shared memory operations through a diosix-specific SBI extension in the vendor
+ * extension ID range. Calls follow the SBI calling convention:
+ * extension ID in a7, function ID in a6, parameters in a0 to a5,
+ * and an SBI error code returned in a0 with a value in a1.
+ * See docs/hypercalls.md for the list of functions.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use hashbrown::hash_set::HashSet;
+use super::error::Cause;
+use super::sbi::{Call, Outcome, SBIError};
+use super::capsule::{self, CapsuleID};
+use super::service::{self, ServiceID};
+use super::grant::{self, GrantID};
+use super::physmem;
+use super::message::{self, Message, MessageContent, Recipient, Sender, Payload};
+use super::vcore::Priority;
+use super::pcore::PhysicalCore;
+use platform::irq::SupervisorIRQ;
+
+/* diosix's SBI extension ID, within the vendor-specific range */
+pub const EXT_DIOSIX: usize = 0x0900d105;
+
+/* hypercall function IDs */
+const FN_CAPSULE_GET_ID: usize = 0;
+const FN_CAPSULE_CREATE: usize = 1;
+const FN_CAPSULE_DESTROY: usize = 2;
+const FN_CAPSULE_ALLOW_SERVICE: usize = 3;
+const FN_SERVICE_REGISTER: usize = 4;
+const FN_SERVICE_DEREGISTER: usize = 5;
+const FN_MESSAGE_SEND: usize = 6;
+const FN_SERVICE_RECEIVE: usize = 7;
+const FN_MESSAGE_REPLY: usize = 8;
+const FN_MESSAGE_RECEIVE_REPLY: usize = 9;
+const FN_SERVICE_LOOKUP: usize = 10;
+const FN_SERVICE_REGISTER_DYNAMIC: usize = 11;
+const FN_GRANT_CREATE: usize = 12;
+const FN_GRANT_MAP: usize = 13;
+const FN_GRANT_REVOKE: usize = 14;
+const FN_MEMORY_STATS: usize = 15;
+const FN_MEMORY_RECLAIM: usize = 16;
+const FN_MEMORY_RETURN: usize = 17;
+const FN_MEMORY_ADD: usize = 18;
+
+/* received messages are written into the capsule's memory as a record: a header of words
+holding the type of sender, the sender's ID, and the number of bytes of payload, followed
+by space for the largest possible payload */
+const RECORD_SENDER_TYPE: usize = 0;
+const RECORD_SENDER_ID: usize = 1;
+const RECORD_PAYLOAD_SIZE: usize = 2;
+const RECORD_HEADER_WORDS: usize = 3;
+
+/* memory statistics are written into the capsule's memory as a record of words */
+const STATS_TOTAL: usize = 0;
+const STATS_USED: usize = 1;
+const STATS_FREE: usize = 2;
+const STATS_CAPSULE_USED: usize = 3;
+const STATS_CAPSULE_QUOTA: usize = 4;
+const STATS_WORDS: usize = 5;
+
+/* types of message sender */
+const SENDER_CAPSULE: usize = 0;
+const SENDER_SERVICE: usize = 1;
+const SENDER_HYPERVISOR: usize = 2;
+
+/* messages from the hypervisor carry a payload of two words: the type of request and its parameter */
+const HYPERVISOR_REQUEST_WORDS: usize = 2;
+const REQUEST_MEMORY_RETURN: usize = 0; /* parameter = number of pages of RAM to give back */
+
+/* handle a hypercall from a capsule
+   => call = environment call made by a virtual core
+   <= outcome of the call */
+pub fn dispatch(call: &Call) -> Outcome
+{
+    let result = match call.function
+    {
+        FN_CAPSULE_GET_ID => Ok(call.capsule),
+        FN_CAPSULE_CREATE => capsule_create(call),
+        FN_CAPSULE_DESTROY => capsule_destroy(call),
+        FN_CAPSULE_ALLOW_SERVICE => capsule_allow_service(call),
+        FN_SERVICE_REGISTER => service::register(call.args[0] as ServiceID, call.capsule).and(Ok(0)),
+        FN_SERVICE_DEREGISTER => service_deregister(call),
+        FN_MESSAGE_SEND => message_send(call),
+        FN_SERVICE_RECEIVE => service_receive(call),
+        FN_MESSAGE_REPLY => message_reply(call),
+        FN_MESSAGE_RECEIVE_REPLY => message_receive_reply(call),
+        FN_SERVICE_LOOKUP => match service::get_owner(call.args[0] as ServiceID)
+        {
+            Some(cid) => Ok(cid),
+            None => Err(Cause::ServiceNotFound)
+        },
+        FN_SERVICE_REGISTER_DYNAMIC => service::register_dynamic(call.capsule),
+        FN_GRANT_CREATE => grant_create(call),
+        FN_GRANT_MAP => grant::map(call.args[0] as GrantID, call.capsule),
+        FN_GRANT_REVOKE => grant::revoke(call.args[0] as GrantID, call.capsule).and(Ok(0)),
+        FN_MEMORY_STATS => memory_stats(call),
+        FN_MEMORY_RECLAIM => memory_reclaim(call),
+        FN_MEMORY_RETURN => capsule::return_memory(call.capsule, call.args[0], call.args[1]).and(Ok(0)),
+        FN_MEMORY_ADD => capsule::add_memory(call.capsule, call.args[0]),
+        _ => return Outcome::Return(SBIError::NotSupported, 0)
+    };
+
+    match result
+    {
+        Ok(value) => Outcome::Return(SBIError::Success, value),
+        Err(e) =>
+        {
+            hvdebug!("Hypercall 0x{:x} from capsule {} failed: {:?}", call.function, call.capsule, e);
+            Outcome::Return(to_sbi_error(e), 0)
+        }
+    }
+}
+
+/* check the calling capsule is allowed to create and manage other capsules
+   <= Ok if allowed, or an error code if not */
+fn check_manager(call: &Call) -> Result<(), Cause>
+{
+    match capsule::is_manager(call.capsule)
+    {
+        Some(true) => Ok(()),
+        Some(false) => Err(Cause::CapsulePermissionDenied),
+        None => Err(Cause::CapsuleBadID)
+    }
+}
+
+/* create a new capsule from a supervisor binary in the caller's memory
+   => a0 = caller's address of the supervisor binary
+      a1 = size of the supervisor binary in bytes
+      a2 = number of bytes of RAM to give the new capsule
+      a3 = maximum number of virtual cores in the new capsule
+      a4 = most bytes of RAM the new capsule can hold, or 0 for the same as a2
+   <= ID of the new capsule */
+fn capsule_create(call: &Call) -> Result<usize, Cause>
+{
+    check_manager(call)?;
+
+    let (image_addr, image_size, ram, cpus) = (call.args[0], call.args[1], call.args[2], call.args[3]);
+    if cpus == 0
+    {
+        return Err(Cause::CapsuleBadParameter);
+    }
+
+    let quota = match call.args[4]
+    {
+        0 => ram,
+        q => q
+    };
+
+    let image = match capsule::translate_region(call.capsule, image_addr, image_size)
+    {
+        Some(r) => r,
+        None => return Err(Cause::CapsuleBadAddress)
+    };
+
+    capsule::create_from_image(image, ram, quota, cpus, Priority::Normal, false, false, HashSet::new())
+}
+
+/* destroy a capsule other than the caller. use the SBI reset extension to shut down the caller
+   => a0 = ID of the capsule to destroy */
+fn capsule_destroy(call: &Call) -> Result<usize, Cause>
+{
+    check_manager(call)?;
+
+    let victim = call.args[0] as CapsuleID;
+    if victim == call.capsule
+    {
+        return Err(Cause::CapsuleBadParameter);
+    }
+
+    capsule::destroy(victim).and(Ok(0))
+}
+
+/* allow a capsule to register a service. dynamic service IDs are handed out by the
+   hypervisor, so only the reserved range of IDs can be allowed this way
+   => a0 = ID of the capsule
+      a1 = ID of the service */
+fn capsule_allow_service(call: &Call) -> Result<usize, Cause>
+{
+    check_manager(call)?;
+
+    let sid = call.args[1] as ServiceID;
+    if sid == 0 || service::is_dynamic(sid) == true
+    {
+        return Err(Cause::CapsuleBadParameter);
+    }
+
+    capsule::allow_service(call.args[0] as CapsuleID, sid).and(Ok(0))
+}
+
+/* stop the caller providing a service it registered
+   => a0 = ID of the service */
+fn service_deregister(call: &Call) -> Result<usize, Cause>
+{
+    let sid = call.args[0] as ServiceID;
+    match service::get_owner(sid)
+    {
+        Some(owner) if owner == call.capsule => service::deregister(sid).and(Ok(0)),
+        Some(_) => Err(Cause::ServiceNotAllowed),
+        None => Err(Cause::ServiceNotFound)
+    }
+}
+
+/* send a request to a service
+   => a0 = ID of the service
+      a1 = caller's address of the request's payload
+      a2 = number of bytes of payload, which may be zero */
+fn message_send(call: &Call) -> Result<usize, Cause>
+{
+    let payload = read_payload(call, call.args[1], call.args[2])?;
+    let msg = Message::from_capsule(call.capsule, Recipient::send_to_service(call.args[0] as ServiceID),
+                                    MessageContent::ServiceRequest(payload));
+    message::send(msg).and(Ok(0))
+}
+
+/* receive the oldest message waiting for a service the caller registered
+   => a0 = ID of the service
+      a1 = caller's address of a message record to fill in
+   <= 1 if a message was received, or 0 if none were waiting */
+fn service_receive(call: &Call) -> Result<usize, Cause>
+{
+    let record = check_record(call, call.args[1])?;
+    let msg = service::receive(call.args[0] as ServiceID, call.capsule)?;
+    Ok(received(call, record, msg))
+}
+
+/* reply to a capsule on behalf of a service the caller registered
+   => a0 = ID of the service replying
+      a1 = ID of the capsule to reply to
+      a2 = caller's address of the reply's payload
+      a3 = number of bytes of payload, which may be zero */
+fn message_reply(call: &Call) -> Result<usize, Cause>
+{
+    let sid = call.args[0] as ServiceID;
+    let payload = read_payload(call, call.args[2], call.args[3])?;
+    let msg = Message::new(Recipient::send_to_capsule(call.args[1] as CapsuleID),
+                           MessageContent::ServiceReply(sid, payload));
+    service::reply(sid, call.capsule, msg).and(Ok(0))
+}
+
+/* share a range of the caller's own memory with another capsule
+   => a0 = caller's page-aligned address of the memory
+      a1 = page-aligned number of bytes to share
+      a2 = ID of the capsule to share the memory with
+      a3 = 1 to allow the capsule to write to the memory, or 0 for read-only access
+   <= ID of the grant, which the other capsule uses to map the memory */
+fn grant_create(call: &Call) -> Result<usize, Cause>
+{
+    let writable = match call.args[3]
+    {
+        0 => false,
+        1 => true,
+        _ => return Err(Cause::CapsuleBadParameter)
+    };
+
+    grant::create(call.capsule, call.args[0], call.args[1], call.args[2] as CapsuleID, writable)
+}
+
+/* describe the host's physical RAM and how much of it a capsule holds. only manager
+   capsules may ask about capsules other than themselves
+   => a0 = caller's address of a record of words to fill in
+      a1 = ID of the capsule to describe */
+fn memory_stats(call: &Call) -> Result<usize, Cause>
+{
+    let cid = call.args[1] as CapsuleID;
+    if cid != call.capsule
+    {
+        check_manager(call)?;
+    }
+
+    let word = core::mem::size_of::<usize>();
+    let record = match (call.args[0] % word, capsule::translate_writable_region(call.capsule, call.args[0], STATS_WORDS * word))
+    {
+        (0, Some(r)) => r.base() as *mut usize,
+        (_, _) => return Err(Cause::CapsuleBadAddress)
+    };
+
+    let usage = match capsule::memory_usage(cid)
+    {
+        Some(u) => u,
+        None => return Err(Cause::CapsuleBadID)
+    };
+    let host = physmem::stats();
+
+    unsafe
+    {
+        *record.add(STATS_TOTAL) = host.total;
+        *record.add(STATS_USED) = host.total.saturating_sub(host.free + host.unscrubbed);
+        *record.add(STATS_FREE) = host.free + host.unscrubbed;
+        *record.add(STATS_CAPSULE_USED) = usage.used;
+        *record.add(STATS_CAPSULE_QUOTA) = usage.quota;
+    }
+    Ok(0)
+}
+
+/* ask another capsule's balloon driver to give RAM back to the hypervisor
+   => a0 = ID of the capsule
+      a1 = number of pages of RAM to ask for */
+fn memory_reclaim(call: &Call) -> Result<usize, Cause>
+{
+    check_manager(call)?;
+    capsule::request_memory_return(call.args[0] as CapsuleID, call.args[1]).and(Ok(0))
+}
+
+/* copy a message payload out of the caller's memory
+   => addr = caller's address of the payload
+      size = number of bytes of payload
+   <= payload, or an error code */
+fn read_payload(call: &Call, addr: usize, size: usize) -> Result<Payload, Cause>
+{
+    if size == 0
+    {
+        return Payload::new(&[]);
+    }
+
+    if size > message::PAYLOAD_MAX
+    {
+        return Err(Cause::MessageTooLarge);
+    }
+
+    match capsule::translate_region(call.capsule, addr, size)
+    {
+        Some(r) => Payload::new(unsafe { core::slice::from_raw_parts(r.base() as *const u8, size) }),
+        None => Err(Cause::CapsuleBadAddress)
+    }
+}
+
+/* receive the oldest reply waiting in the caller's inbox
+   => a0 = caller's address of a message record to fill in
+   <= 1 if a message was received, or 0 if none were waiting */
+fn message_receive_reply(call: &Call) -> Result<usize, Cause>
+{
+    let record = check_record(call, call.args[0])?;
+    let msg = service::receive_reply(call.capsule);
+    Ok(received(call, record, msg))
+}
+
+/* check a capsule's message record is aligned and entirely within its memory. do this before
+   taking a message from a queue so that a bad record doesn't lose the message
+   => addr = capsule's address of the record
+   <= physical address of the record, or an error code */
+fn check_record(call: &Call, addr: usize) -> Result<usize, Cause>
+{
+    let word = core::mem::size_of::<usize>();
+    if addr % word != 0
+    {
+        return Err(Cause::CapsuleBadAddress);
+    }
+
+    match capsule::translate_writable_region(call.capsule, addr, (RECORD_HEADER_WORDS * word) + message::PAYLOAD_MAX)
+    {
+        Some(r) => Ok(r.base()),
+        None => Err(Cause::CapsuleBadAddress)
+    }
+}
+
+/* write a message into a capsule's message record
+   => record = physical address of the record
+      msg = message to write */
+fn write_record(record: usize, msg: &Message)
+{
+    let (sender_type, sender_id) = match msg.get_sender()
+    {
+        Sender::Capsule(cid) => (SENDER_CAPSULE, cid),
+        Sender::Service(sid) => (SENDER_SERVICE, sid),
+        Sender::PhysicalCore(_) => (SENDER_HYPERVISOR, 0)
+    };
+
+    let mut request = [0usize; HYPERVISOR_REQUEST_WORDS];
+    let payload: &[u8] = match msg.get_content()
+    {
+        MessageContent::ServiceRequest(p) | MessageContent::ServiceReply(_, p) => p.as_slice(),
+        MessageContent::MemoryReturnRequest(pages) =>
+        {
+            request = [REQUEST_MEMORY_RETURN, *pages];
+            unsafe { core::slice::from_raw_parts(request.as_ptr() as *const u8, core::mem::size_of_val(&request)) }
+        },
+        _ => &[]
+    };
+
+    let ptr = record as *mut usize;
+    unsafe
+    {
+        *ptr.add(RECORD_SENDER_TYPE) = sender_type;
+        *ptr.add(RECORD_SENDER_ID) = sender_id;
+        *ptr.add(RECORD_PAYLOAD_SIZE) = payload.len();
+
+        let bytes = ptr.add(RECORD_HEADER_WORDS) as *mut u8;
+        core::ptr::copy_nonoverlapping(payload.as_ptr(), bytes, payload.len());
+    }
+}
+
+/* write any received message into the caller's record, and once the caller has nothing
+   left waiting, clear the notification interrupt from the calling virtual core
+   => record = physical address of the record
+      msg = message received, or None if there was nothing waiting
+   <= 1 if a message was written, or 0 if not */
+fn received(call: &Call, record: usize, msg: Option<Message>) -> usize
+{
+    let result = match msg
+    {
+        Some(m) =>
+        {
+            write_record(record, &m);
+            1
+        },
+        None => 0
+    };
+
+    if service::messages_waiting(call.capsule) == false
+    {
+        PhysicalCore::update_running_vcore(|vcore| vcore.clear_irq(SupervisorIRQ::External));
+    }
+    result
+}
+
+/* convert a hypervisor error code into an SBI error code for the caller */
+fn to_sbi_error(cause: Cause) -> SBIError
+{
+    match cause
+    {
+        Cause::CapsulePermissionDenied | Cause::ServiceNotAllowed | Cause::GrantNotAllowed => SBIError::Denied,
+        Cause::CapsuleBadAddress | Cause::GrantBadRange | Cause::GrantOverlap => SBIError::InvalidAddress,
+        Cause::CapsuleBadID | Cause::CapsuleBadParameter | Cause::ServiceNotFound |
+        Cause::MessageTooLarge | Cause::GrantBadID => SBIError::InvalidParam,
+        Cause::ServiceAlreadyRegistered | Cause::GrantAlreadyMapped => SBIError::AlreadyAvailable,
+        _ => SBIError::Failed
+    }
+}
@@ -10,6 +10,9 @@ use super::debug;
 use super::scheduler;
 use super::capsule;
 use super::pcore;
+use super::sbi;
+use super::message;
+use super::hardware;
 
 /* platform-specific code must implement all this */
 use platform;
@@ -39,17 +42,7 @@ fn exception(irq: IRQ)
     match (irq.fatal, irq.privilege_mode, irq.cause)
     {
         /* catch non-fatal supervisor-level exceptions */
-        (false, PrivilegeMode::Supervisor, IRQCause::SupervisorEnvironmentCall) =>
-        {
-            if let Some(c) = pcore::PhysicalCore::get_capsule_id()
-            {
-                hvdebug!("Environment call from supervisor-mode capsule ID {}", c);
-            }
-            else
-            {
-                hvalert!("BUG: Environment call from supervisor mode but no capsule found");
-            }
-        },
+        (false, PrivilegeMode::Supervisor, IRQCause::SupervisorEnvironmentCall) => sbi::handle(),
 
         /* catch fatal supervisor-level exceptions */
         (true, PrivilegeMode::Supervisor, cause) =>
@@ -58,10 +51,12 @@ fn exception(irq: IRQ)
                 "Fatal exception in {:?}: {:?} at 0x{:x}, stack 0x{:x}",
                 PrivilegeMode::Supervisor, cause, irq.pc, irq.sp);
 
-            /* terminate the capsule running on this core */
+            /* terminate the capsule running on this core, restarting it if it's flagged to auto-restart.
+            the crashed virtual core must not be queued to run again */
             if let Some(c) = pcore::PhysicalCore::get_capsule_id()
             {
-                if capsule::destroy(c).is_ok() != true
+                pcore::discard_running_vcore();
+                if capsule::crashed(c).is_ok() != true
                 {
                     hvalert!("BUG: Could not kill capsule ID {}", c);
                 }
@@ -97,7 +92,18 @@ fn interrupt(irq: IRQ)
     match irq.cause
     {
         /* handle our scheduler's timer by picking another thing to run, if possible */
-        IRQCause::HypervisorTimer => scheduler::run_next(false), 
+        IRQCause::HypervisorTimer => scheduler::timer_irq(),
+
+        /* another physical CPU core has sent us a message. if the virtual core we were running
+        has been taken away as a result, we can't return to it */
+        IRQCause::HypervisorSoftware =>
+        {
+            hardware::clear_pcore_interrupt();
+            if message::check_mailbox() == true
+            {
+                scheduler::run_next(true);
+            }
+        },
         _ => hvdebug!("Unhandled hardware interrupt: {:?}", irq.cause)
     };
 
@@ -23,6 +23,7 @@
 /* plug our custom heap allocator into the Rust language: Box, etc */
 #![feature(alloc_error_handler)]
 #![feature(box_syntax)]
+#![feature(try_reserve)]
 #[macro_use]
 extern crate alloc;
 
@@ -48,7 +49,11 @@ extern crate platform;
 #[macro_use]
 mod debug;      /* get us some kind of debug output, typically to a serial port */
 mod hardware;   /* parse device trees into hardware objects */
+#[macro_use]
+mod console;    /* virtual consoles for capsules */
+#[macro_use]
 mod heap;       /* per-CPU private heap management */
+mod slab;       /* per-CPU allocator for small objects */
 mod abort;      /* implement abort() and panic() handlers */
 mod irq;        /* handle hw interrupts and sw exceptions, collectively known as IRQs */
 #[macro_use]
@@ -58,9 +63,14 @@ mod pcore;      /* manage CPU cores */
 mod vcore;      /* virtual CPU core management... */
 mod scheduler;  /* ...and scheduling */
 mod capsule;    /* manage capsules */
+mod grant;      /* share memory between capsules */
 mod loader;     /* parse and load supervisor binaries */
+mod manifest;   /* create the capsules listed in the built-in manifest */
+mod dtb;        /* generate device trees describing capsules */
 mod message;    /* send messages between physical cores */
 mod service;    /* allow capsules to register services */
+mod sbi;        /* handle environment calls from capsule supervisors */
+mod hypercall;  /* diosix-specific calls from capsules */
 
 use pcore::{PhysicalCoreID, BOOT_PCORE_ID};
 
@@ -92,11 +102,7 @@ in future, we may support 16- or 128-bit, too. stick to usize as much as possibl
 #[no_mangle]
 pub extern "C" fn hventry(cpu_nr: PhysicalCoreID, dtb: &devicetree::DeviceTreeBlob)
 {
-    /* carry out tests if that's what we're here for */
-    #[cfg(test)]
-    hvtests();
-
-    /* if not then start the system as normal */
+    /* start the system as normal */
     match hvmain(cpu_nr, dtb)
     {
         Err(e) => hvalert!("hvmain bailed out with error: {:?}", e),
@@ -131,6 +137,16 @@ fn hvmain(cpu_nr: PhysicalCoreID, dtb: &devicetree::DeviceTreeBlob) -> Result<()
     resources. all non-boot CPUs should wait until global resources are ready. */
     pcore::PhysicalCore::init(cpu_nr);
 
+    /* carry out tests if that's what we're here for. this is done once the boot CPU
+    has its heap so that tests can allocate memory */
+    #[cfg(test)]
+    {
+        if cpu_nr == BOOT_PCORE_ID
+        {
+            hvtests();
+        }
+    }
+
     match cpu_nr
     {
         /* delegate to boot CPU the welcome banner and set up global resources */
@@ -142,13 +158,14 @@ fn hvmain(cpu_nr: PhysicalCoreID, dtb: &devicetree::DeviceTreeBlob) -> Result<()
             hardware::parse_and_init(dtb)?;
 
             physmem::init()?; /* register all the available physical RAM */
+            virtmem::init(); /* decide how capsules' memory will be translated and protected */
 
             /* say hello via the debug port */
             hvlog!("Welcome to {} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
             hvdebug!("Debugging enabled, {} CPU cores found", hardware::get_nr_cpu_cores().unwrap_or(0));
 
-            /* initialize boot capsule */
-            capsule::create_boot_capsule()?;
+            /* create the capsules to run at boot */
+            manifest::launch()?;
 
             /* allow other cores to continue */
             *(INIT_DONE.lock()) = true;
@@ -170,12 +187,17 @@ fn hvmain(cpu_nr: PhysicalCoreID, dtb: &devicetree::DeviceTreeBlob) -> Result<()
     Ok(())
 }
 
-/* mandatory error handler for memory allocations */
+/* mandatory error handler for memory allocations. the heap grows when it runs out of memory,
+and code that can recover from running out, such as capsule creation, uses try_reserve()
+and friends to get an error code instead. so we only end up here when all else has failed */
 #[alloc_error_handler]
 fn kalloc_error(attempt: core::alloc::Layout) -> !
 {
-    hvalert!("alloc_error_handler: Failed to allocate/free {} bytes. Halting...", attempt.size());
-    loop {} /* it would be nice to be able to not die here :( */
+    /* the debug queue needs the heap, and this core may have been adding to it, so write straight to the debug port */
+    hvalert_now!("alloc_error_handler: Failed to allocate/free {} bytes. Halting...", attempt.size());
+    heap::dump();
+    debug::flush_queue();
+    loop {}
 }
 
 /* perform all unit tests required */
@@ -0,0 +1,321 @@
+/* diosix capsule launch manifest
+ *
+ * The build system packages a manifest listing the capsules to
+ * create at boot, and the supervisor binaries they run, into the
+ * hypervisor. The manifest is a normalized subset of TOML: one
+ * [[capsule]] table per capsule, each holding key = value lines
+ * where a value is a "string", a number, true or false, or a
+ * [ list, of, numbers ]. See boot/manifest.toml for the settings.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use hashbrown::hash_set::HashSet;
+use platform::physmem::PhysMemSize;
+use super::error::Cause;
+use super::physmem::Region;
+use super::vcore::Priority;
+use super::service::ServiceID;
+use super::capsule;
+use super::heap;
+
+/* the table of supervisors packaged with the hypervisor, generated by build.rs */
+mod images
+{
+    include!(concat!(env!("OUT_DIR"), "/images.rs"));
+}
+
+/* the normalized manifest, generated by build.rs */
+const MANIFEST: &'static str = include_str!(concat!(env!("OUT_DIR"), "/manifest.toml"));
+
+/* RAM sizes are given in megabytes */
+const RAM_UNIT: PhysMemSize = 1024 * 1024;
+
+/* describe a capsule to create at boot */
+pub struct Entry
+{
+    image: String,              /* name of the supervisor binary to run */
+    ram: PhysMemSize,           /* bytes of physical RAM to give the capsule */
+    quota: Option<PhysMemSize>, /* most bytes of physical RAM the capsule can hold, or None for the same as ram */
+    vcores: usize,              /* maximum number of virtual cores */
+    priority: Priority,         /* priority of the capsule's virtual cores */
+    auto_restart: bool,         /* true to restart the capsule if it dies */
+    manager: bool,              /* true to allow the capsule to manage other capsules */
+    services: Vec<ServiceID>    /* services the capsule may register */
+}
+
+impl Entry
+{
+    pub fn new() -> Entry
+    {
+        Entry
+        {
+            image: String::new(),
+            ram: 0,
+            quota: None,
+            vcores: 0,
+            priority: Priority::Normal,
+            auto_restart: false,
+            manager: false,
+            services: Vec::new()
+        }
+    }
+
+    /* apply a key = value setting from the manifest to this entry
+       <= Ok for success, or an error code for an unknown key or bad value */
+    fn set(&mut self, key: &str, value: &str) -> Result<(), Cause>
+    {
+        match key
+        {
+            "image" => self.image = String::from(parse_string(value)?),
+            "ram" => self.ram = parse_megabytes(value)?,
+            "quota" => self.quota = Some(parse_megabytes(value)?),
+            "vcores" => self.vcores = parse_number(value)?,
+            "priority" => self.priority = match parse_string(value)?
+            {
+                "high" => Priority::High,
+                "normal" => Priority::Normal,
+                _ => return Err(Cause::ManifestBadValue)
+            },
+            "auto_restart" => self.auto_restart = parse_bool(value)?,
+            "manager" => self.manager = parse_bool(value)?,
+            "services" => self.services = parse_number_list(value)?,
+            _ => return Err(Cause::ManifestBadKey)
+        };
+        Ok(())
+    }
+
+    /* <= Ok if this entry describes a capsule that can be created, or an error code */
+    fn validate(&self) -> Result<(), Cause>
+    {
+        if self.image.len() == 0 || self.ram == 0 || self.vcores == 0 || self.get_quota() < self.ram
+        {
+            return Err(Cause::ManifestBadValue);
+        }
+        Ok(())
+    }
+
+    /* <= most bytes of physical RAM the capsule can hold */
+    fn get_quota(&self) -> PhysMemSize
+    {
+        self.quota.unwrap_or(self.ram)
+    }
+
+    /* find this entry's supervisor in memory
+       <= region of physical memory holding the supervisor, or an error code */
+    fn find_image(&self) -> Result<Region, Cause>
+    {
+        match images::find(self.image.as_str())
+        {
+            Some((start, end)) => Ok(Region::new(start, end - start)),
+            None => Err(Cause::ManifestImageNotFound)
+        }
+    }
+}
+
+/* parse a manifest into a list of capsules to create
+   => text = manifest to parse
+   <= list of capsules, in the order they appear, or an error code */
+pub fn parse(text: &str) -> Result<Vec<Entry>, Cause>
+{
+    let mut entries: Vec<Entry> = Vec::new();
+
+    for line in text.lines()
+    {
+        /* strip comments. strings in the manifest never contain # */
+        let line = match line.find('#')
+        {
+            Some(index) => &line[..index],
+            None => line
+        }.trim();
+
+        if line.len() == 0
+        {
+            continue;
+        }
+
+        if line == "[[capsule]]"
+        {
+            if let Some(previous) = entries.last()
+            {
+                previous.validate()?;
+            }
+            entries.push(Entry::new());
+            continue;
+        }
+
+        /* settings must belong to a capsule */
+        let entry = match (entries.last_mut(), line.find('='))
+        {
+            (Some(e), Some(_)) => e,
+            (_, _) => return Err(Cause::ManifestBadSyntax)
+        };
+
+        let mut setting = line.splitn(2, '=');
+        match (setting.next(), setting.next())
+        {
+            (Some(key), Some(value)) => entry.set(key.trim(), value.trim())?,
+            (_, _) => return Err(Cause::ManifestBadSyntax)
+        }
+    }
+
+    match entries.last()
+    {
+        Some(last) => last.validate()?,
+        None => return Err(Cause::ManifestNoCapsules)
+    };
+
+    Ok(entries)
+}
+
+/* create every capsule listed in the manifest packaged with the hypervisor. a capsule
+   that can't be created is reported and skipped so that the rest can still run
+   <= Ok if at least one capsule was created, or an error code */
+pub fn launch() -> Result<(), Cause>
+{
+    let mut created = 0;
+
+    for (index, entry) in parse(MANIFEST)?.iter().enumerate()
+    {
+        match create(entry)
+        {
+            Ok(cid) =>
+            {
+                hvdebug!("Created capsule {} running supervisor '{}' with {} MiB of RAM and up to {} virtual cores",
+                    cid, entry.image, entry.ram / RAM_UNIT, entry.vcores);
+                created = created + 1;
+            },
+            Err(e) => hvalert!("Failed to create capsule {} in manifest, supervisor '{}' ({:?})", index, entry.image, e)
+        }
+    }
+
+    match created
+    {
+        0 => Err(Cause::ManifestNoCapsules),
+        _ => Ok(())
+    }
+}
+
+/* create a capsule described in the manifest, allowed to register its services from the moment it starts
+   => entry = the capsule to create
+   <= ID of the new capsule, or an error code */
+fn create(entry: &Entry) -> Result<capsule::CapsuleID, Cause>
+{
+    let mut services = HashSet::new();
+    heap::reserved(services.try_reserve(entry.services.len()))?;
+    services.extend(entry.services.iter().cloned());
+
+    capsule::create_from_image(entry.find_image()?, entry.ram, entry.get_quota(), entry.vcores,
+                               entry.priority, entry.auto_restart, entry.manager, services)
+}
+
+/* <= contents of a "string" value, or an error code */
+fn parse_string(value: &str) -> Result<&str, Cause>
+{
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"')
+    {
+        return Ok(&value[1..value.len() - 1]);
+    }
+    Err(Cause::ManifestBadValue)
+}
+
+/* <= a decimal or 0x-prefixed hexadecimal number, or an error code */
+fn parse_number(value: &str) -> Result<usize, Cause>
+{
+    let result = match value.starts_with("0x")
+    {
+        true => usize::from_str_radix(&value[2..], 16),
+        false => usize::from_str_radix(value, 10)
+    };
+
+    match result
+    {
+        Ok(n) => Ok(n),
+        Err(_) => Err(Cause::ManifestBadValue)
+    }
+}
+
+/* <= a number of megabytes converted into bytes, or an error code */
+fn parse_megabytes(value: &str) -> Result<PhysMemSize, Cause>
+{
+    match parse_number(value)?.checked_mul(RAM_UNIT)
+    {
+        Some(bytes) => Ok(bytes),
+        None => Err(Cause::ManifestBadValue)
+    }
+}
+
+/* <= true or false, or an error code */
+fn parse_bool(value: &str) -> Result<bool, Cause>
+{
+    match value
+    {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(Cause::ManifestBadValue)
+    }
+}
+
+/* <= contents of a [ list, of, numbers ], or an error code */
+fn parse_number_list(value: &str) -> Result<Vec<usize>, Cause>
+{
+    if value.len() < 2 || value.starts_with('[') == false || value.ends_with(']') == false
+    {
+        return Err(Cause::ManifestBadValue);
+    }
+
+    let mut list = Vec::new();
+    for item in value[1..value.len() - 1].split(',')
+    {
+        let item = item.trim();
+        if item.len() > 0
+        {
+            list.push(parse_number(item)?);
+        }
+    }
+    Ok(list)
+}
+
+#[test_case]
+fn test_parse()
+{
+    let manifest = "# test manifest\n\
+                    [[capsule]]\n\
+                    image = \"supervisor\"\n\
+                    ram = 128 # megabytes\n\
+                    vcores = 2\n\
+                    priority = \"high\"\n\
+                    auto_restart = true\n\
+                    manager = true\n\
+                    \n\
+                    [[capsule]]\n\
+                    image = \"guest\"\n\
+                    ram = 0x40\n\
+                    quota = 256\n\
+                    vcores = 1\n\
+                    services = [ 1, 3 ]\n";
+
+    let entries = parse(manifest).expect("Failed to parse test manifest");
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].image.as_str(), "supervisor");
+    assert_eq!(entries[0].ram, 128 * RAM_UNIT);
+    assert_eq!(entries[0].vcores, 2);
+    assert_eq!(entries[0].auto_restart && entries[0].manager, true);
+    assert_eq!(entries[1].ram, 64 * RAM_UNIT);
+    assert_eq!(entries[0].get_quota(), entries[0].ram);
+    assert_eq!(entries[1].get_quota(), 256 * RAM_UNIT);
+    assert_eq!(entries[1].auto_restart || entries[1].manager, false);
+    assert_eq!(entries[1].services, vec![1, 3]);
+
+    /* capsules must have an image, RAM and at least one virtual core */
+    assert_eq!(parse("[[capsule]]\nimage = \"x\"\nram = 1\n").is_err(), true);
+    assert_eq!(parse("image = \"x\"\n").is_err(), true);
+    assert_eq!(parse("").is_err(), true);
+
+    /* and they can't start with more RAM than their quota allows */
+    assert_eq!(parse("[[capsule]]\nimage = \"x\"\nram = 2\nquota = 1\nvcores = 1\n").is_err(), true);
+}
@@ -9,10 +9,14 @@ use spin::Mutex;
 use alloc::collections::vec_deque::VecDeque;
 use hashbrown::hash_map::{self, HashMap};
 use super::error::Cause;
+use super::heap;
 use super::service::{self, ServiceID};
-use super::capsule::CapsuleID;
-use super::vcore::VirtualCoreID;
-use super::pcore::{PhysicalCoreID, PhysicalCore};
+use super::capsule::{self, CapsuleID};
+use super::vcore::{self, VirtualCoreID, VirtualCoreCanonicalID};
+use super::pcore::{self, PhysicalCoreID, PhysicalCore};
+use super::scheduler;
+use super::hardware;
+use alloc::vec::Vec;
 
 /* here's how message passing works, depending on the target:
     * To an individual physical core:
@@ -25,8 +29,12 @@ use super::pcore::{PhysicalCoreID, PhysicalCore};
         3. interrupt each physical CPU core to check its mailbox
     * To a service registered by a capsule:
         1. locate the service's mailbox
-        2. insert the message into the mailbox
-        3. raise an interrupt or wait for the capsule to poll the mailbox
+        2. insert the message at the end of the mailbox, unless it's full
+        3. raise an external interrupt in the capsule, which then fetches the message with a hypercall
+    * To a capsule, typically a service's reply:
+        1. locate the capsule's inbox
+        2. insert the message at the end of the inbox, unless it's full
+        3. raise an external interrupt in the capsule, which then fetches the message with a hypercall
 */
 
 /* maintain a mailbox of messages per physical CPU core */
@@ -41,11 +49,18 @@ pub fn create_mailbox(coreid: PhysicalCoreID)
     MAILBOXES.lock().insert(coreid, VecDeque::<Message>::new());
 }
 
-#[derive(Clone)]
+/* return the IDs of the physical CPU cores that have mailboxes, and so receive broadcasts */
+pub fn mailbox_owners() -> Vec<PhysicalCoreID>
+{
+    MAILBOXES.lock().keys().cloned().collect()
+}
+
+#[derive(Clone, Copy)]
 pub enum Sender
 {
     PhysicalCore(PhysicalCoreID),
-    Capsule(CapsuleID)
+    Capsule(CapsuleID),
+    Service(ServiceID)
 }
 
 #[derive(Clone, Copy)]
@@ -53,7 +68,8 @@ pub enum Recipient
 {
     Broadcast,                      /* send to all physical CPU cores */
     PhysicalCore(PhysicalCoreID),   /* send to a single physical CPU core */
-    Service(ServiceID)              /* send to a single registered service */
+    Service(ServiceID),             /* send to a single registered service */
+    Capsule(CapsuleID)              /* send to a capsule's inbox */
 }
 
 impl Recipient
@@ -72,15 +88,63 @@ impl Recipient
     {
         Recipient::Service(id)
     }
+
+    /* send to a particular capsule's inbox */
+    pub fn send_to_capsule(id: CapsuleID) -> Recipient
+    {
+        Recipient::Capsule(id)
+    }
 }
 
-#[derive(Clone, Copy)]
+/* maximum number of bytes of data a message can carry between capsules */
+pub const PAYLOAD_MAX: usize = 256;
+
+/* data carried by a message, copied in from the sender's memory and out into the recipient's */
+#[derive(Clone)]
+pub struct Payload
+{
+    bytes: Vec<u8>
+}
+
+impl Payload
+{
+    /* create a payload from a copy of the given bytes
+       <= payload, or an error code if there are too many bytes */
+    pub fn new(bytes: &[u8]) -> Result<Payload, Cause>
+    {
+        if bytes.len() > PAYLOAD_MAX
+        {
+            return Err(Cause::MessageTooLarge);
+        }
+
+        let mut copy = Vec::new();
+        heap::reserved(copy.try_reserve_exact(bytes.len()))?;
+        copy.extend_from_slice(bytes);
+        Ok(Payload { bytes: copy })
+    }
+
+    pub fn as_slice(&self) -> &[u8] { self.bytes.as_slice() }
+    pub fn len(&self) -> usize { self.bytes.len() }
+}
+
+#[derive(Clone)]
 pub enum MessageContent
 {
     /* warn all physical CPUs capsule is dying */
     CapsuleTeardown(CapsuleID),
+    /* tell all physical CPUs to reload a capsule's memory protection after memory was taken away from it,
+    and to acknowledge the numbered revocation once they have */
+    CapsuleMemoryRevoked(CapsuleID, usize),
     /* return any queued virtual core to the global queue for other physical CPUs to schedule */
-    DisownQueuedVirtualCore
+    DisownQueuedVirtualCore,
+    /* capsule-defined request sent to a service */
+    ServiceRequest(Payload),
+    /* service-defined reply sent from a service back to a capsule */
+    ServiceReply(ServiceID, Payload),
+    /* ask a capsule to give the given number of pages of RAM back to the hypervisor */
+    MemoryReturnRequest(usize),
+    /* deliver the interrupts and actions raised for a virtual core if it's running on the recipient physical CPU */
+    DeliverPending(VirtualCoreCanonicalID)
 }
 
 #[derive(Clone)]
@@ -103,14 +167,43 @@ impl Message
         Message
         {
             receiver: recv,
-            code: data,
             
             /* determine sender from message type */
-            sender: match data
+            sender: match &data
             {
                 MessageContent::CapsuleTeardown(_) => Sender::PhysicalCore(PhysicalCore::get_id()),
-                MessageContent::DisownQueuedVirtualCore => Sender::PhysicalCore(PhysicalCore::get_id())
-            }
+                MessageContent::CapsuleMemoryRevoked(_, _) => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::DisownQueuedVirtualCore => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::MemoryReturnRequest(_) => Sender::PhysicalCore(PhysicalCore::get_id()),
+                MessageContent::DeliverPending(_) => Sender::PhysicalCore(PhysicalCore::get_id()),
+
+                /* requests come from the capsule running on this physical core */
+                MessageContent::ServiceRequest(_) => match PhysicalCore::get_capsule_id()
+                {
+                    Some(cid) => Sender::Capsule(cid),
+                    None => Sender::PhysicalCore(PhysicalCore::get_id())
+                },
+
+                /* replies come from the service */
+                MessageContent::ServiceReply(sid, _) => Sender::Service(*sid)
+            },
+
+            code: data
+        }
+    }
+
+    /* create a new message sent by a capsule
+       => sender = ID of the capsule sending the message, taken from the calling virtual core
+          recv = end point to send the message to
+          data = message to send to the recipient
+       <= returns message structure */
+    pub fn from_capsule(sender: CapsuleID, recv: Recipient, data: MessageContent) -> Message
+    {
+        Message
+        {
+            sender: Sender::Capsule(sender),
+            receiver: recv,
+            code: data
         }
     }
 
@@ -118,40 +211,147 @@ impl Message
     {
         self.receiver
     }
+
+    pub fn get_sender(&self) -> Sender
+    {
+        self.sender
+    }
+
+    pub fn get_content(&self) -> &MessageContent
+    {
+        &self.code
+    }
+}
+
+/* act on the messages waiting in this physical CPU core's mailbox. this is called when
+   the physical core is interrupted by another, and periodically in case an interrupt was missed
+   <= true if the virtual core running on this physical CPU core was removed as a result,
+      so something else must be found to run, or false if not */
+pub fn check_mailbox() -> bool
+{
+    let mut running_removed = false;
+    loop
+    {
+        /* release the lock before handling the message so that handlers can send messages */
+        let msg = match MAILBOXES.lock().get_mut(&PhysicalCore::get_id())
+        {
+            Some(mailbox) => mailbox.pop_front(),
+            None => None
+        };
+
+        match msg
+        {
+            Some(m) => if handle(m) == true
+            {
+                running_removed = true;
+            },
+            None => return running_removed
+        }
+    }
+}
+
+/* act on a message received by this physical CPU core
+   => msg = message to handle
+   <= true if the virtual core running on this physical CPU core was removed, or false if not */
+fn handle(msg: Message) -> bool
+{
+    match msg.code
+    {
+        MessageContent::CapsuleTeardown(cid) => pcore::purge_capsule(cid),
+        MessageContent::CapsuleMemoryRevoked(cid, number) =>
+        {
+            /* capsules not running here pick up their new memory layout when they're next switched in */
+            if PhysicalCore::get_capsule_id() == Some(cid)
+            {
+                if let Err(e) = capsule::enforce(cid)
+                {
+                    hvalert!("BUG: Could not enforce capsule {} memory access ({:?})", cid, e);
+                }
+            }
+
+            /* this physical CPU core can no longer reach the memory, so it can be freed once the others can't either */
+            capsule::acknowledge_revocation(number);
+            false
+        },
+        MessageContent::DisownQueuedVirtualCore =>
+        {
+            scheduler::disown_queued_vcore();
+            false
+        },
+        MessageContent::DeliverPending(id) =>
+        {
+            vcore::deliver_running(id);
+            false
+        },
+
+        /* requests and replies are queued for services and capsules, not sent to physical CPU cores */
+        MessageContent::ServiceRequest(_) | MessageContent::ServiceReply(_, _) | MessageContent::MemoryReturnRequest(_) =>
+        {
+            hvalert!("BUG: Service request delivered to physical CPU core {}", PhysicalCore::get_id());
+            false
+        }
+    }
 }
 
 /* send the given message msg, consuming it so it can't be reused or resent */
 pub fn send(msg: Message) -> Result<(), Cause>
 {
+    let _tag = heaptag!();
     let receiver = msg.receiver;
     match receiver
     {
         /* iterate over all physical CPU cores */
         Recipient::Broadcast =>
         {
-            for (_, mailbox) in MAILBOXES.lock().iter_mut()
+            let mut recipients = Vec::new();
             {
-                mailbox.push_back(msg.clone())
+                /* make room in every mailbox first so that the message reaches all physical CPU cores or none */
+                let mut mailboxes = MAILBOXES.lock();
+                heap::reserved(recipients.try_reserve(mailboxes.len()))?;
+                for mailbox in mailboxes.values_mut()
+                {
+                    heap::reserved(mailbox.try_reserve(1))?;
+                }
+
+                for (&pid, mailbox) in mailboxes.iter_mut()
+                {
+                    mailbox.push_back(msg.clone());
+                    recipients.push(pid);
+                }
+            }
+
+            /* interrupt the cores once they can get at their mailboxes */
+            for pid in recipients
+            {
+                hardware::interrupt_pcore(pid);
             }
         },
 
         /* send to a particular physical CPU core */
         Recipient::PhysicalCore(pid) =>
         {
-            if let Some(mailbox) = MAILBOXES.lock().get_mut(&pid)
-            {
-                mailbox.push_back(msg);
-            }
-            else
+            match MAILBOXES.lock().get_mut(&pid)
             {
-                return Err(Cause::PhysicalCoreBadID);
-            }
+                Some(mailbox) =>
+                {
+                    heap::reserved(mailbox.try_reserve(1))?;
+                    mailbox.push_back(msg)
+                },
+                None => return Err(Cause::PhysicalCoreBadID)
+            };
+            hardware::interrupt_pcore(pid);
         },
 
         /* send to a service */
-        Recipient::Service(sid) =>
+        Recipient::Service(_) =>
         {
             return service::send(msg);
+        },
+
+        /* send to a capsule's inbox */
+        Recipient::Capsule(_) =>
+        {
+            return service::deliver(msg);
         }
     };
 
@@ -23,6 +23,7 @@ use super::scheduler::ScheduleQueues;
 use super::capsule::{self, CapsuleID};
 use super::message;
 use super::heap;
+use super::slab;
 
 /* physical CPU core IDs and count */
 pub type PhysicalCoreID = usize;
@@ -68,6 +69,9 @@ pub struct PhysicalCore
     /* each physical CPU core gets its own heap that it can share, but it must manage its own */
     pub heap: heap::Heap,
 
+    /* small allocations are made from slabs taken from this core's heap */
+    pub slabs: slab::SlabAllocator,
+
     /* each physical CPU gets its own set of queues of virtual CPU cores to schedule */
     queues: ScheduleQueues,
 
@@ -94,6 +98,7 @@ impl PhysicalCore
 
         let (heap_ptr, heap_size) = PhysicalCore::get_heap_config();
         cpu.heap.init(heap_ptr, heap_size);
+        cpu.slabs.init();
       
         cpu.queues = ScheduleQueues::new();
 
@@ -172,13 +177,77 @@ impl PhysicalCore
             None
         }
     }
+
+    /* inspect and update the virtual core running on this physical CPU core. this should be
+    called from an IRQ context: the interrupted virtual core's context is preserved and passed to f,
+    and then loaded back so that any changes take effect when we return to supervisor mode.
+    f must not try to access the running virtual cores list.
+    => f = function to call with the running virtual core
+    <= Some value returned by f, or None if no virtual core is running */
+    pub fn update_running_vcore<F, R>(f: F) -> Option<R> where F: FnOnce(&mut VirtualCore) -> R
+    {
+        match VCORES.lock().get_mut(&PhysicalCore::get_id())
+        {
+            Some(vcore) =>
+            {
+                platform::cpu::save_supervisor_state(vcore.state_as_ref());
+                let result = f(vcore);
+                platform::cpu::load_supervisor_state(vcore.state_as_ref());
+                Some(result)
+            },
+            None => None
+        }
+    }
+}
+
+/* return the ID of the physical CPU core that last ran the given virtual core, or None if it hasn't run */
+pub fn last_ran_on(id: VirtualCoreCanonicalID) -> Option<PhysicalCoreID>
+{
+    PCORES.lock().get(&id).cloned()
+}
+
+/* remove the virtual core running on this physical CPU core, if any, so that it is not
+queued to run again, and destroy it. call this from an IRQ context before switching to
+another virtual core */
+pub fn discard_running_vcore()
+{
+    /* drop the virtual core after releasing the lock: dropping it may release its capsule */
+    let vcore = VCORES.lock().remove(&PhysicalCore::get_id());
+    drop(vcore);
+}
+
+/* forget which physical CPU core last ran the given virtual core. called when the virtual core is dropped */
+pub fn forget_vcore(id: VirtualCoreCanonicalID)
+{
+    PCORES.lock().remove(&id);
+}
+
+/* remove a dying capsule's virtual cores from this physical CPU core's queues and, if it's running
+   one of them, the virtual core running here, and destroy them
+   => cid = ID of capsule being torn down
+   <= true if the running virtual core was removed, in which case this physical CPU core must switch
+      to something else before returning to supervisor mode, or false if not */
+pub fn purge_capsule(cid: CapsuleID) -> bool
+{
+    let purged = PhysicalCore::this().queues.purge(cid);
+    drop(purged);
+
+    match PhysicalCore::get_capsule_id()
+    {
+        Some(running) if running == cid =>
+        {
+            discard_running_vcore();
+            true
+        },
+        _ => false
+    }
 }
 
 /* save current virtual CPU core's context, if we're running one, and load next virtual core's context.
 this should be called from an IRQ context as it preserves the interrupted code's context
 and overwrites the context with the next virtual core's context, so returning to supervisor
 mode will land us in the new context */
-pub fn context_switch(next: VirtualCore)
+pub fn context_switch(mut next: VirtualCore)
 {
     let next_capsule = next.get_capsule_id();
     let id = PhysicalCore::get_id();
@@ -200,7 +269,10 @@ pub fn context_switch(next: VirtualCore)
             to the next capsule to run */
             if current_vcore.get_capsule_id() != next_capsule
             {
-                capsule::enforce(next_capsule);
+                if let Err(e) = capsule::enforce(next_capsule)
+                {
+                    hvalert!("BUG: Could not enforce capsule {} memory access ({:?})", next_capsule, e);
+                }
             }
 
             /* queue the current virtual core on the waiting list */
@@ -214,14 +286,15 @@ pub fn context_switch(next: VirtualCore)
             rather than hypervisor mode */
             platform::cpu::prep_supervisor_return();
             /* and enforce its hardware access permissions */
-            capsule::enforce(next_capsule);
+            if let Err(e) = capsule::enforce(next_capsule)
+            {
+                hvalert!("BUG: Could not enforce capsule {} memory access ({:?})", next_capsule, e);
+            }
         }
     }
 
-    /* prepare next virtual core to run when we leave this IRQ context */
-    platform::cpu::load_supervisor_state(next.state_as_ref());
-
-    /* link virtual core and capsule to this physical CPU */
+    /* link virtual core and capsule to this physical CPU. do this before delivering its pending
+    interrupts so that anything raised for it afterwards is sent here: see vcore::raise() */
     PCORES.lock().insert(VirtualCoreCanonicalID
         {
             vcoreid: next.get_id(),
@@ -229,6 +302,11 @@ pub fn context_switch(next: VirtualCore)
         },
         id);
 
+    /* deliver anything raised for the next virtual core while it wasn't running, and
+    prepare it to run when we leave this IRQ context */
+    next.deliver_pending();
+    platform::cpu::load_supervisor_state(next.state_as_ref());
+
     /*and add the virtual core to the running virtual cores list */
     VCORES.lock().insert(id, next);
 }
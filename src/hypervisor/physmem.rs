@@ -3,6 +3,20 @@
  * Allocate/free regions of memory for supervisors.
  * these regions can be used in 1:1 mappings or used
  * as RAM backing for virtual memory.
+ *
+ * Free memory is tracked by a buddy allocator: blocks of
+ * a power-of-two number of pages, each aligned to its size.
+ * An allocation takes the smallest block that's big and
+ * aligned enough, splitting larger blocks as needed, and
+ * hands back any unused pages at its end. Freed blocks
+ * are merged with their free buddies.
+ *
+ * Freed RAM may hold a capsule's secrets, so it isn't
+ * returned to the allocator until it has been scrubbed.
+ * Idle physical CPU cores scrub freed RAM a piece at a
+ * time. If an allocation can't be met while RAM is
+ * waiting to be scrubbed, the allocating core scrubs
+ * it there and then.
  * 
  * (c) Chris Williams, 2019.
  *
@@ -15,17 +29,31 @@ use alloc::vec::Vec;
 use platform::physmem::{PhysMemBase, PhysMemEnd, PhysMemSize, AccessPermissions, validate_ram};
 use super::error::Cause;
 use super::hardware;
+use super::heap;
 
-/* return the physical RAM region covering the entirely of the boot capsule's supervisor */
-pub fn boot_supervisor() -> Region
-{
-    let (base, end) = platform::physmem::boot_supervisor();
-    Region { base: base, size: end - base }
-}
+/* physical memory is allocated in whole numbers of pages of this many bytes */
+const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE: PhysMemSize = 1 << PAGE_SHIFT;
 
-/* to avoid fragmentation, round up physical memory region allocations into whole numbers of these bytes.
-this only applies when creating regions with alloc_region() */
-const PHYS_RAM_REGION_MIN_SIZE: PhysMemSize = 64 * 1024 * 1024; /* 64MB ought to be enough for anyone */
+/* the largest block the allocator tracks is this many powers of two of pages: 256GB on 64-bit targets.
+on 32-bit targets, this is limited to half the address space so that block sizes fit in a usize */
+#[cfg(target_pointer_width = "64")]
+const MAX_ORDER: usize = 26;
+#[cfg(target_pointer_width = "32")]
+const MAX_ORDER: usize = 32 - PAGE_SHIFT - 1;
+
+/* idle physical CPU cores scrub freed RAM in pieces of up to this many bytes at a time */
+const SCRUB_CHUNK_SIZE: PhysMemSize = 2 * 1024 * 1024;
+
+/* give up trying to lock the allocator to grow a heap after this many attempts */
+const HEAP_LOCK_ATTEMPTS: usize = 100000;
+
+/* freed RAM is zeroed, or filled with a recognizable pattern to help catch use of stale memory */
+const SCRUB_PATTERN: u8 = match cfg!(feature = "scrub_pattern")
+{
+    true => 0xa5,
+    false => 0
+};
 
 /* describe a physical memory region */
 #[derive(Copy, Clone)]
@@ -47,11 +75,19 @@ impl Region
         }
     }
 
-    /* allow the currently running supervisor kernel to access this region of physical memory */
-    pub fn grant_access(&self)
+    /* allow the currently running supervisor kernel to access this region of physical memory
+       => slot = hardware protection slot to use for this region, from zero to max_protected_regions() - 1
+          permissions = what the supervisor kernel may do with the region */
+    pub fn grant_access(&self, slot: usize, permissions: AccessPermissions)
+    {
+        hvdebug!("Granting {:?} access to 0x{:x}, {} bytes", permissions, self.base, self.size);
+        platform::physmem::protect(slot, self.base, self.base + self.size, permissions);
+    }
+
+    /* return true if this region shares any bytes with the other region */
+    pub fn overlaps(&self, other: &Region) -> bool
     {
-        hvdebug!("Granting {:?} access to 0x{:x}, {} bytes", AccessPermissions::ReadWriteExecute, self.base, self.size);
-        platform::physmem::protect(self.base, self.base + self.size, AccessPermissions::ReadWriteExecute);
+        self.base < other.end() && other.base() < self.end()
     }
 
     /* return or change attributes */
@@ -75,107 +111,416 @@ impl Region
     }
 }
 
+/* return the number of physical RAM regions the hardware can protect at any one time */
+pub fn max_protected_regions() -> usize
+{
+    platform::physmem::protection_slots()
+}
+
+/* deny the currently running supervisor kernel access to the physical memory
+   covered by protection slots from first onwards
+   => first = first hardware protection slot to clear */
+pub fn revoke_access_from(first: usize)
+{
+    for slot in first..max_protected_regions()
+    {
+        platform::physmem::unprotect(slot);
+    }
+}
+
 /* gather up all physical RAM areas from which future capsule physical
-RAM allocations will be drawn into the REGIONS list. this list is built from
+RAM allocations will be drawn into the allocator. this is built from
 available physical RAM: it must *not* include any RAM areas already in use by
 the hypervisor, boot supervisor image, peripherals, etc. the underlying
-platform code needs to exclude those off-limits areas.
-
-this list must also be sorted, by base address, lowest first. this is so that
-adjoining regions can be merged into one. this list also contains only free
-and available regions. if a region is in use, it must be removed from the list. */
+platform code needs to exclude those off-limits areas. */
 lazy_static!
 {
-    /* acquire REGIONS lock before accessing any physical RAM regions */
-    static ref REGIONS: Mutex<SortedRegions> = Mutex::new(SortedRegions::new());
+    /* acquire ALLOCATOR lock before allocating or freeing any physical RAM regions */
+    static ref ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new());
+
+    /* freed regions waiting to be scrubbed before they can be returned to the allocator.
+    if ALLOCATOR is needed too, acquire UNSCRUBBED first */
+    static ref UNSCRUBBED: Mutex<Vec<Region>> = Mutex::new(Vec::new());
 }
 
-/* implement a sorted list of regions */
-struct SortedRegions
+/* summarize the physical RAM available to capsules */
+#[derive(Clone, Copy, Debug)]
+pub struct Stats
 {
-    regions: Vec<Region>
+    pub total: PhysMemSize,         /* bytes of RAM managed by the allocator */
+    pub free: PhysMemSize,          /* bytes of RAM that can be allocated */
+    pub unscrubbed: PhysMemSize,    /* bytes of RAM freed but not yet scrubbed, which will become free */
+    pub largest_free: PhysMemSize   /* size of the largest region that can be allocated right now */
 }
 
-impl SortedRegions
+/* bits in each word of a zone's bitmaps */
+const BITMAP_WORD_BITS: usize = 64;
+
+/* a run of physical RAM handed to the allocator. for each order, a bitmap has a bit for every block of that
+order in the zone, set if the block is free. the bitmaps are allocated when the zone is created, so the
+allocator never needs the heap afterwards: the heap may need the allocator to grow while its lock is held */
+struct Zone
 {
-    /* create an empty list */
-    pub fn new() -> SortedRegions
+    base: PhysMemBase,
+    end: PhysMemEnd,
+    free: Vec<Vec<u64>>,                /* bitmap of free blocks of each order */
+    counts: [usize; MAX_ORDER + 1],     /* number of free blocks of each order */
+    first: [usize; MAX_ORDER + 1]       /* lowest word of each bitmap that may have a bit set */
+}
+
+impl Zone
+{
+    /* create a zone of page-aligned RAM with no free blocks
+       => base, end = start and end of the RAM
+       <= zone, or an error code if there isn't enough memory for its bitmaps */
+    pub fn new(base: PhysMemBase, end: PhysMemEnd) -> Result<Zone, Cause>
+    {
+        let mut free = Vec::new();
+        heap::reserved(free.try_reserve_exact(MAX_ORDER + 1))?;
+        for order in 0..(MAX_ORDER + 1)
+        {
+            let shift = PAGE_SHIFT + order;
+            let blocks = ((end - 1) >> shift) - (base >> shift) + 1;
+            let mut bitmap = Vec::new();
+            heap::reserved(bitmap.try_reserve_exact((blocks + BITMAP_WORD_BITS - 1) / BITMAP_WORD_BITS))?;
+            bitmap.resize((blocks + BITMAP_WORD_BITS - 1) / BITMAP_WORD_BITS, 0);
+            free.push(bitmap);
+        }
+
+        Ok(Zone
+        {
+            base: base,
+            end: end,
+            free: free,
+            counts: [0; MAX_ORDER + 1],
+            first: [0; MAX_ORDER + 1]
+        })
+    }
+
+    /* return true if the block of the given order starting at base lies within the zone */
+    fn contains(&self, base: PhysMemBase, order: usize) -> bool
+    {
+        base >= self.base && base + BuddyAllocator::block_size(order) <= self.end
+    }
+
+    /* return the position in its order's bitmap of the block starting at base */
+    fn index(&self, base: PhysMemBase, order: usize) -> usize
     {
-        SortedRegions
+        (base >> (PAGE_SHIFT + order)) - (self.base >> (PAGE_SHIFT + order))
+    }
+
+    /* return true if the block of the given order starting at base is free */
+    fn is_free(&self, base: PhysMemBase, order: usize) -> bool
+    {
+        let index = self.index(base, order);
+        self.free[order][index / BITMAP_WORD_BITS] & (1 << (index % BITMAP_WORD_BITS)) != 0
+    }
+
+    /* mark the block of the given order starting at base as free or not */
+    fn set_free(&mut self, base: PhysMemBase, order: usize, free: bool)
+    {
+        let index = self.index(base, order);
+        let (word, bit) = (index / BITMAP_WORD_BITS, 1 << (index % BITMAP_WORD_BITS));
+        match free
         {
-            regions: Vec::new()
+            true =>
+            {
+                self.free[order][word] = self.free[order][word] | bit;
+                self.counts[order] = self.counts[order] + 1;
+                if word < self.first[order]
+                {
+                    self.first[order] = word;
+                }
+            },
+            false =>
+            {
+                self.free[order][word] = self.free[order][word] & !bit;
+                self.counts[order] = self.counts[order] - 1;
+            }
         }
     }
 
-    /* find a region that has a size equal to or greater than the required size.
-       if one is found, remove the region and return it. if one can't be found,
-       return None. */
-    pub fn find(&mut self, required_size: PhysMemSize) -> Result<Region, Cause>
+    /* return the base address of the lowest free block of the given order, or None if there isn't one */
+    fn lowest_free(&mut self, order: usize) -> Option<PhysMemBase>
     {
-        for index in 0..self.regions.len()
+        let shift = PAGE_SHIFT + order;
+        for word in self.first[order]..self.free[order].len()
         {
-            if self.regions[index].size() >= required_size
+            let bits = self.free[order][word];
+            if bits != 0
             {
-                /* remove from the list and return */
-                return Ok(self.regions.remove(index));
+                self.first[order] = word;
+                let index = (word * BITMAP_WORD_BITS) + bits.trailing_zeros() as usize;
+                return Some((index + (self.base >> shift)) << shift);
             }
         }
 
-        Err(Cause::PhysRegionNoMatch) /* can't find a region large enough */
+        self.first[order] = self.free[order].len();
+        None
     }
 
-    /* insert a region into the list, sorted by base addresses, lowest first */
-    pub fn insert(&mut self, to_insert: Region) -> Result<(), Cause>
+    /* free a page-aligned range of memory in the zone by breaking it into the largest aligned blocks that fit */
+    fn free_range(&mut self, mut base: PhysMemBase, end: PhysMemEnd)
     {
-        /* ignore zero-size inserts */
-        if to_insert.size() == 0
+        while base < end
         {
-            return Ok(())
+            let order = BuddyAllocator::fit(base, end - base);
+            self.free_block(base, order);
+            base = base + BuddyAllocator::block_size(order);
         }
+    }
 
-        for index in 0..self.regions.len()
+    /* mark a block as free, merging it with its buddy, and then their combined block with its buddy, and so on */
+    fn free_block(&mut self, mut base: PhysMemBase, mut order: usize)
+    {
+        while order < MAX_ORDER
         {
-            if to_insert.end() <= self.regions[index].base()
+            let buddy = base ^ BuddyAllocator::block_size(order);
+            if self.contains(buddy, order) == false || self.is_free(buddy, order) == false
             {
-                self.regions.insert(index, to_insert);
-                return Ok(())
+                break;
             }
 
-            /* check to make sure we're not adding a region that will collide with another */
-            if to_insert.base() >= self.regions[index].base() && to_insert.base() < self.regions[index].end()
+            self.set_free(buddy, order, false);
+            if buddy < base
             {
-                return Err(Cause::PhysRegionCollision);
+                base = buddy;
             }
+            order = order + 1;
         }
 
-        /* insert at the end: region greater than all others */
-        self.regions.push(to_insert);
-        Ok(())
+        self.set_free(base, order, true);
     }
 
-    /* merge all adjoining free regions. this requires the list to be sorted by base address ascending */
-    pub fn merge(&mut self)
+    /* return true if any part of the region is free in this zone */
+    fn overlaps_free(&self, region: Region) -> bool
     {
-        let mut cursor = 0;
-        loop
+        let base = region.base().max(self.base);
+        let end = region.end().min(self.end);
+        if base >= end
         {
-            /* prevent search from going out of bounds */
-            if (cursor + 1) >= self.regions.len()
+            return false;
+        }
+
+        for order in 0..(MAX_ORDER + 1)
+        {
+            /* check the bits of the blocks of this order that the region touches, a word at a time */
+            let mut index = self.index(base, order);
+            let last = self.index(end - 1, order);
+            while index <= last
+            {
+                let offset = index % BITMAP_WORD_BITS;
+                let span = (BITMAP_WORD_BITS - offset).min(last - index + 1);
+                let mask = match span
+                {
+                    BITMAP_WORD_BITS => !0,
+                    _ => ((1 << span) - 1) << offset
+                };
+
+                if self.free[order][index / BITMAP_WORD_BITS] & mask != 0
+                {
+                    return true;
+                }
+                index = index + span;
+            }
+        }
+        false
+    }
+}
+
+/* keep track of free blocks of each order in each zone of RAM, where a block of order n is PAGE_SIZE << n bytes */
+struct BuddyAllocator
+{
+    zones: Vec<Zone>,
+    total: PhysMemSize,
+    free_bytes: PhysMemSize
+}
+
+impl BuddyAllocator
+{
+    /* create an allocator with no RAM to hand out */
+    pub fn new() -> BuddyAllocator
+    {
+        BuddyAllocator
+        {
+            zones: Vec::new(),
+            total: 0,
+            free_bytes: 0
+        }
+    }
+
+    /* return the number of bytes in a block of the given order */
+    fn block_size(order: usize) -> PhysMemSize
+    {
+        PAGE_SIZE << order
+    }
+
+    /* return the order of the largest block that starts at base, is aligned to its size, and fits in size bytes */
+    fn fit(base: PhysMemBase, size: PhysMemSize) -> usize
+    {
+        let mut order = 0;
+        while order < MAX_ORDER
+        {
+            let next = BuddyAllocator::block_size(order + 1);
+            if base % next != 0 || next > size
             {
                 break;
             }
+            order = order + 1;
+        }
+        order
+    }
+
+    /* hand RAM to the allocator, trimming it to whole pages. this allocates the bitmaps for the RAM,
+       so don't call this with the allocator's lock held
+       => region = RAM to add, which must not be in use or already added
+       <= Ok for success, or an error code */
+    pub fn add(&mut self, region: Region) -> Result<(), Cause>
+    {
+        let base = (region.base() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = region.end() & !(PAGE_SIZE - 1);
+        if end <= base
+        {
+            return Ok(());
+        }
+
+        if self.overlaps_free(Region::new(base, end - base)) == true
+        {
+            return Err(Cause::PhysRegionCollision);
+        }
+
+        let zone = Zone::new(base, end)?;
+        heap::reserved(self.zones.try_reserve(1))?;
+        self.zones.push(zone);
+
+        self.release(Region::new(base, end - base))?;
+        self.total = self.total + (end - base);
+        Ok(())
+    }
+
+    /* allocate a region of RAM
+       => size = number of bytes required, rounded up to a whole number of pages
+          align = required alignment of the region's base address, a power of two
+       <= allocated region, or an error code */
+    pub fn allocate(&mut self, size: PhysMemSize, align: PhysMemSize) -> Result<Region, Cause>
+    {
+        if size == 0 || align.is_power_of_two() == false
+        {
+            return Err(Cause::PhysRegionBadAlignment);
+        }
+
+        let size = match size.checked_add(PAGE_SIZE - 1)
+        {
+            Some(s) => s & !(PAGE_SIZE - 1),
+            None => return Err(Cause::PhysNotEnoughFreeRAM)
+        };
+
+        /* blocks are aligned to their size, so pick one big enough to satisfy both */
+        let mut order = 0;
+        while BuddyAllocator::block_size(order) < size || BuddyAllocator::block_size(order) < align
+        {
+            order = order + 1;
+            if order > MAX_ORDER
+            {
+                return Err(Cause::PhysNotEnoughFreeRAM);
+            }
+        }
+
+        let (zone, base) = match self.take_block(order)
+        {
+            Some(b) => b,
+            None => return Err(Cause::PhysNotEnoughFreeRAM)
+        };
+
+        /* give back the pages at the end of the block that weren't asked for */
+        self.zones[zone].free_range(base + size, base + BuddyAllocator::block_size(order));
+        self.free_bytes = self.free_bytes - size;
+        Ok(Region::new(base, size))
+    }
 
-            if self.regions[cursor].end() == self.regions[cursor + 1].base()
+    /* return a previously allocated region of RAM to the allocator
+       => region = RAM to free, which must be a whole number of pages
+       <= Ok for success, or an error code if the region is misaligned, wasn't handed to the
+          allocator, or any of it is already free */
+    pub fn release(&mut self, region: Region) -> Result<(), Cause>
+    {
+        if region.base() % PAGE_SIZE != 0 || region.size() % PAGE_SIZE != 0
+        {
+            return Err(Cause::PhysRegionBadAlignment);
+        }
+
+        if self.overlaps_free(region) == true
+        {
+            return Err(Cause::PhysRegionCollision);
+        }
+
+        match self.zones.iter_mut().find(|z| region.base() >= z.base && region.end() <= z.end)
+        {
+            Some(zone) => zone.free_range(region.base(), region.end()),
+            None => return Err(Cause::PhysRegionNotManaged)
+        }
+        self.free_bytes = self.free_bytes + region.size();
+        Ok(())
+    }
+
+    /* <= statistics describing the RAM managed by this allocator */
+    pub fn stats(&self) -> Stats
+    {
+        let mut largest_free = 0;
+        for order in (0..(MAX_ORDER + 1)).rev()
+        {
+            if self.zones.iter().any(|z| z.counts[order] > 0) == true
             {
-                /* absorb the next region's size into this region */
-                self.regions[cursor].size = self.regions[cursor].size() + self.regions.remove(cursor + 1).size();
+                largest_free = BuddyAllocator::block_size(order);
+                break;
             }
-            else
+        }
+
+        Stats
+        {
+            total: self.total,
+            free: self.free_bytes,
+            unscrubbed: 0,
+            largest_free: largest_free
+        }
+    }
+
+    /* take the lowest free block of the smallest order, of at least the given order, from the zones,
+       splitting a larger block if necessary, and return the index of its zone and the base address of a block of the given order */
+    fn take_block(&mut self, order: usize) -> Option<(usize, PhysMemBase)>
+    {
+        for mut found in order..(MAX_ORDER + 1)
+        {
+            for (index, zone) in self.zones.iter_mut().enumerate()
             {
-                /* move onto next region */
-                cursor = cursor + 1;
+                if zone.counts[found] == 0
+                {
+                    continue;
+                }
+
+                let base = match zone.lowest_free(found)
+                {
+                    Some(b) => b,
+                    None => continue
+                };
+                zone.set_free(base, found, false);
+
+                /* keep the lower half of each split and free the upper half */
+                while found > order
+                {
+                    found = found - 1;
+                    zone.set_free(base + BuddyAllocator::block_size(found), found, true);
+                }
+                return Some((index, base));
             }
         }
+        None
+    }
+
+    /* return true if any part of the region is already free */
+    fn overlaps_free(&self, region: Region) -> bool
+    {
+        self.zones.iter().any(|z| z.overlaps_free(region))
     }
 }
 
@@ -196,69 +541,194 @@ pub fn init() -> Result<(), Cause>
         None => return Err(Cause::PhysNoRAMFound)
     };
 
-    /* iterate over the physical memory chunks... */
-    let mut regions = REGIONS.lock();
+    /* iterate over the physical memory chunks, building the allocator away from its lock as it needs the heap... */
+    let mut allocator = BuddyAllocator::new();
     for chunk in chunks
     {
         /* ...and let validate_ram break each chunk in sections we can safely use */
         for section in validate_ram(nr_cpu_cores, chunk)
         {
             hvdebug!("Enabling RAM region 0x{:x}, size {} MB", section.base, section.size / 1024 / 1024);
-            regions.insert(Region::new(section.base, section.size));
+            allocator.add(Region::new(section.base, section.size))?;
         }
     }
 
+    /* the allocator this replaces is empty, so nothing is freed while the lock is held */
+    *ALLOCATOR.lock() = allocator;
     Ok(())
 }
 
 /* perform housekeeping duties on idle physical CPU cores */
 macro_rules! physmemhousekeeper
 {
-    () => ($crate::physmem::coalesce_regions());
+    () => ($crate::physmem::scrub_unscrubbed(true));
 }
 
-pub fn coalesce_regions()
+/* allocate a region of available physical memory for capsule use
+   => size = number of bytes in region, rounded up to a whole number of pages
+   <= Region structure for the space, or an error code */
+pub fn alloc_region(size: PhysMemSize) -> Result<Region, Cause>
 {
-    REGIONS.lock().merge();
+    alloc_region_aligned(size, PAGE_SIZE)
 }
 
-/* allocate a region of available physical memory for capsule use
-   => size = number of bytes in region, rounded up to next multiple of PHYS_RAM_REGION_MIN_SIZE
+/* allocate a region of available physical memory with its base address aligned
+   => size = number of bytes in region, rounded up to a whole number of pages
+      align = required alignment in bytes, a power of two. regions are always at least page aligned
    <= Region structure for the space, or an error code */
-pub fn alloc_region(size: PhysMemSize) -> Result<Region, Cause>
+pub fn alloc_region_aligned(size: PhysMemSize, align: PhysMemSize) -> Result<Region, Cause>
 {
-    /* round up to a multiple of the minimum size of a region to avoid fragmentation */
-    let adjusted_size = match size % PHYS_RAM_REGION_MIN_SIZE
+    loop
     {
-        0 => size,
-        r => (size - r) + PHYS_RAM_REGION_MIN_SIZE
-    };
+        match ALLOCATOR.lock().allocate(size, align)
+        {
+            Err(Cause::PhysNotEnoughFreeRAM) => (),
+            result => return result
+        }
+
+        /* rather than fail, scrub freed RAM now and try again, giving up once there's none left */
+        if scrub_unscrubbed(false) == false
+        {
+            return Err(Cause::PhysNotEnoughFreeRAM);
+        }
+    }
+}
 
-    let mut regions = REGIONS.lock();
-    match regions.find(adjusted_size) // find will remove found region if successful 
+/* allocate a region of available physical memory to grow a physical CPU core's heap. the heap
+   asks for this when it runs out of memory, which can happen while its core holds the allocator's lock,
+   so rather than wait forever for the lock, give up if it can't be taken. RAM waiting to be scrubbed
+   is left for the housekeeper for the same reason
+   => size = number of bytes in region, rounded up to a whole number of pages
+   <= Region structure for the space, or an error code */
+pub fn alloc_region_for_heap(size: PhysMemSize) -> Result<Region, Cause>
+{
+    for _ in 0..HEAP_LOCK_ATTEMPTS
     {
-        Ok(found) => 
+        if let Some(mut allocator) = ALLOCATOR.try_lock()
         {
-            /* split the found region into two parts: the lower portion for the newly
-            allocated region, and the remaining upper portion which is returned to the free list */
-            match found.split(adjusted_size)
-            {
-                Ok((lower, upper)) =>
-                {
-                    regions.insert(upper);
-                    Ok(lower)
-                },
-                Err(e) => Err(e)
-            }
-        },
-        Err(e) => Err(Cause::PhysNotEnoughFreeRAM)
+            return allocator.allocate(size, PAGE_SIZE);
+        }
     }
+
+    Err(Cause::PhysAllocatorBusy)
 }
 
-/* deallocate a region so that its physical RAM can be reallocated
+/* deallocate a region so that its physical RAM can be reallocated once it has been scrubbed
    => to_free = region to deallocate
    <= Ok for success, or an error code for failure */
-pub fn dealloc_region(mut to_free: Region) -> Result<(), Cause>
+pub fn dealloc_region(to_free: Region) -> Result<(), Cause>
 {
-    REGIONS.lock().insert(to_free)
+    if to_free.base() % PAGE_SIZE != 0 || to_free.size() % PAGE_SIZE != 0
+    {
+        return Err(Cause::PhysRegionBadAlignment);
+    }
+
+    if to_free.size() == 0
+    {
+        return Ok(());
+    }
+
+    /* refuse to free RAM that's already free */
+    let mut unscrubbed = UNSCRUBBED.lock();
+    if ALLOCATOR.lock().overlaps_free(to_free) == true || unscrubbed.iter().any(|r| r.overlaps(&to_free))
+    {
+        return Err(Cause::PhysRegionCollision);
+    }
+
+    heap::reserved(unscrubbed.try_reserve(1))?;
+    unscrubbed.push(to_free);
+    Ok(())
+}
+
+/* scrub a freed region, or a piece of one, and return it to the allocator
+   => piecemeal = true to scrub no more than SCRUB_CHUNK_SIZE bytes, or false to scrub a whole region
+   <= true if any RAM was scrubbed, or false if there was none waiting */
+pub fn scrub_unscrubbed(piecemeal: bool) -> bool
+{
+    /* take the RAM to scrub off the list so other cores can scrub other RAM at the same time */
+    let region = match UNSCRUBBED.lock().pop()
+    {
+        Some(r) => r,
+        None => return false
+    };
+
+    let (to_scrub, remainder) = match piecemeal && region.size() > SCRUB_CHUNK_SIZE
+    {
+        true => match region.split(SCRUB_CHUNK_SIZE)
+        {
+            Ok((lower, upper)) => (lower, Some(upper)),
+            Err(_) => (region, None)
+        },
+        false => (region, None)
+    };
+
+    if let Some(r) = remainder
+    {
+        UNSCRUBBED.lock().push(r);
+    }
+
+    unsafe { core::ptr::write_bytes(to_scrub.base() as *mut u8, SCRUB_PATTERN, to_scrub.size()); }
+
+    if let Err(e) = ALLOCATOR.lock().release(to_scrub)
+    {
+        hvalert!("BUG: Could not return scrubbed RAM at 0x{:x}, {} bytes to the allocator ({:?})",
+                 to_scrub.base(), to_scrub.size(), e);
+    }
+    true
+}
+
+/* <= statistics describing the physical RAM available to capsules. both locks are held
+   so that RAM moving from the unscrubbed list to the allocator isn't counted twice or missed */
+pub fn stats() -> Stats
+{
+    let unscrubbed = UNSCRUBBED.lock();
+    let allocator = ALLOCATOR.lock();
+    let mut stats = allocator.stats();
+    stats.unscrubbed = unscrubbed.iter().fold(0, |total, r| total + r.size());
+    stats
+}
+
+#[test_case]
+fn test_buddy_allocator()
+{
+    /* the allocator never touches the memory it manages, so any addresses will do */
+    let mut allocator = BuddyAllocator::new();
+    allocator.add(Region::new(0x80000000, 16 * 1024 * 1024)).unwrap();
+    assert_eq!(allocator.stats().free, 16 * 1024 * 1024);
+    assert_eq!(allocator.stats().largest_free, 16 * 1024 * 1024);
+
+    /* allocations are rounded up to whole pages, not powers of two */
+    let small = allocator.allocate(1, PAGE_SIZE).unwrap();
+    let odd = allocator.allocate(3 * PAGE_SIZE, PAGE_SIZE).unwrap();
+    assert_eq!(small.size(), PAGE_SIZE);
+    assert_eq!(odd.size(), 3 * PAGE_SIZE);
+    assert_eq!(allocator.stats().free, (16 * 1024 * 1024) - (4 * PAGE_SIZE));
+
+    let aligned = allocator.allocate(PAGE_SIZE, 2 * 1024 * 1024).unwrap();
+    assert_eq!(aligned.base() % (2 * 1024 * 1024), 0);
+
+    /* freeing memory twice is refused */
+    allocator.release(small).unwrap();
+    assert_eq!(allocator.release(small).is_err(), true);
+
+    /* freed blocks merge back together */
+    allocator.release(odd).unwrap();
+    allocator.release(aligned).unwrap();
+    assert_eq!(allocator.stats().free, 16 * 1024 * 1024);
+    assert_eq!(allocator.stats().largest_free, 16 * 1024 * 1024);
+    assert_eq!(allocator.allocate(32 * 1024 * 1024, PAGE_SIZE).is_err(), true);
+
+    /* RAM that wasn't handed to the allocator can't be freed into it */
+    assert_eq!(allocator.release(Region::new(0x40000000, PAGE_SIZE)).is_err(), true);
+
+    /* separate runs of RAM are managed separately, and blocks don't merge across them */
+    allocator.add(Region::new(0x81000000, PAGE_SIZE * 3)).unwrap();
+    assert_eq!(allocator.stats().free, (16 * 1024 * 1024) + (3 * PAGE_SIZE));
+    let whole = allocator.allocate(16 * 1024 * 1024, PAGE_SIZE).unwrap();
+    let pair = allocator.allocate(2 * PAGE_SIZE, PAGE_SIZE).unwrap();
+    assert_eq!(pair.base(), 0x81000000);
+    assert_eq!(allocator.allocate(2 * PAGE_SIZE, PAGE_SIZE).is_err(), true);
+    allocator.release(pair).unwrap();
+    allocator.release(whole).unwrap();
+    assert_eq!(allocator.stats().largest_free, 16 * 1024 * 1024);
 }
@@ -0,0 +1,459 @@
+/* diosix Supervisor Binary Interface (SBI) for capsules
+ *
+ * Supervisor kernels running in capsules call down into the
+ * hypervisor using environment calls, following the RISC-V SBI
+ * specification. Arguments are passed in a0 to a5, the function ID
+ * in a6 and the extension ID in a7. The hypervisor returns an error
+ * code in a0 and a value in a1, or a single value in a0 for legacy calls.
+ *
+ * (c) Chris Williams, 2019-2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use platform::irq::SupervisorIRQ;
+use super::pcore::{self, PhysicalCore};
+use super::vcore::{self, VirtualCore, VirtualCoreID, VirtualCoreCanonicalID, Priority, Pending};
+use super::capsule::{self, CapsuleID};
+use super::scheduler;
+use super::hypercall;
+use super::console;
+use super::error::Cause;
+
+/* general purpose register numbers used to pass parameters */
+const REG_A0: usize = 10;
+const REG_A1: usize = 11;
+const REG_A6: usize = 16;
+const REG_A7: usize = 17;
+
+/* number of parameter registers, a0 to a5 */
+const ARGS_MAX: usize = 6;
+
+/* size of the ecall instruction to skip over when returning to the supervisor */
+const ECALL_SIZE: usize = 4;
+
+/* describe this SBI implementation. we conform to version 0.3 of the spec */
+const SBI_SPEC_VERSION: usize = (0 << 24) | 3;
+const SBI_IMPL_ID: usize = 5; /* ID assigned to diosix by the SBI spec */
+const SBI_IMPL_VERSION: usize = (2 << 16) | 0; /* hypervisor version 2.0 */
+
+/* legacy extensions, which are their own function */
+const EXT_LEGACY_SET_TIMER: usize = 0x00;
+const EXT_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+const EXT_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+const EXT_LEGACY_CLEAR_IPI: usize = 0x03;
+const EXT_LEGACY_SEND_IPI: usize = 0x04;
+const EXT_LEGACY_REMOTE_FENCE_I: usize = 0x05;
+const EXT_LEGACY_REMOTE_SFENCE_VMA: usize = 0x06;
+const EXT_LEGACY_REMOTE_SFENCE_VMA_ASID: usize = 0x07;
+const EXT_LEGACY_SHUTDOWN: usize = 0x08;
+
+/* extensions and their function IDs */
+const EXT_BASE: usize = 0x10;
+const FN_BASE_GET_SPEC_VERSION: usize = 0;
+const FN_BASE_GET_IMPL_ID: usize = 1;
+const FN_BASE_GET_IMPL_VERSION: usize = 2;
+const FN_BASE_PROBE_EXTENSION: usize = 3;
+const FN_BASE_GET_MVENDORID: usize = 4;
+const FN_BASE_GET_MARCHID: usize = 5;
+const FN_BASE_GET_MIMPID: usize = 6;
+
+const EXT_TIMER: usize = 0x54494D45;
+const FN_TIMER_SET_TIMER: usize = 0;
+
+const EXT_IPI: usize = 0x735049;
+const FN_IPI_SEND_IPI: usize = 0;
+
+const EXT_RFENCE: usize = 0x52464E43;
+const FN_RFENCE_REMOTE_FENCE_I: usize = 0;
+const FN_RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+const FN_RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+const EXT_HSM: usize = 0x48534D;
+const FN_HSM_HART_START: usize = 0;
+const FN_HSM_HART_STOP: usize = 1;
+const FN_HSM_HART_GET_STATUS: usize = 2;
+
+const EXT_SRST: usize = 0x53525354;
+const FN_SRST_SYSTEM_RESET: usize = 0;
+const SRST_TYPE_SHUTDOWN: usize = 0;
+const SRST_TYPE_COLD_REBOOT: usize = 1;
+const SRST_TYPE_WARM_REBOOT: usize = 2;
+
+/* states of virtual cores as reported by the HSM extension */
+const HSM_STATE_STARTED: usize = 0;
+const HSM_STATE_STOPPED: usize = 1;
+
+/* a hart mask base of all ones means every virtual core in the capsule */
+const HART_MASK_ALL: usize = !0;
+
+/* SBI error codes returned in a0 */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SBIError
+{
+    Success = 0,
+    Failed = -1,
+    NotSupported = -2,
+    InvalidParam = -3,
+    Denied = -4,
+    InvalidAddress = -5,
+    AlreadyAvailable = -6,
+    AlreadyStarted = -7,
+    AlreadyStopped = -8
+}
+
+/* what to do with the calling virtual core once its call has been handled */
+pub enum Outcome
+{
+    Return(SBIError, usize),    /* return an error code in a0 and value in a1 */
+    Legacy(usize),              /* return a single value in a0, for legacy calls */
+    StopVirtualCore,            /* caller's virtual core has stopped itself */
+    Shutdown,                   /* caller's capsule has asked to be shut down */
+    Reboot                      /* caller's capsule has asked to be restarted */
+}
+
+/* describe an environment call made by a virtual core */
+pub struct Call
+{
+    pub extension: usize,
+    pub function: usize,
+    pub args: [usize; ARGS_MAX],
+    pub capsule: CapsuleID,
+    pub vcore: VirtualCoreID,
+    pub priority: Priority
+}
+
+impl Call
+{
+    /* extract an environment call's parameters from the given virtual core */
+    pub fn from(vcore: &VirtualCore) -> Call
+    {
+        let mut args = [0; ARGS_MAX];
+        for index in 0..ARGS_MAX
+        {
+            args[index] = vcore.get_register(REG_A0 + index);
+        }
+
+        Call
+        {
+            extension: vcore.get_register(REG_A7),
+            function: vcore.get_register(REG_A6),
+            args: args,
+            capsule: vcore.get_capsule_id(),
+            vcore: vcore.get_id(),
+            priority: vcore.get_priority()
+        }
+    }
+}
+
+/* handle an environment call from the supervisor running on this physical CPU core.
+   the results are written back to the virtual core's context, which is
+   loaded when we return from this IRQ context */
+pub fn handle()
+{
+    let call = match PhysicalCore::update_running_vcore(|vcore| Call::from(vcore))
+    {
+        Some(c) => c,
+        None =>
+        {
+            hvalert!("BUG: Environment call from supervisor mode but no virtual core found");
+            return;
+        }
+    };
+
+    match dispatch(&call)
+    {
+        Outcome::Return(error, value) => complete(error as isize as usize, Some(value)),
+        Outcome::Legacy(value) => complete(value, None),
+        Outcome::StopVirtualCore =>
+        {
+            hvdebug!("Stopping vcore {} in capsule {}", call.vcore, call.capsule);
+            pcore::discard_running_vcore(); /* this also removes it from its capsule */
+            scheduler::run_next(true);
+        },
+        Outcome::Shutdown =>
+        {
+            hvdebug!("Capsule {} requested shutdown", call.capsule);
+            pcore::discard_running_vcore();
+            if capsule::destroy(call.capsule).is_err()
+            {
+                hvalert!("BUG: Could not shut down capsule ID {}", call.capsule);
+            }
+            scheduler::run_next(true);
+        },
+        Outcome::Reboot =>
+        {
+            hvdebug!("Capsule {} requested reboot", call.capsule);
+            pcore::discard_running_vcore();
+            if capsule::reboot(call.capsule).is_err()
+            {
+                hvalert!("BUG: Could not reboot capsule ID {}", call.capsule);
+            }
+            scheduler::run_next(true);
+        }
+    }
+}
+
+/* write the results of a call back to the virtual core running on this physical CPU core,
+   and skip it past the ecall instruction
+   => a0 = value to return in a0
+      a1 = value to return in a1, or None to leave a1 untouched */
+fn complete(a0: usize, a1: Option<usize>)
+{
+    PhysicalCore::update_running_vcore(|vcore|
+    {
+        vcore.set_register(REG_A0, a0);
+        if let Some(value) = a1
+        {
+            vcore.set_register(REG_A1, value);
+        }
+
+        let pc = vcore.get_pc();
+        vcore.set_pc(pc + ECALL_SIZE);
+    });
+}
+
+/* decide what to do with the given call
+   <= outcome of the call */
+fn dispatch(call: &Call) -> Outcome
+{
+    match call.extension
+    {
+        EXT_LEGACY_SET_TIMER => Outcome::Legacy(set_timer(call, timer_value(call)) as isize as usize),
+        EXT_LEGACY_CONSOLE_PUTCHAR =>
+        {
+            console::putchar(call.capsule, call.args[0] as u8);
+            Outcome::Legacy(0)
+        },
+        EXT_LEGACY_CONSOLE_GETCHAR => match console::getchar(call.capsule)
+        {
+            Some(c) => Outcome::Legacy(c as usize),
+            None => Outcome::Legacy(!0) /* -1 means nothing to read */
+        },
+        EXT_LEGACY_CLEAR_IPI =>
+        {
+            PhysicalCore::update_running_vcore(|vcore| vcore.clear_irq(SupervisorIRQ::Software));
+            Outcome::Legacy(0)
+        },
+        EXT_LEGACY_SEND_IPI | EXT_LEGACY_REMOTE_FENCE_I |
+        EXT_LEGACY_REMOTE_SFENCE_VMA | EXT_LEGACY_REMOTE_SFENCE_VMA_ASID =>
+        {
+            /* legacy calls pass a pointer to the hart mask in the capsule's memory */
+            let mask = match read_legacy_hart_mask(call.capsule, call.args[0])
+            {
+                Some(m) => m,
+                None => return Outcome::Legacy(SBIError::InvalidAddress as isize as usize)
+            };
+
+            let pending = match call.extension
+            {
+                EXT_LEGACY_SEND_IPI => Pending::SoftwareIRQ,
+                EXT_LEGACY_REMOTE_FENCE_I => Pending::FenceInstructions,
+                _ => Pending::FenceTLB
+            };
+
+            Outcome::Legacy(raise(call, mask, 0, pending) as isize as usize)
+        },
+        EXT_LEGACY_SHUTDOWN => Outcome::Shutdown,
+
+        EXT_BASE => base(call),
+        EXT_TIMER => match call.function
+        {
+            FN_TIMER_SET_TIMER => Outcome::Return(set_timer(call, timer_value(call)), 0),
+            _ => Outcome::Return(SBIError::NotSupported, 0)
+        },
+        EXT_IPI => match call.function
+        {
+            FN_IPI_SEND_IPI => Outcome::Return(raise(call, call.args[0], call.args[1], Pending::SoftwareIRQ), 0),
+            _ => Outcome::Return(SBIError::NotSupported, 0)
+        },
+        EXT_RFENCE => match call.function
+        {
+            FN_RFENCE_REMOTE_FENCE_I =>
+                Outcome::Return(raise(call, call.args[0], call.args[1], Pending::FenceInstructions), 0),
+            FN_RFENCE_REMOTE_SFENCE_VMA | FN_RFENCE_REMOTE_SFENCE_VMA_ASID =>
+                Outcome::Return(raise(call, call.args[0], call.args[1], Pending::FenceTLB), 0),
+
+            /* there's no support for nested virtualization */
+            _ => Outcome::Return(SBIError::NotSupported, 0)
+        },
+        EXT_HSM => hsm(call),
+        hypercall::EXT_DIOSIX => hypercall::dispatch(call),
+        EXT_SRST => match call.function
+        {
+            /* the capsule is the system as far as its supervisor is concerned */
+            FN_SRST_SYSTEM_RESET => match call.args[0]
+            {
+                SRST_TYPE_SHUTDOWN => Outcome::Shutdown,
+                SRST_TYPE_COLD_REBOOT | SRST_TYPE_WARM_REBOOT => Outcome::Reboot,
+                _ => Outcome::Return(SBIError::InvalidParam, 0)
+            },
+            _ => Outcome::Return(SBIError::NotSupported, 0)
+        },
+
+        _ =>
+        {
+            hvdebug!("Unsupported SBI call 0x{:x} function 0x{:x} from capsule {}",
+                     call.extension, call.function, call.capsule);
+            Outcome::Return(SBIError::NotSupported, 0)
+        }
+    }
+}
+
+/* return true if the given extension ID is implemented */
+fn probe(extension: usize) -> bool
+{
+    match extension
+    {
+        EXT_LEGACY_SET_TIMER | EXT_LEGACY_CONSOLE_PUTCHAR | EXT_LEGACY_CONSOLE_GETCHAR |
+        EXT_LEGACY_CLEAR_IPI | EXT_LEGACY_SEND_IPI |
+        EXT_LEGACY_REMOTE_FENCE_I | EXT_LEGACY_REMOTE_SFENCE_VMA |
+        EXT_LEGACY_REMOTE_SFENCE_VMA_ASID | EXT_LEGACY_SHUTDOWN => true,
+        EXT_BASE | EXT_TIMER | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST => true,
+        hypercall::EXT_DIOSIX => true,
+        _ => false
+    }
+}
+
+/* handle the base extension, which describes this SBI implementation */
+fn base(call: &Call) -> Outcome
+{
+    match call.function
+    {
+        FN_BASE_GET_SPEC_VERSION => Outcome::Return(SBIError::Success, SBI_SPEC_VERSION),
+        FN_BASE_GET_IMPL_ID => Outcome::Return(SBIError::Success, SBI_IMPL_ID),
+        FN_BASE_GET_IMPL_VERSION => Outcome::Return(SBIError::Success, SBI_IMPL_VERSION),
+        FN_BASE_PROBE_EXTENSION => Outcome::Return(SBIError::Success, if probe(call.args[0]) { 1 } else { 0 }),
+
+        /* don't leak details of the host's hardware to capsules */
+        FN_BASE_GET_MVENDORID | FN_BASE_GET_MARCHID | FN_BASE_GET_MIMPID => Outcome::Return(SBIError::Success, 0),
+        _ => Outcome::Return(SBIError::NotSupported, 0)
+    }
+}
+
+/* handle the hart state management extension, which starts and stops virtual cores */
+fn hsm(call: &Call) -> Outcome
+{
+    match call.function
+    {
+        FN_HSM_HART_START =>
+        {
+            let (vid, start, opaque) = (call.args[0], call.args[1], call.args[2]);
+
+            if vid >= capsule::get_max_vcores(call.capsule).unwrap_or(0)
+            {
+                return Outcome::Return(SBIError::InvalidParam, 0);
+            }
+
+            if capsule::translate(call.capsule, start).is_none()
+            {
+                return Outcome::Return(SBIError::InvalidAddress, 0);
+            }
+
+            /* the new virtual core receives the opaque value in a1 rather than the device tree.
+            the core is only started if it isn't already, which is checked as it's added to the capsule */
+            match capsule::create_and_add_vcore(call.capsule, vid, start, opaque, call.priority)
+            {
+                Ok(()) => Outcome::Return(SBIError::Success, 0),
+                Err(Cause::CapsuleVirtualCoreExists) => Outcome::Return(SBIError::AlreadyAvailable, 0),
+                Err(_) => Outcome::Return(SBIError::Failed, 0)
+            }
+        },
+        FN_HSM_HART_STOP => Outcome::StopVirtualCore,
+        FN_HSM_HART_GET_STATUS =>
+        {
+            let vid = call.args[0];
+            if vid >= capsule::get_max_vcores(call.capsule).unwrap_or(0)
+            {
+                return Outcome::Return(SBIError::InvalidParam, 0);
+            }
+
+            match capsule::has_vcore(call.capsule, vid)
+            {
+                Some(true) => Outcome::Return(SBIError::Success, HSM_STATE_STARTED),
+                _ => Outcome::Return(SBIError::Success, HSM_STATE_STOPPED)
+            }
+        },
+        _ => Outcome::Return(SBIError::NotSupported, 0)
+    }
+}
+
+/* set the calling virtual core's timer, clearing any pending timer interrupt
+   => call = call making the request
+      target = timer value at which to interrupt the virtual core
+   <= error code */
+fn set_timer(call: &Call, target: u64) -> SBIError
+{
+    PhysicalCore::update_running_vcore(|vcore| vcore.clear_irq(SupervisorIRQ::Timer));
+
+    /* arm the timer and make sure this physical core wakes up in time to deliver it */
+    scheduler::set_vcore_timer(VirtualCoreCanonicalID
+    {
+        capsuleid: call.capsule,
+        vcoreid: call.vcore
+    }, Some(target));
+    scheduler::program_timer();
+    SBIError::Success
+}
+
+/* the timer value is 64 bits wide, and split over a0 and a1 on 32-bit systems */
+#[cfg(target_pointer_width = "64")]
+fn timer_value(call: &Call) -> u64 { call.args[0] as u64 }
+#[cfg(target_pointer_width = "32")]
+fn timer_value(call: &Call) -> u64 { (call.args[0] as u64) | ((call.args[1] as u64) << 32) }
+
+/* raise an interrupt or request an action in a set of virtual cores in the caller's capsule
+   => call = call making the request
+      mask = bitmask of virtual cores to target
+      mask_base = virtual core ID of bit zero in the mask, or HART_MASK_ALL for all virtual cores
+      pending = interrupt or action to raise
+   <= error code */
+fn raise(call: &Call, mask: usize, mask_base: usize, pending: Pending) -> SBIError
+{
+    let max = capsule::get_max_vcores(call.capsule).unwrap_or(0);
+    let bits = core::mem::size_of::<usize>() * 8;
+
+    for vid in 0..max
+    {
+        let targeted = match mask_base
+        {
+            HART_MASK_ALL => true,
+            base => vid >= base && vid - base < bits && mask & (1 << (vid - base)) != 0
+        };
+
+        if targeted == true && capsule::has_vcore(call.capsule, vid) == Some(true)
+        {
+            vcore::raise(VirtualCoreCanonicalID
+            {
+                capsuleid: call.capsule,
+                vcoreid: vid
+            }, pending);
+        }
+    }
+
+    SBIError::Success
+}
+
+/* legacy calls pass the hart mask by reference. read it from the capsule's memory
+   => cid = capsule making the call
+      addr = capsule's address of the mask
+   <= mask, or None if the address is invalid */
+fn read_legacy_hart_mask(cid: CapsuleID, addr: usize) -> Option<usize>
+{
+    /* a null pointer means all virtual cores */
+    if addr == 0
+    {
+        return Some(!0);
+    }
+
+    if addr % core::mem::size_of::<usize>() != 0
+    {
+        return None;
+    }
+
+    match capsule::translate(cid, addr)
+    {
+        Some(phys) => Some(unsafe { *(phys as *const usize) }),
+        None => None
+    }
+}
@@ -12,10 +12,12 @@ use spin::Mutex;
 use alloc::collections::vec_deque::VecDeque;
 use hashbrown::hash_map::{HashMap, self};
 use super::error::Cause;
-use super::vcore::{VirtualCore, Priority};
+use super::vcore::{self, VirtualCore, VirtualCoreCanonicalID, Priority, Pending};
 use super::pcore::{self, PhysicalCore, PhysicalCoreID};
 use super::hardware;
 use super::message;
+use super::capsule::{self, CapsuleID};
+use alloc::vec::Vec;
 
 pub type TimesliceCount = u64;
 
@@ -25,6 +27,7 @@ const HIGH_PRIO_TIMESLICES_MAX: TimesliceCount = 10;
 
 /* number of microseconds a virtual core is allowed to run */
 const TIMESLICE_LENGTH: u64 = 50000;
+const MICROSECONDS_PER_SECOND: u64 = 1000000;
 
 /* these are the global wait queues. while each physical CPU core gets its own pair
 of high-normal wait queues, virtual cores waiting to be assigned to a physical CPU sit in these global queues.
@@ -34,6 +37,19 @@ lazy_static!
 {
     static ref GLOBAL_QUEUES: Mutex<ScheduleQueues> = Mutex::new(ScheduleQueues::new());
     static ref WORKLOAD: Mutex<HashMap<PhysicalCoreID, usize>> = Mutex::new(HashMap::new());
+
+    /* set to true while a physical CPU core has been asked to give up a virtual core, so that
+    idle physical cores don't flood it with requests */
+    static ref DISOWN_REQUESTED: Mutex<bool> = Mutex::new(false);
+}
+
+/* all the virtual cores' timers are multiplexed onto the physical CPU cores' timers.
+these tables record when each armed virtual core timer should fire, and when each physical
+CPU core's current timeslice ends. both are measured in timer ticks rather than microseconds */
+lazy_static!
+{
+    static ref VCORE_TIMERS: Mutex<HashMap<VirtualCoreCanonicalID, u64>> = Mutex::new(HashMap::new());
+    static ref TIMESLICE_ENDS: Mutex<HashMap<PhysicalCoreID, u64>> = Mutex::new(HashMap::new());
 }
 
 /* queue a virtual core in global wait list */
@@ -42,6 +58,60 @@ pub fn queue(to_queue: VirtualCore)
     GLOBAL_QUEUES.lock().queue(to_queue);
 }
 
+/* remove and destroy a dying capsule's virtual cores waiting in the global queue.
+   physical CPU cores purge their own queues when they're told the capsule is dying
+   => cid = ID of the capsule being torn down */
+pub fn purge_capsule(cid: CapsuleID)
+{
+    /* drop the virtual cores after releasing the lock: the last one to go releases the capsule */
+    let purged = GLOBAL_QUEUES.lock().purge(cid);
+    drop(purged);
+}
+
+/* move a virtual core waiting in this physical CPU core's queue to the global queue, if there's
+   one to spare, so that an idle physical core can pick it up. this is done at the request of
+   another physical core, via a DisownQueuedVirtualCore message */
+pub fn disown_queued_vcore()
+{
+    if let Some(vcore) = PhysicalCore::dequeue()
+    {
+        GLOBAL_QUEUES.lock().queue(vcore);
+        if let Some(count) = WORKLOAD.lock().get_mut(&PhysicalCore::get_id())
+        {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    *(DISOWN_REQUESTED.lock()) = false;
+}
+
+/* take the next virtual core to run from the global queue or, failing that, from this physical CPU core's
+   queue. virtual cores belonging to dying capsules that slipped through a purge are destroyed rather than run
+   <= virtual core to run, or None if nothing's waiting. the flag is true if it came from the global queue */
+fn dequeue_runnable() -> Option<(VirtualCore, bool)>
+{
+    loop
+    {
+        let global = GLOBAL_QUEUES.lock().dequeue();
+        let (candidate, from_global) = match global
+        {
+            Some(v) => (v, true),
+            None => match PhysicalCore::dequeue()
+            {
+                Some(v) => (v, false),
+                None => return None
+            }
+        };
+
+        if capsule::is_alive(candidate.get_capsule_id()) == true
+        {
+            return Some((candidate, from_global));
+        }
+
+        drop(candidate);
+    }
+}
+
 /* activate preemptive multitasking. each physical CPU core should call this
    to start running workloads - be them user/supervisor or management tasks
    <= returns OK, or error code on failure */
@@ -68,11 +138,12 @@ pub fn run_next(must_switch: bool)
             let mut something_found = true;
 
             /* check to see if there's anything waiting to be picked up for this
-            physical CPU from a global queue. if so, then adopt it so it can get a chance to run */
-            match GLOBAL_QUEUES.lock().dequeue()
+            physical CPU from a global queue. if so, then adopt it so it can get a chance to run.
+            otherwise, try to take a virtual CPU core waiting for this physical CPU core and run it */
+            match dequeue_runnable()
             {
                 /* we've found a virtual CPU core to run, so switch to that */
-                Some(orphan) =>
+                Some((orphan, true)) =>
                 {
                     let mut workloads =  WORKLOAD.lock();
 
@@ -90,12 +161,8 @@ pub fn run_next(must_switch: bool)
                     pcore::context_switch(orphan);
                 },
 
-                /* otherwise, try to take a virtual CPU core waiting for this physical CPU core and run it */
-                _ => match PhysicalCore::dequeue()
-                {
-                    Some(virtcore) => pcore::context_switch(virtcore), /* waiting virtual CPU core found, queuing now */
-                    _ => something_found = false /* nothing else to run */
-                }
+                Some((virtcore, false)) => pcore::context_switch(virtcore), /* waiting virtual CPU core found, queuing now */
+                None => something_found = false /* nothing else to run */
             }
 
             if must_switch == false || (must_switch && something_found == true)
@@ -113,8 +180,121 @@ pub fn run_next(must_switch: bool)
         housekeeping(); /* can't run workloads so find something else to do */
     }
 
-    /* tell the timer system to call us back soon */
-    hardware::scheduler_timer_next(TIMESLICE_LENGTH);
+    /* start a new timeslice and tell the timer system to call us back at the end of it, or sooner */
+    start_timeslice();
+    program_timer();
+}
+
+/* handle this physical CPU core's timer firing. deliver any expired virtual core timers,
+   and if the current timeslice is over, find something else to run */
+pub fn timer_irq()
+{
+    fire_vcore_timers();
+
+    /* bring back any crashed capsules that are due a restart */
+    capsule::restart_waiting();
+
+    /* act on messages from other physical CPU cores. if the virtual core we were running
+    has been taken away, we can't return to it */
+    if message::check_mailbox() == true
+    {
+        run_next(true);
+        return;
+    }
+
+    let slice_end = TIMESLICE_ENDS.lock().get(&PhysicalCore::get_id()).cloned();
+    match (hardware::scheduler_get_timer_now(), slice_end)
+    {
+        /* we were woken early for a virtual core's timer, so carry on with this timeslice */
+        (Some(now), Some(end)) if now < end => program_timer(),
+        (_, _) => run_next(false)
+    }
+}
+
+/* arm or disarm a virtual core's timer. call program_timer() afterwards if the
+   virtual core is running on this physical CPU core so that the timer fires on time
+   => id = virtual core to update
+      target = timer value at which to interrupt the virtual core, or None to disarm */
+pub fn set_vcore_timer(id: VirtualCoreCanonicalID, target: Option<u64>)
+{
+    let mut timers = VCORE_TIMERS.lock();
+    match target
+    {
+        Some(t) => timers.insert(id, t),
+        None => timers.remove(&id)
+    };
+}
+
+/* raise a timer interrupt in every virtual core whose timer has expired. virtual cores running on
+   other physical cores are interrupted by those cores, and virtual cores that aren't running
+   will receive the interrupt when they are next scheduled to run */
+fn fire_vcore_timers()
+{
+    let now = match hardware::scheduler_get_timer_now()
+    {
+        Some(n) => n,
+        None => return
+    };
+
+    let mut expired = Vec::new();
+    VCORE_TIMERS.lock().retain(|&id, &mut target|
+    {
+        if target <= now
+        {
+            expired.push(id);
+            return false;
+        }
+        true
+    });
+
+    /* raise the interrupts once the timers are unlocked, as raising them may send messages */
+    for id in expired
+    {
+        vcore::raise(id, Pending::TimerIRQ);
+    }
+}
+
+/* record when this physical CPU core's new timeslice will end */
+fn start_timeslice()
+{
+    let id = PhysicalCore::get_id();
+    match (hardware::scheduler_get_timer_now(), hardware::get_timebase_frequency())
+    {
+        (Some(now), Some(freq)) =>
+        {
+            TIMESLICE_ENDS.lock().insert(id, now + (TIMESLICE_LENGTH * freq) / MICROSECONDS_PER_SECOND);
+        },
+        (_, _) =>
+        {
+            TIMESLICE_ENDS.lock().remove(&id);
+        }
+    };
+}
+
+/* program this physical CPU core's timer to fire at the end of the current timeslice, or
+   sooner if a virtual core last run by this physical core has a timer due before then */
+pub fn program_timer()
+{
+    let id = PhysicalCore::get_id();
+    let mut next = TIMESLICE_ENDS.lock().get(&id).cloned();
+
+    for (&vcoreid, &target) in VCORE_TIMERS.lock().iter()
+    {
+        if pcore::last_ran_on(vcoreid) == Some(id)
+        {
+            next = match next
+            {
+                Some(n) if n <= target => Some(n),
+                _ => Some(target)
+            };
+        }
+    }
+
+    match next
+    {
+        Some(target) => hardware::scheduler_timer_at(target),
+        None => hardware::scheduler_timer_next(TIMESLICE_LENGTH)
+    };
 }
 
 /* perform any housekeeping duties */
@@ -122,18 +302,24 @@ fn housekeeping()
 {
     /* hook into housekeeping functions here with suitable macros */
     debughousekeeper!(); /* drain the debug logs to the debug hardware port */
+    consolehousekeeper!(); /* route any keys pressed to the capsule using the console */
     physmemhousekeeper!(); /* tidy up any physical memory structures */
+    heaphousekeeper!(); /* check this physical CPU core's heap is intact */
+    message::check_mailbox(); /* act on messages from other physical CPU cores */
+    capsule::restart_waiting(); /* bring back any crashed capsules that are due a restart */
 
     /* if the global queues are empty then work out which physical CPU core
-    has the most number of virtual cores and is therefore the busiest */
-    let global_queue_lock = GLOBAL_QUEUES.lock();
-    if global_queue_lock.total_queued() > 0
+    has the most number of virtual cores and is therefore the busiest. only ask
+    one physical core at a time to give up a virtual core */
+    let global_queue_empty = GLOBAL_QUEUES.lock().total_queued() == 0;
+    let mut requested = DISOWN_REQUESTED.lock();
+    if global_queue_empty == true && *requested == false
     {
         let mut highest_count = 0;
         let mut busiest_pcore: Option<PhysicalCoreID> = None;
         for (&pcoreid, &vcore_count) in WORKLOAD.lock().iter()
         {
-            if vcore_count > highest_count
+            if vcore_count > highest_count && pcoreid != PhysicalCore::get_id()
             {
                 highest_count = vcore_count;
                 busiest_pcore = Some(pcoreid);
@@ -148,7 +334,7 @@ fn housekeeping()
             {
                 let m = message::Message::new(message::Recipient::send_to_pcore(pid),
                                                 message::MessageContent::DisownQueuedVirtualCore);
-                message::send(m);
+                *requested = message::send(m).is_ok();
             }
         }
     }
@@ -228,6 +414,33 @@ impl ScheduleQueues
         }
     }
 
+    /* remove all of a capsule's virtual cores from these queues
+       => cid = ID of the capsule
+       <= the removed virtual cores. drop them to destroy them */
+    pub fn purge(&mut self, cid: CapsuleID) -> Vec<VirtualCore>
+    {
+        let mut purged = Vec::new();
+        for queue in [&mut self.high, &mut self.low].iter_mut()
+        {
+            let mut index = 0;
+            while index < queue.len()
+            {
+                if queue[index].get_capsule_id() == cid
+                {
+                    if let Some(v) = queue.remove(index)
+                    {
+                        purged.push(v);
+                    }
+                }
+                else
+                {
+                    index = index + 1;
+                }
+            }
+        }
+        purged
+    }
+
     /* return the total number of virtual cores queued */
     pub fn total_queued(&self) -> usize
     {
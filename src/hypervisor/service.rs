@@ -10,37 +10,128 @@ use hashbrown::hash_map::{self, HashMap, Entry};
 use alloc::collections::vec_deque::VecDeque;
 use super::message;
 use super::error::Cause;
+use super::heap;
 use super::capsule::{self, CapsuleID};
 
 pub type ServiceID = usize;
 
-/* todo: a fixed list of known system services,
-such as video, sound, serial, network, etc
-that privileged / trusted capsules can register.
-then other capsules can message those services
-to access those underlying resources. */
+/* a fixed list of known system services that privileged / trusted capsules
+can be allowed to register. other capsules can then message those services
+to access those underlying resources. their IDs are reserved and never
+handed out dynamically. zero is never a valid service ID */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SystemService
+{
+    Console = 1,    /* text console */
+    Block = 2,      /* block storage */
+    Network = 3,    /* network interface */
+    Video = 4,      /* framebuffer */
+    Sound = 5,      /* audio */
+    Serial = 6,     /* serial ports */
+    Input = 7       /* keyboard, mouse, etc */
+}
+
+impl SystemService
+{
+    /* return the well-known service with the given ID, or None if it isn't one */
+    pub fn from_id(sid: ServiceID) -> Option<SystemService>
+    {
+        match sid
+        {
+            1 => Some(SystemService::Console),
+            2 => Some(SystemService::Block),
+            3 => Some(SystemService::Network),
+            4 => Some(SystemService::Video),
+            5 => Some(SystemService::Sound),
+            6 => Some(SystemService::Serial),
+            7 => Some(SystemService::Input),
+            _ => None
+        }
+    }
+
+    /* return this well-known service's ID */
+    pub fn id(self) -> ServiceID
+    {
+        self as ServiceID
+    }
+}
+
+/* service IDs below this are reserved for well-known system services. IDs from this
+value upwards are handed out by the hypervisor to capsules providing their own services.
+build.rs generates this so that the manifest is checked against the same range */
+pub const DYNAMIC_SERVICE_BASE: ServiceID = include!(concat!(env!("OUT_DIR"), "/service_base.rs"));
+
+/* return true if the given service ID is in the range handed out dynamically */
+pub fn is_dynamic(sid: ServiceID) -> bool
+{
+    sid >= DYNAMIC_SERVICE_BASE
+}
+
+/* most dynamic services a capsule can hold at once, so that no capsule can use up
+the dynamic service IDs or the hypervisor's memory */
+const DYNAMIC_SERVICES_MAX: usize = 16;
+
+/* maximum number of messages that can wait for a service, or in a capsule's inbox,
+before further messages are refused */
+const QUEUE_DEPTH_MAX: usize = 64;
 
 /* maintain a table of registered services */
 lazy_static!
 {
     static ref SERVICES: Mutex<HashMap<ServiceID, Service>> = Mutex::new(HashMap::new());
+
+    /* the next dynamic service ID to try handing out */
+    static ref DYNAMIC_NEXT: Mutex<ServiceID> = Mutex::new(DYNAMIC_SERVICE_BASE);
+}
+
+/* replies from services wait in their capsules' inboxes */
+lazy_static!
+{
+    static ref INBOXES: Mutex<HashMap<CapsuleID, VecDeque<message::Message>>> = Mutex::new(HashMap::new());
 }
 
 /* describe an individual service */
 struct Service
 {
     capsuleid: CapsuleID,       /* capsule that's registered this service */
-    msgs: VecDeque<message::Message>  /* queue of messages to deliver to service */
+    msgs: VecDeque<message::Message>,  /* queue of messages to deliver to service, oldest first */
+    outstanding: HashMap<CapsuleID, usize>  /* number of requests awaiting a reply, by requesting capsule */
 }
 
 impl Service
 {
-    pub fn queue(&mut self, msg: message::Message)
+    pub fn new(cid: CapsuleID) -> Service
     {
-        self.msgs.push_front(msg);
+        Service
+        {
+            capsuleid: cid,
+            msgs: VecDeque::new(),
+            outstanding: HashMap::new()
+        }
+    }
+
+    /* add a message to the back of the service's queue
+       <= Ok for success, or an error code if the queue is full */
+    pub fn queue(&mut self, msg: message::Message) -> Result<(), Cause>
+    {
+        queue_bounded(&mut self.msgs, msg)
     }
 }
 
+/* add a message to the back of a queue, unless the queue is full
+   <= Ok for success, or an error code if the queue is full or there isn't enough memory */
+fn queue_bounded(queue: &mut VecDeque<message::Message>, msg: message::Message) -> Result<(), Cause>
+{
+    if queue.len() >= QUEUE_DEPTH_MAX
+    {
+        return Err(Cause::MessageQueueFull);
+    }
+
+    heap::reserved(queue.try_reserve(1))?;
+    queue.push_back(msg);
+    Ok(())
+}
+
 /* register a service for a capsule. this will fail if the service is
    already registered or if the capsule has no right to run the service
    or if the capsule doesn't exist. 
@@ -49,6 +140,7 @@ impl Service
     <= return Ok for success, or a failure code */
 pub fn register(sid: ServiceID, cid: CapsuleID) -> Result<(), Cause>
 {
+    let _tag = heaptag!();
     match capsule::is_service_allowed(cid, sid)
     {
         Some(flag) => if flag == false
@@ -58,16 +150,12 @@ pub fn register(sid: ServiceID, cid: CapsuleID) -> Result<(), Cause>
         None => return Err(Cause::CapsuleBadID)
     };
 
-    let service = Service
-    {
-        capsuleid: cid,
-        msgs: VecDeque::new()
-    };
-
     /* ensure we do not double register a service */
-    if let Entry::Vacant(v) = SERVICES.lock().entry(sid)
+    let mut services = SERVICES.lock();
+    heap::reserved(services.try_reserve(1))?;
+    if let Entry::Vacant(v) = services.entry(sid)
     {
-        v.insert(service);
+        v.insert(Service::new(cid));
         return Ok(());
     }
     else
@@ -76,6 +164,67 @@ pub fn register(sid: ServiceID, cid: CapsuleID) -> Result<(), Cause>
     }
 }
 
+/* register a service for a capsule under a newly assigned dynamic ID. a capsule can hold up to
+   DYNAMIC_SERVICES_MAX of these at once. the ID isn't added to the capsule's allowed services,
+   so nothing is left behind when the service is deregistered
+   => cid = ID of capsule to handle this service
+   <= the new service's ID, or a failure code */
+pub fn register_dynamic(cid: CapsuleID) -> Result<ServiceID, Cause>
+{
+    let _tag = heaptag!();
+    let sid =
+    {
+        let mut services = SERVICES.lock();
+        if services.iter().filter(|(&sid, s)| is_dynamic(sid) && s.capsuleid == cid).count() >= DYNAMIC_SERVICES_MAX
+        {
+            return Err(Cause::ServiceLimitReached);
+        }
+
+        heap::reserved(services.try_reserve(1))?;
+        let sid = allocate_dynamic(&services)?;
+        services.insert(sid, Service::new(cid));
+        sid
+    };
+
+    /* the capsule is marked as dying before its services are removed, so checking this after
+    the service is in place ensures the service can't outlive the capsule */
+    if capsule::is_alive(cid) == false
+    {
+        let _ = deregister(sid);
+        return Err(Cause::CapsuleBadID);
+    }
+    Ok(sid)
+}
+
+/* find an unused dynamic service ID, wrapping around to the start of the dynamic range if needed
+   => services = table of registered services, locked by the caller
+   <= unused service ID, or an error code if they're all in use */
+fn allocate_dynamic(services: &HashMap<ServiceID, Service>) -> Result<ServiceID, Cause>
+{
+    let mut next = DYNAMIC_NEXT.lock();
+    let first = *next;
+
+    loop
+    {
+        let candidate = *next;
+        *next = match candidate.checked_add(1)
+        {
+            Some(n) => n,
+            None => DYNAMIC_SERVICE_BASE
+        };
+
+        if services.contains_key(&candidate) == false
+        {
+            return Ok(candidate);
+        }
+
+        if *next == first
+        {
+            return Err(Cause::ServiceIDExhaustion);
+        }
+    }
+}
+
 /* deregister a service so that its capsule is no longer responsible for it
    => sid = service to deregister
    <= Ok for success, or an error code for failure */
@@ -88,22 +237,190 @@ pub fn deregister(sid: ServiceID) -> Result<(), Cause>
     }
 }
 
-/* send the given message msg to a registered service */
+/* deregister every service provided by a capsule, such as when it is torn down
+   => cid = ID of the capsule */
+pub fn deregister_capsule(cid: CapsuleID)
+{
+    SERVICES.lock().retain(|_, service| service.capsuleid != cid);
+}
+
+/* return the ID of the capsule that registered the given service, or None if the service isn't registered */
+pub fn get_owner(sid: ServiceID) -> Option<CapsuleID>
+{
+    match SERVICES.lock().get(&sid)
+    {
+        Some(service) => Some(service.capsuleid),
+        None => None
+    }
+}
+
+/* send the given message msg to a registered service, and interrupt the service's capsule to let it know
+   <= Ok for success, or an error code if the service isn't registered or its queue is full */
 pub fn send(msg: message::Message) -> Result<(), Cause>
 {
+    let _tag = heaptag!();
     let sid = match msg.get_receiver()
     {
         message::Recipient::Service(sid) => sid,
         _ => return Err(Cause::MessageBadType)
     };
 
-    if let Some(service) = SERVICES.lock().get_mut(&sid)
+    let owner = match SERVICES.lock().get_mut(&sid)
+    {
+        Some(service) =>
+        {
+            /* a request from a capsule entitles the service to send that capsule one reply */
+            let requester = match msg.get_sender()
+            {
+                message::Sender::Capsule(cid) =>
+                {
+                    heap::reserved(service.outstanding.try_reserve(1))?;
+                    Some(cid)
+                },
+                _ => None
+            };
+
+            service.queue(msg)?;
+            if let Some(cid) = requester
+            {
+                *service.outstanding.entry(cid).or_insert(0) += 1;
+            }
+            service.capsuleid
+        },
+        None => return Err(Cause::ServiceNotFound)
+    };
+
+    capsule::notify(owner);
+    Ok(())
+}
+
+/* send a service's reply to a capsule's inbox. the capsule must have sent the service a request
+   that hasn't been replied to yet, and each request can be replied to once
+   => sid = ID of the service replying
+      cid = ID of the capsule replying on the service's behalf, which must have registered it
+      msg = the reply, addressed to the capsule that made the request
+   <= Ok for success, or an error code */
+pub fn reply(sid: ServiceID, cid: CapsuleID, msg: message::Message) -> Result<(), Cause>
+{
+    let requester = match msg.get_receiver()
+    {
+        message::Recipient::Capsule(requester) => requester,
+        _ => return Err(Cause::MessageBadType)
+    };
+
+    match SERVICES.lock().get_mut(&sid)
+    {
+        Some(service) if service.capsuleid == cid => match service.outstanding.get_mut(&requester)
+        {
+            Some(count) if *count > 0 => *count = *count - 1,
+            _ => return Err(Cause::ServiceNotAllowed)
+        },
+        Some(_) => return Err(Cause::ServiceNotAllowed),
+        None => return Err(Cause::ServiceNotFound)
+    };
+
+    if let Err(e) = message::send(msg)
     {
-        service.queue(msg);
-        Ok(())
+        /* the reply wasn't delivered, so allow the service to try again */
+        if let Some(service) = SERVICES.lock().get_mut(&sid)
+        {
+            if let Some(count) = service.outstanding.get_mut(&requester)
+            {
+                *count = *count + 1;
+            }
+        }
+        return Err(e);
     }
-    else
+    Ok(())
+}
+
+/* take the oldest message waiting for a service. only the capsule that registered the service can do this
+   => sid = ID of service
+      cid = ID of capsule asking for the message
+   <= Some message, or None if none are waiting, or an error code */
+pub fn receive(sid: ServiceID, cid: CapsuleID) -> Result<Option<message::Message>, Cause>
+{
+    match SERVICES.lock().get_mut(&sid)
+    {
+        Some(service) if service.capsuleid == cid => Ok(service.msgs.pop_front()),
+        Some(_) => Err(Cause::ServiceNotAllowed),
+        None => Err(Cause::ServiceNotFound)
+    }
+}
+
+/* deliver the given message msg to a capsule's inbox, and interrupt the capsule to let it know
+   <= Ok for success, or an error code if the capsule isn't running or its inbox is full */
+pub fn deliver(msg: message::Message) -> Result<(), Cause>
+{
+    let cid = match msg.get_receiver()
+    {
+        message::Recipient::Capsule(cid) => cid,
+        _ => return Err(Cause::MessageBadType)
+    };
+
+    if capsule::is_alive(cid) == false
+    {
+        return Err(Cause::CapsuleBadID);
+    }
+
+    let mut inboxes = INBOXES.lock();
+    heap::reserved(inboxes.try_reserve(1))?;
+    match inboxes.entry(cid)
+    {
+        Entry::Occupied(mut inbox) => queue_bounded(inbox.get_mut(), msg)?,
+        Entry::Vacant(v) =>
+        {
+            let mut inbox = VecDeque::new();
+            queue_bounded(&mut inbox, msg)?;
+            v.insert(inbox);
+        }
+    };
+    drop(inboxes);
+
+    capsule::notify(cid);
+    Ok(())
+}
+
+/* take the oldest message waiting in a capsule's inbox
+   => cid = ID of capsule
+   <= Some message, or None if the inbox is empty */
+pub fn receive_reply(cid: CapsuleID) -> Option<message::Message>
+{
+    match INBOXES.lock().get_mut(&cid)
+    {
+        Some(inbox) => inbox.pop_front(),
+        None => None
+    }
+}
+
+/* return true if any messages are waiting for a capsule, either in its inbox or for its services */
+pub fn messages_waiting(cid: CapsuleID) -> bool
+{
+    if let Some(inbox) = INBOXES.lock().get(&cid)
+    {
+        if inbox.len() > 0
+        {
+            return true;
+        }
+    }
+
+    for (_, service) in SERVICES.lock().iter()
+    {
+        if service.capsuleid == cid && service.msgs.len() > 0
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/* discard a capsule's inbox when the capsule is torn down
+   => cid = ID of capsule */
+pub fn forget_capsule(cid: CapsuleID)
+{
+    INBOXES.lock().remove(&cid);
+    for (_, service) in SERVICES.lock().iter_mut()
     {
-        return Err(Cause::ServiceNotAllowed)
+        service.outstanding.remove(&cid);
     }
 }
\ No newline at end of file
@@ -0,0 +1,588 @@
+/* diosix size-class slab allocator
+ *
+ * Most of the hypervisor's allocations are small and short-lived:
+ * hash map tables, message queues, boxed objects. Rather than search
+ * a physical CPU core's heap for a free block each time, small
+ * allocations are rounded up to a power-of-two size class and
+ * served from slabs: blocks taken from the heap and divided into
+ * objects of the same size. Each physical CPU core has its own
+ * slabs, from which only it allocates. Any core can free an object:
+ * objects freed by other cores are queued for the slabs' owner to
+ * take back. Larger allocations are made from the heap.
+ *
+ * In debug builds, each slab keeps the heap tag of each of its
+ * allocated objects, and whether each object is allocated, in arrays
+ * between its header and objects. Freed objects are poisoned, and
+ * freeing an object twice is caught, even by another core.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use core::alloc::Layout;
+use core::ptr::{self, null_mut};
+use core::mem;
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::error::Cause;
+use super::heap::{self, Heap, RemoteFrees, AllocTag};
+
+/* size classes run from 32 to 4096 bytes in powers of two */
+const SMALLEST_CLASS_SHIFT: usize = 5;
+const LARGEST_CLASS_SHIFT: usize = 12;
+const CLASSES: usize = LARGEST_CLASS_SHIFT - SMALLEST_CLASS_SHIFT + 1;
+
+/* slabs are taken from the heap in blocks of this size, aligned to this size,
+so that an object's slab can be found from the object's address */
+pub const SLAB_SIZE: usize = 16 * 1024;
+
+/* mark the start of a slab */
+const SLAB_MAGIC: usize = 0x051ab51ab;
+
+/* heap tag given to slabs, so their objects can be counted individually */
+#[cfg(debug_assertions)]
+pub const SLAB_TAG: AllocTag = Some("slabs");
+#[cfg(not(debug_assertions))]
+pub const SLAB_TAG: AllocTag = ();
+
+/* in debug builds, each object has a flag that's set while it's allocated */
+#[cfg(debug_assertions)]
+const STATE_SIZE: usize = mem::size_of::<AtomicBool>();
+#[cfg(not(debug_assertions))]
+const STATE_SIZE: usize = 0;
+
+/* a free object points to the next free object in its slab */
+struct FreeObject
+{
+    next: *mut FreeObject
+}
+
+/* describe a slab. this header sits at the start of the slab, followed by its objects */
+#[repr(C)]
+struct Slab
+{
+    magic: usize,
+    owner: *const SlabAllocator,    /* per-CPU allocator that owns this slab */
+    class: usize,                   /* index of the slab's size class */
+    prev: *mut Slab,                /* neighbours in the size class's list of slabs with free objects */
+    next: *mut Slab,
+    free: *mut FreeObject,          /* list of this slab's free objects */
+    in_use: usize,                  /* number of objects allocated from this slab */
+    first: usize,                   /* offset from the start of the slab to its first object */
+    capacity: usize                 /* number of objects in the slab */
+    /* array of the objects' tags follows, then in debug builds their states, then the objects themselves */
+}
+
+impl Slab
+{
+    /* return true if the given address is the start of one of this slab's objects */
+    unsafe fn holds(slab: *const Slab, object: *mut u8) -> bool
+    {
+        let size = class_size((*slab).class);
+        let offset = (object as usize).wrapping_sub((slab as usize) + (*slab).first);
+        offset % size == 0 && offset / size < (*slab).capacity
+    }
+
+    /* return the index of the given object in this slab */
+    unsafe fn index_of(slab: *const Slab, object: *mut u8) -> usize
+    {
+        ((object as usize) - (slab as usize) - (*slab).first) / class_size((*slab).class)
+    }
+
+    /* in debug builds, return the flag that's set while the given object is allocated */
+    #[cfg(debug_assertions)]
+    unsafe fn state_of(slab: *const Slab, object: *mut u8) -> &'static AtomicBool
+    {
+        let states = (slab as usize) + mem::size_of::<Slab>() + ((*slab).capacity * mem::size_of::<AllocTag>());
+        &*((states + (Slab::index_of(slab, object) * STATE_SIZE)) as *const AtomicBool)
+    }
+
+    /* in debug builds, note the given object is allocated */
+    #[cfg(debug_assertions)]
+    unsafe fn mark_allocated(slab: *const Slab, object: *mut u8)
+    {
+        Slab::state_of(slab, object).store(true, Ordering::SeqCst);
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn mark_allocated(_slab: *const Slab, _object: *mut u8) {}
+
+    /* in debug builds, note the given object is free. any core can do this, so the flag is
+    changed atomically to catch two cores freeing the same object at once
+       <= true if the object was allocated, or false if it was already free */
+    #[cfg(debug_assertions)]
+    unsafe fn mark_freed(slab: *const Slab, object: *mut u8) -> bool
+    {
+        Slab::state_of(slab, object).swap(false, Ordering::SeqCst)
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn mark_freed(_slab: *const Slab, _object: *mut u8) -> bool { true }
+
+    /* in debug builds, fill a freed object with poison so that use after free stands out */
+    #[cfg(debug_assertions)]
+    unsafe fn poison(slab: *const Slab, object: *mut u8)
+    {
+        ptr::write_bytes(object, heap::POISON, class_size((*slab).class));
+    }
+
+    #[cfg(not(debug_assertions))]
+    unsafe fn poison(_slab: *const Slab, _object: *mut u8) {}
+
+    /* return a pointer to the tag of the given object in this slab */
+    unsafe fn tag_of(slab: *mut Slab, object: *mut u8) -> *mut AllocTag
+    {
+        let index = Slab::index_of(slab, object);
+        ((slab as usize) + mem::size_of::<Slab>() + (index * mem::size_of::<AllocTag>())) as *mut AllocTag
+    }
+}
+
+/* list the slabs of a size class that have free objects. full slabs are left off
+the list until one of their objects is freed */
+#[derive(Clone, Copy)]
+struct SizeClass
+{
+    partial: *mut Slab
+}
+
+impl SizeClass
+{
+    /* add a slab to the front of this size class's list */
+    unsafe fn push(&mut self, slab: *mut Slab)
+    {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if self.partial.is_null() == false
+        {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    /* remove a slab from this size class's list */
+    unsafe fn unlink(&mut self, slab: *mut Slab)
+    {
+        match (*slab).prev.is_null()
+        {
+            true => self.partial = (*slab).next,
+            false => (*(*slab).prev).next = (*slab).next
+        }
+
+        if (*slab).next.is_null() == false
+        {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+}
+
+/* each physical CPU core has one of these alongside its heap */
+#[repr(C)]
+pub struct SlabAllocator
+{
+    /* only the physical CPU core that owns this allocator touches its slabs */
+    classes: [SizeClass; CLASSES],
+    /* number of objects allocated in each size class */
+    objects: [usize; CLASSES],
+    /* objects freed by other physical CPU cores, to be returned to their slabs when the owner next allocates */
+    remote: RemoteFrees
+}
+
+impl SlabAllocator
+{
+    pub fn new() -> SlabAllocator
+    {
+        SlabAllocator
+        {
+            classes: [SizeClass { partial: null_mut() }; CLASSES],
+            objects: [0; CLASSES],
+            remote: RemoteFrees::new()
+        }
+    }
+
+    /* initialize this allocator in place. its memory may hold junk, so don't try to drop what's there */
+    pub fn init(&mut self)
+    {
+        unsafe { ptr::write(self, SlabAllocator::new()); }
+    }
+
+    /* allocate an object. only call this on the physical CPU core that owns this allocator
+       => heap = this physical CPU core's heap, from which new slabs are taken
+          class = size class of the object, from class_of()
+       <= pointer to the object, or an error code */
+    pub fn alloc(&mut self, heap: &mut Heap, class: usize) -> Result<*mut u8, Cause>
+    {
+        self.reclaim_remote_frees(heap);
+
+        unsafe
+        {
+            if self.classes[class].partial.is_null()
+            {
+                let slab = self.create_slab(heap, class)?;
+                self.classes[class].push(slab);
+            }
+
+            let slab = self.classes[class].partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use = (*slab).in_use + 1;
+            *Slab::tag_of(slab, object as *mut u8) = heap.get_tag();
+            Slab::mark_allocated(slab, object as *mut u8);
+            self.objects[class] = self.objects[class] + 1;
+
+            /* forget the slab until one of its objects is freed if it's now full */
+            if (*slab).free.is_null() == true
+            {
+                self.classes[class].unlink(slab);
+            }
+
+            Ok(object as *mut u8)
+        }
+    }
+
+    /* take a new slab from the heap and divide it into free objects
+       => heap = heap to take the slab from
+          class = size class of the slab's objects
+       <= pointer to the slab, or an error code */
+    unsafe fn create_slab(&self, heap: &mut Heap, class: usize) -> Result<*mut Slab, Cause>
+    {
+        let previous = heap.swap_tag(SLAB_TAG);
+        let slab = heap.alloc_aligned(SLAB_SIZE, SLAB_SIZE);
+        heap.swap_tag(previous);
+        let slab = slab? as *mut Slab;
+        let size = class_size(class);
+
+        /* objects follow the header and their tags and states, aligned to their size */
+        let tag_size = mem::size_of::<AllocTag>() + STATE_SIZE;
+        let mut capacity = (SLAB_SIZE - mem::size_of::<Slab>()) / (size + tag_size);
+        let first = loop
+        {
+            let first = (mem::size_of::<Slab>() + (capacity * tag_size) + size - 1) & !(size - 1);
+            if first + (capacity * size) <= SLAB_SIZE
+            {
+                break first;
+            }
+            capacity = capacity - 1;
+        };
+
+        /* thread the objects onto the free list so that they're handed out lowest address first */
+        let mut free = null_mut();
+        for index in (0..capacity).rev()
+        {
+            let object = ((slab as usize) + first + (index * size)) as *mut FreeObject;
+            (*object).next = free;
+            free = object;
+        }
+
+        ptr::write(slab, Slab
+        {
+            magic: SLAB_MAGIC,
+            owner: self as *const SlabAllocator,
+            class: class,
+            prev: null_mut(),
+            next: null_mut(),
+            free: free,
+            in_use: 0,
+            first: first,
+            capacity: capacity
+        });
+
+        /* all objects start out free */
+        let states = (slab as usize) + mem::size_of::<Slab>() + (capacity * mem::size_of::<AllocTag>());
+        ptr::write_bytes(states as *mut u8, 0, capacity * STATE_SIZE);
+
+        Ok(slab)
+    }
+
+    /* return an object to its slab, giving the slab back to the heap if it's now
+       empty and its size class has other slabs with free objects
+       => heap = heap of the physical CPU core freeing the object
+          slab = slab containing the object
+          object = object to free
+          queued = true if the object was freed by another core, which checked and poisoned it
+       <= Ok for success, or an error code */
+    unsafe fn release(&mut self, heap: &mut Heap, slab: *mut Slab, object: *mut u8, queued: bool) -> Result<(), Cause>
+    {
+        if Slab::holds(slab, object) == false || (*slab).in_use == 0
+        {
+            return Err(Cause::HeapBadBlock);
+        }
+
+        if queued == false
+        {
+            if Slab::mark_freed(slab, object) == false
+            {
+                return Err(Cause::HeapNotInUse);
+            }
+            Slab::poison(slab, object);
+        }
+
+        self.objects[(*slab).class] = self.objects[(*slab).class] - 1;
+        let class = &mut self.classes[(*slab).class];
+
+        let was_full = (*slab).free.is_null();
+        let object = object as *mut FreeObject;
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use = (*slab).in_use - 1;
+
+        if was_full == true
+        {
+            class.push(slab);
+        }
+
+        /* keep the last slab with free objects to avoid repeatedly creating and destroying a slab */
+        if (*slab).in_use == 0 && (class.partial != slab || (*slab).next.is_null() == false)
+        {
+            class.unlink(slab);
+            (*slab).magic = 0;
+            return heap.free(slab);
+        }
+
+        Ok(())
+    }
+
+    /* describe how many objects of each size are allocated straight to the debug port */
+    pub fn dump(&self)
+    {
+        for (class, &count) in self.objects.iter().enumerate()
+        {
+            if count > 0
+            {
+                hvalert_now!("Slabs: {} objects of {} bytes in use", count, class_size(class));
+            }
+        }
+    }
+
+    /* return objects freed by other physical CPU cores to their slabs
+       => heap = this physical CPU core's heap, to which empty slabs are given back */
+    fn reclaim_remote_frees(&mut self, heap: &mut Heap)
+    {
+        for object in self.remote.take()
+        {
+            let slab = ((object as usize) & !(SLAB_SIZE - 1)) as *mut Slab;
+            if let Err(e) = unsafe { self.release(heap, slab, object, true) }
+            {
+                hvalert!("Slabs: Object {:p} owned by physical CPU core {} and freed by another core was bad ({:?})",
+                         object, heap.get_id(), e);
+            }
+        }
+    }
+}
+
+/* return the size class of objects that can hold an allocation, or None if it's too large for a slab */
+pub fn class_of(layout: Layout) -> Option<usize>
+{
+    let size = core::cmp::max(layout.size(), layout.align());
+    if size > class_size(CLASSES - 1)
+    {
+        return None;
+    }
+
+    let size = core::cmp::max(size, class_size(0)).next_power_of_two();
+    Some(size.trailing_zeros() as usize - SMALLEST_CLASS_SHIFT)
+}
+
+/* return the size in bytes of a size class's objects, which are also aligned to this size */
+fn class_size(class: usize) -> usize
+{
+    1 << (class + SMALLEST_CLASS_SHIFT)
+}
+
+/* free an object allocated from any physical CPU core's slabs. if the object
+   belongs to another core's slabs, it's queued for that core to take back
+   => slabs = slab allocator of the physical CPU core freeing the object
+      heap = heap of the physical CPU core freeing the object
+      object = pointer returned by SlabAllocator::alloc()
+   <= Ok for success, or an error code */
+pub fn free(slabs: &mut SlabAllocator, heap: &mut Heap, object: *mut u8) -> Result<(), Cause>
+{
+    let slab = ((object as usize) & !(SLAB_SIZE - 1)) as *mut Slab;
+    let result = unsafe
+    {
+        if (*slab).magic != SLAB_MAGIC || Slab::holds(slab, object) == false
+        {
+            Err(Cause::HeapBadBlock)
+        }
+        else if (*slab).owner == slabs as *const SlabAllocator
+        {
+            slabs.release(heap, slab, object, false)
+        }
+        else if Slab::mark_freed(slab, object) == false
+        {
+            Err(Cause::HeapNotInUse)
+        }
+        else
+        {
+            Slab::poison(slab, object);
+            (*(*slab).owner).remote.push(object);
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result
+    {
+        match heap::owner_of(slab as *const u8)
+        {
+            Some(id) => hvalert!("Slabs: Failed to free object {:p} owned by physical CPU core {} ({:?})", object, id, e),
+            None => hvalert!("Slabs: Failed to free object {:p} of unknown owner ({:?})", object, e)
+        }
+    }
+    result
+}
+
+/* count a slab's allocated objects by tag
+   => contents = address of the heap block holding the slab
+      tally = tally to add the objects to */
+#[cfg(debug_assertions)]
+pub fn tally(contents: usize, tally: &mut super::heap::Tally)
+{
+    let slab = contents as *mut Slab;
+    unsafe
+    {
+        if (*slab).magic != SLAB_MAGIC
+        {
+            return;
+        }
+
+        /* note which objects are free, without allocating memory to do so */
+        let size = class_size((*slab).class);
+        let mut free = [0u8; SLAB_SIZE / (1 << SMALLEST_CLASS_SHIFT) / 8];
+        let mut object = (*slab).free;
+        while object.is_null() == false
+        {
+            let index = ((object as usize) - contents - (*slab).first) / size;
+            free[index / 8] = free[index / 8] | (1 << (index % 8));
+            object = (*object).next;
+        }
+
+        for index in 0..(*slab).capacity
+        {
+            if free[index / 8] & (1 << (index % 8)) == 0
+            {
+                let object = (contents + (*slab).first + (index * size)) as *mut u8;
+                tally.add(*Slab::tag_of(slab, object), size);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_slab_allocator()
+{
+    use alloc::vec::Vec;
+    let heap = &mut super::pcore::PhysicalCore::this().heap;
+    let mut slabs = SlabAllocator::new();
+
+    /* large or overly aligned allocations aren't for slabs */
+    assert_eq!(class_of(Layout::from_size_align(1, 1).unwrap()), Some(0));
+    assert_eq!(class_of(Layout::from_size_align(40, 8).unwrap()), Some(1));
+    assert_eq!(class_of(Layout::from_size_align(8, 4096).unwrap()), Some(CLASSES - 1));
+    assert_eq!(class_of(Layout::from_size_align(4097, 8).unwrap()), None);
+
+    /* allocate enough objects to fill a few slabs. they must be aligned and never overlap */
+    let class = class_of(Layout::from_size_align(40, 8).unwrap()).unwrap();
+    let mut objects = Vec::new();
+    for _ in 0..1000
+    {
+        let object = slabs.alloc(heap, class).unwrap() as usize;
+        assert_eq!(object % 64, 0);
+        objects.push(object);
+    }
+
+    objects.sort();
+    for pair in objects.windows(2)
+    {
+        assert_eq!(pair[1] - pair[0] >= 64, true);
+    }
+
+    /* all but one slab is given back to the heap once they're empty */
+    for object in objects
+    {
+        free(&mut slabs, heap, object as *mut u8).unwrap();
+    }
+
+    let last = slabs.classes[class].partial;
+    unsafe
+    {
+        assert_eq!((*last).in_use, 0);
+        assert_eq!((*last).next.is_null(), true);
+        heap.free(last).unwrap();
+    }
+}
+
+/* measure how many timer ticks a piece of code takes to run, or None if the timer isn't available */
+fn bench<F>(f: F) -> Option<u64> where F: FnOnce()
+{
+    let start = super::hardware::scheduler_get_timer_now();
+    f();
+    match (start, super::hardware::scheduler_get_timer_now())
+    {
+        (Some(start), Some(end)) => Some(end - start),
+        _ => None
+    }
+}
+
+/* compare small allocations, which the global allocator serves from slabs, with allocations just too large
+for a slab, which it serves from the heap's list of blocks. long-lived blocks are left in place, as there would
+be in a running system, for the heap to search. the allocations go through the global allocator, as the rest of
+the hypervisor's do, so that routing them is measured too */
+#[test_case]
+fn bench_small_allocations()
+{
+    use alloc::alloc::{alloc, dealloc};
+    use alloc::vec::Vec;
+    const ROUNDS: usize = 10000;
+    const LONG_LIVED: usize = 64;
+
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align((1 << LARGEST_CLASS_SHIFT) + 64, 8).unwrap();
+    assert_eq!(class_of(small).is_some(), true);
+    assert_eq!(class_of(large).is_none(), true);
+
+    let mut held = Vec::with_capacity(LONG_LIVED);
+    for _ in 0..LONG_LIVED
+    {
+        let block = unsafe { alloc(large) };
+        assert_eq!(block.is_null(), false);
+        held.push(block);
+    }
+
+    /* make sure a slab of small objects exists before taking note of the heap's usage */
+    unsafe { dealloc(alloc(small), small) };
+    let before = super::pcore::PhysicalCore::this().heap.stats();
+
+    let time = |layout: Layout| bench(||
+    {
+        for _ in 0..ROUNDS
+        {
+            let object = unsafe { alloc(layout) };
+            assert_eq!(object.is_null(), false);
+            assert_eq!(object as usize % layout.align(), 0);
+            unsafe { dealloc(object, layout) };
+        }
+    });
+
+    let slab_ticks = time(small);
+    let heap_ticks = time(large);
+
+    /* everything allocated in the loops was freed, so the heap should be as it was */
+    let after = super::pcore::PhysicalCore::this().heap.stats();
+    assert_eq!(after.in_use, before.in_use);
+    assert_eq!(after.blocks, before.blocks);
+
+    match (slab_ticks, heap_ticks)
+    {
+        (Some(slab), Some(heap)) => hvlog!("Benchmark: {} small allocations took {} timer ticks from slabs, {} from the heap",
+                                           ROUNDS, slab, heap),
+        (_, _) => hvlog!("Benchmark: {} small allocations completed, no timer available to measure them", ROUNDS)
+    }
+
+    for block in held
+    {
+        unsafe { dealloc(block, large) };
+    }
+}
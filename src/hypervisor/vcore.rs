@@ -1,14 +1,21 @@
 /* diosix virtual CPU core management
  *
- * (c) Chris Williams, 2018-2019.
+ * (c) Chris Williams, 2018-2020.
  *
  * See LICENSE for usage and copying.
  */
 
+use spin::Mutex;
+use hashbrown::hash_map::HashMap;
+use hashbrown::hash_set::HashSet;
 use super::error::Cause;
-use super::capsule::CapsuleID;
+use super::capsule::{self, CapsuleID};
 use platform::cpu::{SupervisorState, Entry};
+use platform::irq::SupervisorIRQ;
+use platform::virtmem::VirtMemBase;
 use super::scheduler;
+use super::message;
+use super::pcore::{self, PhysicalCore};
 
 #[derive(Copy, Clone, Debug)]
 pub enum Priority
@@ -21,13 +28,84 @@ pub enum Priority
 pub type VirtualCoreID = usize;
 
 /* pair a virtual core with its parent capsule using their ID numbers */
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct VirtualCoreCanonicalID
 {
     pub capsuleid: CapsuleID,
     pub vcoreid: VirtualCoreID
 }
 
+/* interrupts and actions that can be raised for a virtual core. they are delivered straight away if the
+virtual core is running, or when it is next loaded to run on a physical CPU core if not */
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Pending
+{
+    SoftwareIRQ,        /* inter-processor interrupt */
+    TimerIRQ,           /* virtual timer expired */
+    ExternalIRQ,        /* external event, such as a message arriving */
+    FenceInstructions,  /* synchronize instruction and data streams */
+    FenceTLB            /* flush cached virtual memory translations */
+}
+
+lazy_static!
+{
+    /* acquire PENDING lock before raising or delivering interrupts and actions for virtual cores */
+    static ref PENDING: Mutex<HashMap<VirtualCoreCanonicalID, HashSet<Pending>>> = Mutex::new(HashMap::new());
+}
+
+/* raise an interrupt or request an action for a virtual core. if the virtual core is running
+   on this physical CPU core, it's delivered now. if it's running on another physical CPU core,
+   that core is messaged to deliver it. call this from an IRQ context
+   => id = virtual core to target
+      what = interrupt or action to deliver */
+pub fn raise(id: VirtualCoreCanonicalID, what: Pending)
+{
+    {
+        let mut pending = PENDING.lock();
+        match pending.get_mut(&id)
+        {
+            Some(set) =>
+            {
+                set.insert(what);
+            },
+            None =>
+            {
+                let mut set = HashSet::new();
+                set.insert(what);
+                pending.insert(id, set);
+            }
+        }
+    }
+
+    /* a physical CPU core records that it's running a virtual core before delivering the virtual core's
+    pending interrupts, so if the virtual core is switched in after we recorded this, it'll be delivered then */
+    match pcore::last_ran_on(id)
+    {
+        Some(pid) if pid == PhysicalCore::get_id() => deliver_running(id),
+        Some(pid) =>
+        {
+            let msg = message::Message::new(message::Recipient::send_to_pcore(pid), message::MessageContent::DeliverPending(id));
+            if let Err(e) = message::send(msg)
+            {
+                hvalert!("Failed to ask physical CPU core {} to deliver interrupts to vcore {} in capsule {} ({:?})",
+                         pid, id.vcoreid, id.capsuleid, e);
+            }
+        },
+        None => ()
+    }
+}
+
+/* deliver any interrupts and actions raised for the given virtual core if it's running on this physical CPU core.
+   if it isn't, they're delivered when it's next loaded to run. call this from an IRQ context
+   => id = virtual core to check */
+pub fn deliver_running(id: VirtualCoreCanonicalID)
+{
+    if PhysicalCore::get_capsule_id() == Some(id.capsuleid) && PhysicalCore::get_virtualcore_id() == Some(id.vcoreid)
+    {
+        PhysicalCore::update_running_vcore(|vcore| vcore.deliver_pending());
+    }
+}
+
 /* a virtual core is either in a waiting queue awaiting physical CPU time, or is running and held in a physical CPU core struct.
 if you remove a virtual core object from the queue and don't place it back in a queue or Core structure,
 then the vcpu will be dropped, deallocated and destroyed. */
@@ -43,10 +121,13 @@ impl VirtualCore
     /* create a virtual CPU core for a supervisor capsule
        => capsule = ID of the capsule
           core = virtual core ID within the capsule
-          entry = pointer to where to begin execution 
+          entry = pointer to where to begin execution
+          dtb = capsule's address of the device tree describing its virtualized environment.
+                the supervisor receives the virtual core ID in a0 and this address in a1.
+                cores started by the supervisor itself receive an opaque value of its choosing instead
           priority = virtual core's priority
        <= OK for success, or error code */
-    pub fn create(capsuleid: CapsuleID, core: VirtualCoreID, entry: Entry, priority: Priority) -> Result<(), Cause>
+    pub fn create(capsuleid: CapsuleID, core: VirtualCoreID, entry: Entry, dtb: VirtMemBase, priority: Priority) -> Result<(), Cause>
     {
         let new_vcore = VirtualCore
         {
@@ -56,7 +137,7 @@ impl VirtualCore
                 vcoreid: core
             },
             priority: priority,
-            state: platform::cpu::supervisor_state_from(entry)
+            state: platform::cpu::supervisor_state_from(entry, core, dtb)
         };
 
         /* add virtual CPU core to the global waiting list queue */
@@ -69,13 +150,63 @@ impl VirtualCore
     {
         &self.state
     }
-    
+
+    /* read and write the virtual core's general purpose registers and program counter.
+    these are only up to date while the hypervisor holds the virtual core's saved state */
+    pub fn get_register(&self, reg: usize) -> usize { platform::cpu::get_supervisor_register(&self.state, reg) }
+    pub fn set_register(&mut self, reg: usize, value: usize) { platform::cpu::set_supervisor_register(&mut self.state, reg, value) }
+    pub fn get_pc(&self) -> Entry { platform::cpu::get_supervisor_pc(&self.state) }
+    pub fn set_pc(&mut self, pc: Entry) { platform::cpu::set_supervisor_pc(&mut self.state, pc) }
+
+    /* raise or clear a supervisor-level interrupt in this virtual core */
+    pub fn raise_irq(&mut self, irq: SupervisorIRQ) { platform::irq::raise_supervisor_irq(&mut self.state, irq) }
+    pub fn clear_irq(&mut self, irq: SupervisorIRQ) { platform::irq::clear_supervisor_irq(&mut self.state, irq) }
+
+    /* deliver any interrupts and carry out any actions raised for this virtual core.
+    call this on the physical CPU core that is about to run this virtual core */
+    pub fn deliver_pending(&mut self)
+    {
+        let pending = match PENDING.lock().remove(&self.id)
+        {
+            Some(p) => p,
+            None => return
+        };
+
+        for what in pending.iter()
+        {
+            match what
+            {
+                Pending::SoftwareIRQ => self.raise_irq(SupervisorIRQ::Software),
+                Pending::TimerIRQ => self.raise_irq(SupervisorIRQ::Timer),
+                Pending::ExternalIRQ => self.raise_irq(SupervisorIRQ::External),
+                Pending::FenceInstructions => platform::cpu::fence_instructions(),
+                Pending::FenceTLB => platform::virtmem::flush_tlb()
+            }
+        }
+    }
+
     /* return this virtual core's ID within its capsule */
     pub fn get_id(&self) -> VirtualCoreID { self.id.vcoreid }
 
     /* return virtual CPU core capsule's ID */
     pub fn get_capsule_id(&self) -> CapsuleID { self.id.capsuleid }
 
+    /* return this virtual core's system-wide unique ID */
+    pub fn get_canonical_id(&self) -> VirtualCoreCanonicalID { self.id }
+
     /* return virtual CPU core's priority */
     pub fn get_priority(&self) -> Priority { self.priority }
 }
+
+/* forget any armed timer and undelivered interrupts and actions when a virtual core is destroyed,
+and remove it from its capsule, which is released if it's dying and this was its last virtual core */
+impl Drop for VirtualCore
+{
+    fn drop(&mut self)
+    {
+        scheduler::set_vcore_timer(self.id, None);
+        PENDING.lock().remove(&self.id);
+        pcore::forget_vcore(self.id);
+        capsule::remove_vcore(self.id.capsuleid, self.id.vcoreid);
+    }
+}
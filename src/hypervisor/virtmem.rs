@@ -1,40 +1,486 @@
 /* diosix capsule virtual memory management
+ *
+ * On CPU cores with the RISC-V hypervisor extension, capsules
+ * are given their own G-stage page tables that translate their
+ * guest physical addresses to host physical addresses. This allows
+ * capsules to have a conventional memory layout regardless of
+ * where their RAM lives in the host. Otherwise, capsules run
+ * with 1:1 mappings, kept apart by physical memory protection.
  * 
- * (c) Chris Williams, 2019.
+ * (c) Chris Williams, 2019-2020.
  *
  * See LICENSE for usage and copying.
  */
 
+use spin::Mutex;
+use alloc::vec::Vec;
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::alloc::Layout;
 use hashbrown::hash_map::HashMap;
 use hashbrown::hash_map::Entry::{Occupied, Vacant};
 use platform::physmem::{PhysMemBase, PhysMemEnd, PhysMemSize};
 use platform::virtmem::VirtMemBase;
+use platform::physmem::AccessPermissions;
 use super::physmem::Region;
+use super::grant::GrantID;
 use super::error::Cause;
 
+/* where capsules find their RAM when two-stage address translation is available */
+pub const GUEST_RAM_BASE: VirtMemBase = 0x80000000;
+
+/* G-stage page tables are made up of pages of this size, though the root table is four times larger */
+pub const PAGE_SIZE: usize = 4096;
+const PAGE_SHIFT: usize = 12;
+const ROOT_TABLE_SIZE: usize = 4 * PAGE_SIZE;
+
+/* each table level below the root decodes this many bits of address, while the root decodes two more */
+const LEVEL_BITS: usize = 9;
+const ROOT_LEVEL_BITS: usize = LEVEL_BITS + 2;
+
+/* page table entry fields */
+const PTE_SIZE: usize = core::mem::size_of::<usize>();
+const PTE_PPN_SHIFT: usize = 10;
+const PTE_VALID: usize = 1 << 0;
+const PTE_READ: usize = 1 << 1;
+const PTE_WRITE: usize = 1 << 2;
+const PTE_EXECUTE: usize = 1 << 3;
+const PTE_USER: usize = 1 << 4; /* G-stage leaf entries must have this bit set */
+const PTE_ACCESSED: usize = 1 << 6;
+const PTE_DIRTY: usize = 1 << 7;
+const PTE_LEAF: usize = PTE_VALID | PTE_USER | PTE_ACCESSED | PTE_DIRTY;
+
+/* how a capsule may access the memory behind one of its mappings */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access
+{
+    ReadWriteExecute,   /* the capsule's own RAM */
+    ReadWrite,          /* memory shared with the capsule for reading and writing */
+    ReadOnly            /* memory shared with the capsule for reading only */
+}
+
+impl Access
+{
+    /* return the G-stage page table entry permission bits for this level of access */
+    fn pte_bits(&self) -> usize
+    {
+        match self
+        {
+            Access::ReadWriteExecute => PTE_READ | PTE_WRITE | PTE_EXECUTE,
+            Access::ReadWrite => PTE_READ | PTE_WRITE,
+            Access::ReadOnly => PTE_READ
+        }
+    }
+
+    /* return the physical memory protection permissions for this level of access */
+    pub fn permissions(&self) -> AccessPermissions
+    {
+        match self
+        {
+            Access::ReadWriteExecute => AccessPermissions::ReadWriteExecute,
+            Access::ReadWrite => AccessPermissions::ReadWrite,
+            Access::ReadOnly => AccessPermissions::Read
+        }
+    }
+
+    /* return true if this level of access allows writing */
+    pub fn is_writable(&self) -> bool
+    {
+        *self != Access::ReadOnly
+    }
+}
+
+/* G-stage translation schemes, numbered as per the hgatp CSR's mode field */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GStageMode
+{
+    Sv39x4 = 8,
+    Sv48x4 = 9
+}
+
+impl GStageMode
+{
+    /* return the number of levels of page tables used by this scheme */
+    pub fn levels(&self) -> usize
+    {
+        match self
+        {
+            GStageMode::Sv39x4 => 3,
+            GStageMode::Sv48x4 => 4
+        }
+    }
+}
+
+lazy_static!
+{
+    /* the G-stage translation scheme used by all capsules, or None to rely on physical memory protection */
+    static ref GSTAGE_MODE: Mutex<Option<GStageMode>> = Mutex::new(None);
+}
+
+/* decide whether capsules will use two-stage address translation. this assumes all physical
+CPU cores have the same capabilities as the boot CPU core, which should call this function */
+pub fn init()
+{
+    let mode = match cfg!(target_pointer_width = "64")
+    {
+        true => if platform::virtmem::gstage_mode_supported(GStageMode::Sv39x4 as usize)
+        {
+            Some(GStageMode::Sv39x4)
+        }
+        else if platform::virtmem::gstage_mode_supported(GStageMode::Sv48x4 as usize)
+        {
+            Some(GStageMode::Sv48x4)
+        }
+        else
+        {
+            None
+        },
+
+        /* there's no support for Sv32x4 */
+        false => None
+    };
+
+    match mode
+    {
+        Some(m) => hvdebug!("Using {:?} two-stage address translation for capsules", m),
+        None => hvdebug!("Two-stage address translation unavailable, using physical memory protection")
+    };
+
+    *(GSTAGE_MODE.lock()) = mode;
+}
+
+/* return the G-stage translation scheme in use, or None if capsules are not using two-stage address translation */
+pub fn gstage_mode() -> Option<GStageMode>
+{
+    *(GSTAGE_MODE.lock())
+}
+
+/* a capsule's G-stage page tables, translating its guest physical addresses to host physical addresses */
+pub struct GStageTable
+{
+    mode: GStageMode,
+    root: PhysMemBase,
+    pages: Vec<(PhysMemBase, Layout)> /* heap memory holding the tables, freed when the tables are dropped */
+}
+
+impl GStageTable
+{
+    /* create an empty set of page tables
+       => mode = translation scheme to use
+       <= new tables, or an error code */
+    pub fn new(mode: GStageMode) -> Result<GStageTable, Cause>
+    {
+        let mut table = GStageTable
+        {
+            mode: mode,
+            root: 0,
+            pages: Vec::new()
+        };
+
+        table.root = table.alloc_table(ROOT_TABLE_SIZE)?;
+        Ok(table)
+    }
+
+    /* return the translation scheme and physical address of the root table, for loading into hgatp */
+    pub fn get_mode(&self) -> GStageMode { self.mode }
+    pub fn get_root(&self) -> PhysMemBase { self.root }
+
+    /* allocate a zeroed table from the heap, aligned to its size
+       => size = size of the table in bytes, a power of two
+       <= physical address of the table, or an error code */
+    fn alloc_table(&mut self, size: usize) -> Result<PhysMemBase, Cause>
+    {
+        let layout = match Layout::from_size_align(size, size)
+        {
+            Ok(l) => l,
+            Err(_) => return Err(Cause::HeapBadSize)
+        };
+
+        let table = unsafe { alloc_zeroed(layout) };
+        if table.is_null()
+        {
+            return Err(Cause::HeapNoFreeMem);
+        }
+
+        self.pages.push((table as PhysMemBase, layout));
+        Ok(table as PhysMemBase)
+    }
+
+    /* return the number of bytes covered by a leaf entry at the given level, where level zero holds 4KB pages */
+    fn page_size(level: usize) -> usize
+    {
+        PAGE_SIZE << (LEVEL_BITS * level)
+    }
+
+    /* return the address of the entry for the given guest address in the given table at the given level */
+    fn entry(&self, table: PhysMemBase, guest: VirtMemBase, level: usize) -> *mut usize
+    {
+        let bits = match level == self.mode.levels() - 1
+        {
+            true => ROOT_LEVEL_BITS,
+            false => LEVEL_BITS
+        };
+
+        let index = (guest >> (PAGE_SHIFT + (LEVEL_BITS * level))) & ((1 << bits) - 1);
+        (table + (index * PTE_SIZE)) as *mut usize
+    }
+
+    /* map a guest physical address range to a host physical memory region, using the
+       largest pages possible. both addresses and the size must be page aligned
+       => guest = guest physical address of the start of the range
+          region = host physical memory to map the range to
+          access = how the capsule may access the range
+       <= Ok for success, or an error code, in which case whatever part of the range was mapped is unmapped,
+          leaving any existing mapping that the range collided with in place */
+    pub fn map(&mut self, guest: VirtMemBase, region: Region, access: Access) -> Result<(), Cause>
+    {
+        if guest % PAGE_SIZE != 0 || region.base() % PAGE_SIZE != 0 || region.size() % PAGE_SIZE != 0
+        {
+            return Err(Cause::VirtMemBadAlignment);
+        }
+
+        let mut offset = 0;
+        while offset < region.size()
+        {
+            let (gpa, hpa) = (guest + offset, region.base() + offset);
+
+            /* find the largest page that is aligned and fits in what's left to map */
+            let mut level = self.mode.levels() - 1;
+            loop
+            {
+                let size = GStageTable::page_size(level);
+                if level == 0 || (gpa % size == 0 && hpa % size == 0 && region.size() - offset >= size)
+                {
+                    break;
+                }
+                level = level - 1;
+            }
+
+            if let Err(e) = self.set_leaf(gpa, hpa, level, access)
+            {
+                /* undo only the pages this call put in place */
+                if let Err(f) = self.unmap(guest, offset)
+                {
+                    hvalert!("BUG: Could not undo partial mapping of 0x{:x}, {} bytes ({:?})", guest, offset, f);
+                }
+                return Err(e);
+            }
+            offset = offset + GStageTable::page_size(level);
+        }
+
+        Ok(())
+    }
+
+    /* point a guest physical address at a host physical address, creating any intermediate tables needed
+       => gpa = guest physical address to map
+          hpa = host physical address to map it to
+          leaf_level = level at which to place the leaf entry
+          access = how the capsule may access the page
+       <= Ok for success, or an error code if the address is already mapped */
+    fn set_leaf(&mut self, gpa: VirtMemBase, hpa: PhysMemBase, leaf_level: usize, access: Access) -> Result<(), Cause>
+    {
+        let mut table = self.root;
+        let mut level = self.mode.levels() - 1;
+
+        loop
+        {
+            let pte = self.entry(table, gpa, level);
+            unsafe
+            {
+                if level == leaf_level
+                {
+                    if *pte & PTE_VALID != 0
+                    {
+                        return Err(Cause::VirtMemCollision);
+                    }
+
+                    *pte = ((hpa >> PAGE_SHIFT) << PTE_PPN_SHIFT) | PTE_LEAF | access.pte_bits();
+                    return Ok(());
+                }
+
+                if *pte & PTE_VALID == 0
+                {
+                    let next = self.alloc_table(PAGE_SIZE)?;
+                    *pte = ((next >> PAGE_SHIFT) << PTE_PPN_SHIFT) | PTE_VALID;
+                }
+                else if *pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE) != 0
+                {
+                    /* already covered by a larger page */
+                    return Err(Cause::VirtMemCollision);
+                }
+
+                table = (*pte >> PTE_PPN_SHIFT) << PAGE_SHIFT;
+            }
+
+            level = level - 1;
+        }
+    }
+
+    /* find the leaf entry translating a guest physical address
+       => gpa = guest physical address to look up
+       <= Some pointer to the entry and its level, or None if not mapped */
+    fn find_leaf(&self, gpa: VirtMemBase) -> Option<(*mut usize, usize)>
+    {
+        let mut table = self.root;
+        let mut level = self.mode.levels() - 1;
+
+        loop
+        {
+            let pte = self.entry(table, gpa, level);
+            unsafe
+            {
+                if *pte & PTE_VALID == 0
+                {
+                    return None;
+                }
+
+                if *pte & (PTE_READ | PTE_WRITE | PTE_EXECUTE) != 0
+                {
+                    return Some((pte, level));
+                }
+
+                if level == 0
+                {
+                    return None; /* malformed table */
+                }
+
+                table = (*pte >> PTE_PPN_SHIFT) << PAGE_SHIFT;
+            }
+
+            level = level - 1;
+        }
+    }
+
+    /* break a large page into a table of pages one level smaller
+       => pte = leaf entry to split
+          level = level of the leaf entry
+       <= Ok for success, or an error code */
+    fn split(&mut self, pte: *mut usize, level: usize) -> Result<(), Cause>
+    {
+        let next = self.alloc_table(PAGE_SIZE)?;
+        let size = GStageTable::page_size(level - 1);
+
+        unsafe
+        {
+            let base = (*pte >> PTE_PPN_SHIFT) << PAGE_SHIFT;
+            let flags = *pte & ((1 << PTE_PPN_SHIFT) - 1);
+            for index in 0..(1 << LEVEL_BITS)
+            {
+                let entry = (next + (index * PTE_SIZE)) as *mut usize;
+                *entry = (((base + (index * size)) >> PAGE_SHIFT) << PTE_PPN_SHIFT) | flags;
+            }
+
+            *pte = ((next >> PAGE_SHIFT) << PTE_PPN_SHIFT) | PTE_VALID;
+        }
+
+        Ok(())
+    }
+
+    /* remove translations for a guest physical address range, splitting large pages as needed.
+       call platform::virtmem::flush_gstage() on cores using these tables afterwards
+       => guest = page-aligned guest physical address of the start of the range
+          size = page-aligned number of bytes in the range
+       <= Ok for success, or an error code */
+    pub fn unmap(&mut self, guest: VirtMemBase, size: usize) -> Result<(), Cause>
+    {
+        if guest % PAGE_SIZE != 0 || size % PAGE_SIZE != 0
+        {
+            return Err(Cause::VirtMemBadAlignment);
+        }
+
+        let mut offset = 0;
+        while offset < size
+        {
+            let gpa = guest + offset;
+            match self.find_leaf(gpa)
+            {
+                Some((pte, level)) =>
+                {
+                    let page = GStageTable::page_size(level);
+                    if gpa % page != 0 || size - offset < page
+                    {
+                        /* only part of this page is to be unmapped, so break it up and try again */
+                        self.split(pte, level)?;
+                    }
+                    else
+                    {
+                        unsafe { *pte = 0; }
+                        offset = offset + page;
+                    }
+                },
+                None => offset = offset + PAGE_SIZE
+            }
+        }
+
+        Ok(())
+    }
+
+    /* translate a guest physical address to a host physical address, or None if not mapped */
+    pub fn translate(&self, gpa: VirtMemBase) -> Option<PhysMemBase>
+    {
+        match self.find_leaf(gpa)
+        {
+            Some((pte, level)) =>
+            {
+                let page = GStageTable::page_size(level);
+                let base = unsafe { (*pte >> PTE_PPN_SHIFT) << PAGE_SHIFT };
+                Some(base + (gpa & (page - 1)))
+            },
+            None => None
+        }
+    }
+}
+
+/* give the tables' memory back to the heap */
+impl Drop for GStageTable
+{
+    fn drop(&mut self)
+    {
+        for (table, layout) in self.pages.drain(..)
+        {
+            unsafe { dealloc(table as *mut u8, layout) };
+        }
+    }
+}
+
 /* map a capsule's virtual memory to a host physical memory region */
 #[derive(Clone, Copy)]
 pub struct Mapping
 {
     virtual_base: Option<VirtMemBase>,
-    physical_region: Option<Region>
+    physical_region: Option<Region>,
+    access: Access,
+    grant: Option<GrantID>  /* the grant sharing this memory with the capsule, or None if the capsule owns it */
 }
 
 impl Mapping
 {
-    /* create an empty mapping */
+    /* create an empty mapping of memory owned by the capsule */
     pub fn new() -> Mapping
     {
         Mapping
         {
             virtual_base: None,
-            physical_region: None
+            physical_region: None,
+            access: Access::ReadWriteExecute,
+            grant: None
         }
     }
 
+    /* mark this mapping as memory shared with the capsule by another capsule's grant */
+    pub fn set_grant(&mut self, id: GrantID, access: Access)
+    {
+        self.grant = Some(id);
+        self.access = access;
+    }
+
+    /* return the grant this mapping belongs to, or None if the capsule owns the memory */
+    pub fn get_grant(&self) -> Option<GrantID> { self.grant }
+    pub fn get_access(&self) -> Access { self.access }
+
     /* define the virtual base address and corresponding physical RAM region */
     pub fn set_virtual(&mut self, vbase: VirtMemBase) { self.virtual_base = Some(vbase); }
     pub fn set_physical(&mut self, region: Region) { self.physical_region = Some(region); }
+    pub fn get_virtual(&self) -> Option<VirtMemBase> { self.virtual_base }
     pub fn get_physical(&self) -> Option<Region> { self.physical_region }
 
     /* set 1:1 mapping of virtual to physical addresses. requires physical region to be defined */